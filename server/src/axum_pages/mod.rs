@@ -3,10 +3,11 @@ use axum::{
 };
 use bytes::Bytes;
 use crate::{client::Clients, ImageData};
+use shared::http;
 use tokio::{sync::RwLock, time::sleep};
 use futures::stream::{StreamExt};
 
-use std::{sync::Arc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use std::{sync::Arc, time::{Duration, Instant}};
 use axum::response::Json;
 use serde_json::json;
 
@@ -22,6 +23,7 @@ pub async fn mjpeg_page(req: Uri, image: Extension<Arc<RwLock<ImageData>>>, clie
     tokio::spawn(async move 
         {   
             let _c_id = client_clone.write().await.add_client_from_header(line.clone());
+            stream_shared.read().await.viewer_joined();
 
             let mut prev_frame = None;
             // let mut interval = tokio::time::interval(Duration::from_millis(33));
@@ -56,37 +58,10 @@ pub async fn mjpeg_page(req: Uri, image: Extension<Arc<RwLock<ImageData>>>, clie
                 if prev_frame.is_none() {
                     let lock = stream_shared.read().await;
                     let img = {
-                        lock.frame.clone().unwrap_or(Vec::new())
+                        lock.frame.clone().unwrap_or_default()
                     };
                     // println!("img lock acquired parent {}/{}", _c_id.1, _c_id.0);
-                    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
-                    frame = Vec::new();
-                    // TODO Temporarily incorrect, add proper implementation 
-                    if !advance_headers {
-                        frame.extend_from_slice(format!(
-                            "--boundarydonotcross\r\n\
-                            Content-Type: image/jpeg\r\n\
-                            Content-Length: {}\r\n\
-                            X-Timestamp: {:.6}\r\n\r\n",
-                            // img.as_ref().map_or(0, |i| i.len()),
-                            img.len(),
-                            timestamp
-                        ).as_bytes());
-                    } else {
-                        frame.extend_from_slice(format!(
-                            "--boundarydonotcross\r\n\
-                            Content-Type: image/jpeg\r\n\
-                            Content-Length: {}\r\n\r\n",
-                            img.len(),
-                        ).as_bytes());
-                    }
-
-                    // println!("frame header: {}", String::from_utf8(frame.clone()).unwrap());
-                    // if let Some(img) = img {
-                        frame.extend_from_slice(&img);
-                    // }
-
-                    frame.extend_from_slice(b"\r\n");
+                    frame = http::stream_part(&img, !advance_headers, &http::dirty_header(extra_headers && !advance_headers, &lock.dirty));
 
                     prev_frame.replace(frame.clone());
                 } else {
@@ -98,34 +73,7 @@ pub async fn mjpeg_page(req: Uri, image: Extension<Arc<RwLock<ImageData>>>, clie
                             let img = lock.frame.clone().unwrap();
                             // println!("img length:{}", img.len());
                             // println!("img lock acquired parent {}/{}", _c_id.1, _c_id.0);
-                            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
-                            frame = Vec::new();
-                            // TODO Temporarily incorrect, add proper implementation 
-                            if !advance_headers {
-                                frame.extend_from_slice(format!(
-                                    "--boundarydonotcross\r\n\
-                                    Content-Type: image/jpeg\r\n\
-                                    Content-Length: {}\r\n\
-                                    X-Timestamp: {:.6}\r\n\r\n",
-                                    // img.as_ref().map_or(0, |i| i.len()),
-                                    img.len(),
-                                    timestamp
-                                ).as_bytes());
-                            } else {
-                                frame.extend_from_slice(format!(
-                                    "--boundarydonotcross\r\n\
-                                    Content-Type: image/jpeg\r\n\
-                                    Content-Length: {}\r\n",
-                                    img.len(),
-                                ).as_bytes());
-                            }
-
-                            // println!("frame header: {}", String::from_utf8(frame.clone()).unwrap());
-                            // if let Some(img) = img {
-                                frame.extend_from_slice(&img);
-                            // }
-
-                            frame.extend_from_slice(b"\r\n");
+                            frame = http::stream_part(&img, !advance_headers, &http::dirty_header(extra_headers && !advance_headers, &lock.dirty));
                             prev_frame.replace(frame.clone());
                         } else {
                             skip = true;
@@ -171,6 +119,7 @@ pub async fn mjpeg_page(req: Uri, image: Extension<Arc<RwLock<ImageData>>>, clie
                 }
             }
             client_clone.write().await.remove_client_from_header(line.clone());
+            stream_shared.read().await.viewer_left();
         }
     );
 
@@ -178,16 +127,60 @@ pub async fn mjpeg_page(req: Uri, image: Extension<Arc<RwLock<ImageData>>>, clie
 
     Response::builder()
         .status(200)
-        .header(CACHE_CONTROL, "no-store, no-cache, must-revalidate, proxy-revalidate, pre-check=0, post-check=0, max-age=0")
+        .header(CACHE_CONTROL, http::NO_CACHE)
         .header(PRAGMA, "no-cache")
-        .header(EXPIRES, "Mon, 3 Jan 2000 12:34:56 GMT")
+        .header(EXPIRES, http::EXPIRED)
         .header(CONNECTION, "keep-alive")
-        .header(CONTENT_TYPE, "multipart/x-mixed-replace; boundary=boundarydonotcross")
+        .header(CONTENT_TYPE, format!("multipart/x-mixed-replace; boundary={}", http::BOUNDARY))
         .header(TRANSFER_ENCODING, "chunked")
         .body(Body::from_stream(stream.map(Ok::<_, std::convert::Infallible>)))
         .unwrap()
 }
 
+pub async fn h264_page(req: Uri, image: Extension<Arc<RwLock<ImageData>>>, client_list: Extension<Arc<RwLock<Clients>>>) -> Response {
+    let line = if let Some(header) = req.path_and_query() {header.to_string()} else {"/h264".to_string()};
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Bytes>(16);
+    let stream_shared = image.clone();
+    let client_clone = client_list.clone();
+    tokio::spawn(async move {
+        let mut client = http::H264Client::join(&stream_shared, &client_clone, line).await;
+        while let Some(unit) = client.next().await {
+            if tx.send(unit).await.is_err() {
+                break;
+            }
+        }
+        client.leave().await;
+    });
+
+    let stream = tokio_stream::wrappers::ReceiverStream::from(rx);
+
+    Response::builder()
+        .status(200)
+        .header(CACHE_CONTROL, http::NO_CACHE)
+        .header(PRAGMA, "no-cache")
+        .header(CONTENT_TYPE, http::H264_CONTENT_TYPE)
+        .body(Body::from_stream(stream.map(Ok::<_, std::convert::Infallible>)))
+        .unwrap()
+}
+
+pub async fn quality_handler(req: Uri, image: Extension<Arc<RwLock<ImageData>>>) -> Json<serde_json::Value> {
+    let query = req.query().unwrap_or_default();
+    match crate::quality_from_query(query) {
+        Some(quality) if image.read().await.request_quality(quality) => Json(json!({ "ok": true, "result": { "quality": quality } })),
+        Some(_) => Json(json!({ "ok": false, "error": "image server not connected" })),
+        None => Json(json!({ "ok": false, "error": "expected /quality?value=1..100" })),
+    }
+}
+
+/// `/control?fps=15` and friends, answered once the image server acknowledged the change.
+pub async fn control_handler(req: Uri, image: Extension<Arc<RwLock<ImageData>>>) -> Json<serde_json::Value> {
+    match crate::control_request_from_query(req.query().unwrap_or_default()) {
+        Ok(command) => Json(crate::control_response(crate::send_control(&image, &command).await.as_ref())),
+        Err(e) => Json(json!({ "ok": false, "error": e })),
+    }
+}
+
 pub async fn mjpeg_html() -> Html<String> {
     Html(std::fs::read_to_string("index.html").unwrap())
 }
@@ -250,7 +243,8 @@ pub async fn ustreamer_state(image: Extension<Arc<RwLock<ImageData>>>, client_li
             "instance_id": "",
             "encoder": {
                 "encoder": encoder,
-                "quality": 80,
+                "quality": lock.quality,
+                "fallback": if lock.encoder_fallback.is_empty() { None } else { Some(&lock.encoder_fallback) },
             },
             "source": {
                 "resolution": {
@@ -260,6 +254,7 @@ pub async fn ustreamer_state(image: Extension<Arc<RwLock<ImageData>>>, client_li
                 "online": true,
                 "desired_fps": fps,
                 "captured_fps": cfps,
+                "identical_frames": lock.identical_frames,
             },
             "stream": json,
         }
@@ -267,7 +262,29 @@ pub async fn ustreamer_state(image: Extension<Arc<RwLock<ImageData>>>, client_li
     axum::Json(json_body)
 }
 
-pub async fn snapshot_handler(image: Extension<Arc<RwLock<ImageData>>>) -> Response {
+pub async fn snapshot_handler(req: Uri, image: Extension<Arc<RwLock<ImageData>>>) -> Response {
+    match crate::snapshot_request_from_query(req.query().unwrap_or_default()) {
+        Ok(Some(request)) => {
+            return match crate::fetch_still(&image, &request).await {
+                Some((data, content_type)) => Response::builder()
+                    .status(200)
+                    .header(CONTENT_TYPE, content_type)
+                    .body(Body::from(data))
+                    .unwrap(),
+                None => Response::builder()
+                    .status(503)
+                    .body(Body::empty())
+                    .unwrap(),
+            };
+        }
+        Err(e) => {
+            return Response::builder()
+                .status(400)
+                .body(Body::from(e))
+                .unwrap();
+        }
+        Ok(None) => {}
+    }
     let frame = image.read().await.frame.clone();
     match frame {
         Some(data) => 
@@ -1,109 +1,162 @@
-use std::sync::Arc;
+use tokio::{net::UnixStream, sync::RwLock};
 
-use tokio::{io::{AsyncReadExt, BufReader, Interest}, net::{TcpStream, UnixStream}, sync::RwLock};
+use protocol::{Frame, FrameReader};
 
 use futures::{Stream, StreamExt};
 
-pub mod client;
 pub mod unix;
 pub mod axum_pages;
 
+pub use shared::{client, control::{self, Reply}, image::{H264_BACKLOG, ImageData}, protocol, socket, transport};
+
 // TODO: Deprecate ImgStream
 pub struct ImgStream {
-    socket: Arc<RwLock<UnixStream>>,
-    counter: usize,
+    reader: FrameReader<UnixStream>,
 }
 
 impl ImgStream {
-    pub fn new(socket: Arc<RwLock<UnixStream>>) -> Self {
-        ImgStream { 
-            socket, 
-            counter: 0,
+    pub fn new(socket: UnixStream) -> Self {
+        ImgStream {
+            reader: FrameReader::new(socket),
         }
     }
 
-    pub fn get_stream(&mut self) -> impl Stream<Item = (Vec<u8>, Option<String>)> {
-        self.counter += 1;
-        futures::stream::unfold(
-            Arc::new(RwLock::new(ImgStream {
-                socket: self.socket.clone(),
-                counter: 0,
-            })),  
-            move | mut state| async move {
-                
-                let mut state_guard = state.write().await;
-                state_guard.counter += 1;
-                
-                // tokio::time::sleep(Duration::from_millis(20)).await;
-                let mut socket_guard = state_guard.socket.write().await;
-                let mut open_socket = BufReader::new(&mut *socket_guard);
-                let mut len_buf = [0u8; 8];
-                open_socket.read_exact(&mut len_buf).await.unwrap_or_else(|_| {return 0;});
-                // println!("{:?}", len_buf)       ;
-                let len = usize::from_be_bytes(len_buf);
-                // println!("len recieved {}", len);
-                if len < 10000*10000*3 {
-                    let mut buffer = vec![0u8; len];
-                    match open_socket.read_exact(&mut buffer).await {
-                        Ok(n) if n > 0 => {
-                        }
-                        _ => {return None},
-                    }
-
-                    // let test_vec = std::fs::read("ustreaner.jpg").unwrap();
-
-                    let mut metadata_buf = [0u8; 1024];
-                    if open_socket.read_exact(&mut metadata_buf).await.is_err() {
-                        return None;
-                    }
-                    if metadata_buf[0] == 0 {
-                        Some(((buffer, None), state.clone()))
-                        // Some(((test_vec, None), state.clone()))
-                    } else {
-                        let stripped = metadata_buf.into_iter().take_while(|&b| b != 0).collect::<Vec<u8>>();
-                        let content = String::from_utf8(stripped).unwrap_or_default();
-                        // println!("{}", content);
-                        Some(((buffer, Some(content)), state.clone()))
-                        //  Some(((test_vec, Some(content)), state.clone()))
-                    }
-                } else {
-                   None
-                }
-                                                
+    /// Frames from the image server until the socket closes.
+    pub fn get_stream(self) -> impl Stream<Item = Frame> {
+        futures::stream::unfold(self.reader, |mut reader| async move {
+            let frame = reader.next_frame().await.ok()?;
+            Some((frame, reader))
+        }).fuse()
+    }
+}
+
+/// How long `/snapshot?quality=95` and friends wait for the image server to encode the still.
+pub const SNAPSHOT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Reads `value` out of a `/quality?value=NN` request.
+pub fn quality_from_query(query: &str) -> Option<u8> {
+    let value = query.split(['?', '&', ' ']).find_map(|pair| pair.strip_prefix("value="))?;
+    value.parse::<u8>().ok().filter(|q| (1..=100).contains(q))
+}
+
+/// Content type of a snapshot from the image server.
+pub fn still_content_type(frame: &[u8]) -> Option<&'static str> {
+    if frame.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if frame.len() >= 12 && &frame[..4] == b"RIFF" && &frame[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if frame.starts_with(&[0xFF, 0xD8]) {
+        Some("image/jpeg")
+    } else {
+        None
+    }
+}
+
+/// Turns `/snapshot` query parameters (`format`, `quality`, `subsampling`, `width`, `height`) into a
+/// snapshot request for the image server, e.g. `jpeg,quality=95`.
+/// `Ok(None)` means a plain `/snapshot`, which is answered with the latest stream frame.
+pub fn snapshot_request_from_query(query: &str) -> Result<Option<String>, String> {
+    let mut format = "jpeg";
+    let mut settings = Vec::new();
+    for pair in query.split(['?', '&', ' ']) {
+        let Some((key, value)) = pair.split_once('=') else { continue };
+        let valid = match key {
+            "format" => {
+                format = value;
+                matches!(value, "jpeg" | "png" | "webp")
             }
-        ).fuse()
+            "quality" => value.parse::<u8>().is_ok_and(|q| (1..=100).contains(&q)),
+            "subsampling" => matches!(value, "444" | "422" | "420" | "gray"),
+            "width" | "height" => value.parse::<usize>().is_ok_and(|size| (1..=control::MAX_SNAPSHOT_SIDE).contains(&size)),
+            _ => continue,
+        };
+        if !valid {
+            return Err(format!("invalid {} {:?}", key, value));
+        }
+        if key != "format" {
+            settings.push(format!("{}={}", key, value));
+        }
     }
+    if format == "jpeg" && settings.is_empty() {
+        return Ok(None);
+    }
+    settings.insert(0, format.to_string());
+    Ok(Some(settings.join(",")))
+}
 
+/// Requests a snapshot from the image server and waits for it.
+/// Returns the encoded image and its content type, or `None` if the image server didn't deliver one.
+pub async fn fetch_still(image: &RwLock<ImageData>, request: &str) -> Option<(bytes::Bytes, &'static str)> {
+    let (mut stills, id) = {
+        let lock = image.read().await;
+        let stills = lock.stills.subscribe();
+        (stills, lock.request_snapshot(request)?)
+    };
+    tokio::time::timeout(SNAPSHOT_TIMEOUT, async {
+        loop {
+            match stills.recv().await {
+                Ok((still, frame)) if still == id => {
+                    return still_content_type(&frame).map(|content_type| (frame, content_type));
+                }
+                Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }).await.ok().flatten()
 }
 
-use std::sync::atomic::{AtomicUsize, Ordering};
-
-pub struct ImageData{
-    pub skip: bool,
-    pub frame: Option<Vec<u8>>,
-    pub width: u32,
-    pub height: u32,
-    pub client_fps: Arc<AtomicUsize>,
-    pub client_total_frames: Arc<AtomicUsize>,
-    pub server_fps: Arc<AtomicUsize>,
-    pub server_total_frames: Arc<AtomicUsize>,
-    pub encoder: String,
-    pub format: String,
+/// How long `/control` waits for the image server to acknowledge a request.
+pub const CONTROL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Turns `/control` query parameters into a request for the image server, e.g. `/control?fps=15`.
+/// Takes the first of `quality`, `fps`, `resolution`, `keyframe` and `stats`.
+pub fn control_request_from_query(query: &str) -> Result<String, String> {
+    for pair in query.split(['?', '&', ' ']) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let valid = match key {
+            "keyframe" | "stats" => return Ok(key.to_string()),
+            "quality" => value.parse::<u8>().is_ok_and(|q| (1..=100).contains(&q)),
+            "fps" => value.parse::<u32>().is_ok(),
+            "resolution" => value
+                .split_once('x')
+                .is_some_and(|(width, height)| [width, height].iter().all(|size| size.parse::<u32>().is_ok_and(|size| size > 0))),
+            _ => continue,
+        };
+        if !valid {
+            return Err(format!("invalid {} {:?}", key, value));
+        }
+        return Ok(format!("{}={}", key, value));
+    }
+    Err("expected /control?quality=1..100, fps=N, resolution=WxH, keyframe or stats".to_string())
+}
+
+/// Sends a control request to the image server and waits for its answer.
+/// Returns `None` if the image server isn't connected or didn't answer in time.
+pub async fn send_control(image: &RwLock<ImageData>, command: &str) -> Option<Reply> {
+    let (mut replies, id) = {
+        let lock = image.read().await;
+        let replies = lock.replies.subscribe();
+        (replies, lock.send_request(command)?)
+    };
+    tokio::time::timeout(CONTROL_TIMEOUT, async {
+        loop {
+            match replies.recv().await {
+                Ok((request, reply)) if request == id => return Some(reply.as_ref().clone()),
+                Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }).await.ok().flatten()
 }
 
-impl ImageData {
-    pub fn new() -> Self {
-        ImageData {
-            skip: false,
-            frame: None, 
-            width: 1920, 
-            height: 1080, 
-            client_fps: Arc::new(AtomicUsize::new(0)), 
-            client_total_frames: Arc::new(AtomicUsize::new(0)),
-            server_fps: Arc::new(AtomicUsize::new(0)), 
-            server_total_frames: Arc::new(AtomicUsize::new(0)),
-            encoder: String::new(),
-            format: String::from(""),
+/// The `/control` response, `{"ok": true, "result": ...}` or `{"ok": false, "error": ...}`.
+pub fn control_response(reply: Option<&Reply>) -> serde_json::Value {
+    match reply {
+        Some(Reply::Ok(result)) => {
+            let result = serde_json::from_str(result).unwrap_or(serde_json::Value::Null);
+            serde_json::json!({ "ok": true, "result": result })
         }
+        Some(Reply::Error(error)) => serde_json::json!({ "ok": false, "error": error }),
+        None => serde_json::json!({ "ok": false, "error": "image server not connected or not answering" }),
     }
-}
\ No newline at end of file
+}
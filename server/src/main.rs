@@ -1,11 +1,14 @@
 use axum::{
     Extension, Router, body::{Body, BodyDataStream}, http::{Uri, header::{CACHE_CONTROL, CONNECTION, CONTENT_TYPE, EXPIRES, PRAGMA, TRANSFER_ENCODING}}, response::{Html, Response}, routing::get
 };
-use server::{ImageData, ImgStream, axum_pages, client::Clients, unix};
-use tokio::{io::AsyncReadExt, net::{TcpStream, UnixListener, UnixStream}, pin, sync::RwLock, time::sleep};
+use clap::Parser;
+use server::{ImageData, ImgStream, Reply, axum_pages, client::Clients, protocol::{FrameKind, FrameReader}, unix};
+use server::socket::{DEFAULT_HTTP_SOCKET, DEFAULT_IMAGE_SOCKET, ListenOptions, SocketPath, parse_group, parse_mode, parse_user};
+use server::transport::{Backoff, ImageServer, Psk, Reader, TcpTarget, tls};
+use tokio::{io::AsyncWriteExt, net::TcpStream, pin, sync::RwLock, time::sleep};
 use futures::stream::{self, StreamExt};
 
-use std::{io::Read, os::unix::fs::PermissionsExt, path::Path, sync::{mpsc, Arc}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use std::{io::Read, path::{Path, PathBuf}, sync::{mpsc, Arc}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
 
 // TODO: Change client_fps calculation to be performed in mjpeg stream loop?
@@ -24,28 +27,100 @@ use std::{io::Read, os::unix::fs::PermissionsExt, path::Path, sync::{mpsc, Arc},
 // KVMD config files
 // sudo nano /usr/share/kvmd/web/share/js/kvm/stream_mjpeg.js 
 
+#[derive(Parser, Debug)]
+#[command(about, long_about = None)]
+struct Args {
+    /// Socket to serve HTTP on, a path or `@name` for an abstract socket.
+    #[arg(long = "socket", default_value = DEFAULT_HTTP_SOCKET)]
+    socket: SocketPath,
+
+    /// Octal permissions of the HTTP socket.
+    #[arg(long = "socket-mode", default_value = "660", value_parser = parse_mode)]
+    socket_mode: u32,
+
+    /// User name or uid to own the HTTP socket.
+    #[arg(long = "socket-owner", value_parser = parse_user)]
+    socket_owner: Option<u32>,
+
+    /// Group name or gid to own the HTTP socket.
+    #[arg(long = "socket-group", value_parser = parse_group)]
+    socket_group: Option<u32>,
+
+    /// Image server socket to read frames from, a path or `@name` for an abstract socket.
+    #[arg(long = "image-socket", default_value = DEFAULT_IMAGE_SOCKET)]
+    image_socket: SocketPath,
+
+    /// Read frames from an image server on another host instead, `host:port` of its `--tcp`.
+    #[arg(long = "image-tcp", requires = "image_psk_file", conflicts_with = "image_socket")]
+    image_tcp: Option<String>,
+
+    /// File holding the key the image server expects, the same as its `--psk-file`.
+    #[arg(long = "image-psk-file", requires = "image_tcp")]
+    image_psk_file: Option<PathBuf>,
+
+    /// PEM certificate of the image server or the CA that issued it, turns on TLS.
+    #[arg(long = "image-tls-ca", requires = "image_tcp")]
+    image_tls_ca: Option<PathBuf>,
+
+    /// Name the image server's certificate was issued for, the host of `--image-tcp` by default.
+    #[arg(long = "image-tls-name", requires = "image_tls_ca")]
+    image_tls_name: Option<String>,
+
+    /// Ask the image server to pass frames as memfds instead of copying them through its socket.
+    #[arg(long = "image-memfd", conflicts_with = "image_tcp")]
+    image_memfd: bool,
+}
+
+impl Args {
+    fn http_socket(&self) -> ListenOptions {
+        ListenOptions { path: self.socket.clone(), mode: Some(self.socket_mode), owner: self.socket_owner, group: self.socket_group }
+    }
+
+    /// Where to read frames from, loading the key and CA for `--image-tcp`.
+    fn image_server(&self) -> Result<ImageServer, String> {
+        let (Some(addr), Some(psk_file)) = (&self.image_tcp, &self.image_psk_file) else {
+            return Ok(ImageServer::Unix(self.image_socket.clone()));
+        };
+        let psk = Psk::read(psk_file).map_err(|e| format!("Failed to read key {}: {}", psk_file.display(), e))?;
+        let tls = match &self.image_tls_ca {
+            Some(ca) => {
+                let host = addr.rsplit_once(':').map_or(addr.as_str(), |(host, _)| host).trim_matches(['[', ']']);
+                let name = self.image_tls_name.as_deref().unwrap_or(host);
+                Some(tls::Client::from_pem(ca, name).map_err(|e| format!("Failed to load TLS CA: {}", e))?)
+            }
+            None => None,
+        };
+        Ok(ImageServer::Tcp(TcpTarget { addr: addr.clone(), psk, tls }))
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+    let image_server = match args.image_server() {
+        Ok(image_server) => image_server,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
     let shared = Arc::new(RwLock::new(ImageData::new())); 
     let shared_clone = Arc::clone(&shared);
 
     let client_list =Arc::new(RwLock::new(Clients::new()));
-    let socket_path = "/run/kvmd/ustreamer.sock"; 
-    eprintln!("Removing old socket...");
-    std::fs::remove_file(socket_path).ok();
+    let http_socket = args.http_socket();
 
     let unix = true;
     eprintln!("Binding to new socket");
 
     tokio::spawn(async move {
-        attach_socket(shared).await;
+        attach_socket(shared, image_server, args.image_memfd).await;
     });
 
     if unix {
-        let sock_listener = UnixListener::bind(socket_path).unwrap();
-        std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o660)).unwrap();
+        let sock_listener = http_socket.bind_async().unwrap_or_else(|e| panic!("Failed to bind {}: {}", http_socket.path, e));
 
-        eprintln!("Binded to socket {}", socket_path);
+        eprintln!("Binded to socket {}", http_socket.path);
         
         tokio::spawn(async move {
             loop {
@@ -72,6 +147,9 @@ async fn main() {
             .route("/ustate", get(axum_pages::streamer_details))
             .route("/state", get(axum_pages::ustreamer_state))
             .route("/snapshot", get(axum_pages::snapshot_handler))
+            .route("/quality", get(axum_pages::quality_handler))
+            .route("/control", get(axum_pages::control_handler))
+            .route("/h264", get(axum_pages::h264_page))
             .layer(Extension(shared_clone.clone()))
             .layer(Extension(client_list.clone()));
 
@@ -86,22 +164,45 @@ async fn main() {
     }
 }
 
-async fn attach_socket(image_data: Arc<RwLock<ImageData>>) {
+async fn attach_socket(image_data: Arc<RwLock<ImageData>>, image_server: ImageServer, memfd: bool) {
     let shared_data = Arc::clone(&image_data);
+    // Retries quickly after the image server restarted, slower while it stays away.
+    let mut backoff = Backoff::new(Duration::from_millis(200), Duration::from_secs(5));
     loop {
         let mut handle = None;
-        match UnixStream::connect(format!("{}/ustreamer_rs.sock", env!("CARGO_MANIFEST_DIR"))).await {
-            Ok(found) => {
-                // let socket = Arc::new(RwLock::new(found));
+        match image_server.connect_frames(memfd).await {
+            Ok((reader, mut writer)) => {
+                backoff.reset();
+                println!("Connected to image server {}", image_server);
+                if memfd && let Err(e) = writer.write_all(b"memfd=1\n").await {
+                    eprintln!("Failed to ask for memfd frames: {}", e);
+                }
+                let (control_tx, mut control_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+                let mut lock = shared_data.write().await;
+                lock.control = Some(control_tx);
+                // Until it hears otherwise the image server counts this connection as a viewer.
+                lock.report_viewers();
+                drop(lock);
+                tokio::spawn(async move {
+                    while let Some(command) = control_rx.recv().await {
+                        if let Err(e) = writer.write_all(command.as_bytes()).await {
+                            eprintln!("Failed to send control command: {}", e);
+                            break;
+                        }
+                    }
+                });
                 let clone = Arc::clone(&shared_data);
                 handle.replace(tokio::spawn(async move {
-                    mjpeg_stream(found, clone).await;
+                    mjpeg_stream(reader, clone).await;
                 }));
             }, 
-            Err(_) => {
-                eprintln!("Failed to connect to socket. Is the image server running?");
-                shared_data.write().await.skip = true;
-                sleep(Duration::from_millis(200)).await;
+            Err(e) => {
+                eprintln!("Failed to connect to image server {}: {}. Is it running?", image_server, e);
+                let mut lock = shared_data.write().await;
+                lock.skip = true;
+                lock.control = None;
+                drop(lock);
+                sleep(backoff.next_delay()).await;
             }
         }
         
@@ -114,58 +215,49 @@ async fn attach_socket(image_data: Arc<RwLock<ImageData>>) {
                     eprintln!("Streamer failed {}", e);
                  }
             }
+            sleep(Duration::from_millis(2000)).await;
         }
-        sleep(Duration::from_millis(2000)).await;
     }
     
 }
 
 
 
-async fn mjpeg_stream(mut socket: UnixStream, image: Arc<RwLock<ImageData>>) {
+async fn mjpeg_stream(mut reader: FrameReader<Reader>, image: Arc<RwLock<ImageData>>) {
     let mut missed = 0;
     loop {
-        let mut len_buf = [0u8; 8];
-        socket.read_exact(&mut len_buf).await.unwrap_or(0);
+        let frame = match reader.next_frame().await {
+            Ok(frame) => frame,
+            Err(e) => {
+                eprintln!("UnixStream read error {}", e);
+                return;
+            }
+        };
         let mut lock = image.write().await;
-        let len = usize::from_be_bytes(len_buf);
-        if len < 10000*10000*3 {
-            let mut buffer = vec![0u8; len];
-            match socket.read_exact(&mut buffer).await {
-                Ok(n) if n > 0 => {
+        match frame.kind {
+            FrameKind::Still => {
+                // A snapshot someone asked for, not a stream frame.
+                if let Some(id) = frame.metadata.still {
+                    let _ = lock.stills.send((id, frame.payload));
                 }
-                _ => {
-                    eprintln!("UnixStream read error");
-                },
+                continue;
             }
-
-            lock.frame = Some(buffer);
-            // let test_vec = std::fs::read("ustreaner.jpg").unwrap();
-
-            let mut metadata_buf = [0u8; 1024];
-            match socket.read_exact(&mut metadata_buf).await {
-                Ok(_status) => { 
-                    if metadata_buf[0] != 0 {
-                        let stripped = metadata_buf.into_iter().take_while(|&b| b != 0).collect::<Vec<u8>>();
-                        let metadata = String::from_utf8(stripped).unwrap_or_default();
-                        let parts: Vec<&str> = metadata.split('x').collect();
-                        if parts.len() == 7 {
-                            lock.width = parts[0].parse::<u32>().unwrap_or(lock.width);
-                            lock.height = parts[1].parse::<u32>().unwrap_or(lock.height);
-                            lock.format = parts[2].to_owned();
-                            lock.encoder = parts[3].to_owned();
-                            lock.server_fps.swap(parts[4].parse::<usize>().unwrap_or(lock.server_fps.load(std::sync::atomic::Ordering::Relaxed)), std::sync::atomic::Ordering::Relaxed);
-                            lock.server_total_frames.swap(parts[5].parse::<usize>().unwrap_or(lock.server_total_frames.load(std::sync::atomic::Ordering::Relaxed)), std::sync::atomic::Ordering::Relaxed);
-                            lock.skip = match parts[6] {"1" => true, _ => false};
-                        }
-                    }
+            FrameKind::Reply => {
+                if let Some(id) = frame.metadata.request {
+                    let _ = lock.replies.send((id, Arc::new(Reply::from_payload(&frame.payload))));
                 }
-
-                Err(e) => {
-                    eprintln!("Error reading metadata {}", e);
-                }
-            
+                continue;
             }
+            FrameKind::Other(_) => continue,
+            FrameKind::Jpeg | FrameKind::H264 => {}
+        }
+
+        lock.update(&frame);
+        if frame.kind == FrameKind::H264 {
+            // Nobody watching /h264 is not an error.
+            let _ = lock.h264.send(frame.payload);
+        } else {
+            lock.frame = Some(frame.payload);
         }
     }
 
@@ -1,15 +1,13 @@
 
-use crate::{client::Clients, ImageData};
+use crate::{client::Clients, ImageData, Reply};
+use shared::http;
 use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::UnixStream, sync::RwLock, time::sleep};
-use std::{io, os::fd::{AsFd, AsRawFd}, sync::Arc, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use std::{io, os::fd::{AsFd, AsRawFd}, sync::Arc, time::{Duration, Instant}};
 use nix::sys::socket::{setsockopt, sockopt::{RcvBuf, SndBuf}};
 
 use axum::response::Json;
 use serde_json::json;
 
-use chrono::Utc;
-use chrono::format::strftime::StrftimeItems;
-
 pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<ImageData>>, client_list: Arc<RwLock<Clients>>) {
     increase_buf_size(&stream, shared_clone.read().await.width, shared_clone.read().await.height).ok();
     let (reader, mut writer) = stream.into_split();
@@ -18,19 +16,25 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
 
     if buf_reader.read_line(&mut line).await.is_ok() {
         println!("{}", line);
-        if line.starts_with("GET /snapshot HTTP/1.1") {
-            if let Some(img) = &shared_clone.read().await.frame {   
-                let now = Utc::now();
-                let date = now.format_with_items(StrftimeItems::new("%a, %d %b %Y %H:%M:%S GMT")).to_string();
-
-                let mut response = Vec::new();
-                response.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
-                response.extend_from_slice(b"Content-Type: image/jpeg\r\n");
-                response.extend_from_slice(format!("Date: {}\r\n", date).as_bytes());
-                response.extend_from_slice(format!("Content-Length: {}\r\n", img.len()).as_bytes());
-                response.extend_from_slice(b"\r\n");
-
-                response.extend_from_slice(img);
+        let snapshot = match line.starts_with("GET /snapshot") {
+            true => crate::snapshot_request_from_query(&line),
+            false => Ok(None),
+        };
+        if let Err(_) | Ok(Some(_)) = snapshot {
+            let response = match &snapshot {
+                Ok(Some(request)) => match crate::fetch_still(&shared_clone, request).await {
+                    Some((img, content_type)) => http::response("200 OK", content_type, &img),
+                    None => http::empty_response("503 Service Unavailable"),
+                },
+                _ => http::empty_response("400 Bad Request"),
+            };
+            if let Err(e) = writer.write_all(&response).await {
+                eprintln!("Failed to send snapshot: {}", e);
+            }
+            let _ = writer.shutdown().await;
+        } else if line.starts_with("GET /snapshot") {
+            if let Some(img) = &shared_clone.read().await.frame {
+                let response = http::response("200 OK", "image/jpeg", img);
 
                 // Send response and flush
                 if let Err(e) = writer.write_all(&response).await {
@@ -40,7 +44,7 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
                 let _ = writer.shutdown().await;
                 println!("Snapshot sent and connection closed");
             } else {
-                let _ = writer.write_all(b"HTTP/1.1 503 Service Unavailable\r\n\r\n").await;
+                let _ = writer.write_all(&http::empty_response("503 Service Unavailable")).await;
                 let _ = writer.shutdown().await;
                 println!("No image available");
             }
@@ -49,19 +53,11 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
             let client_clone = client_list.clone();
             let stream_shared = shared_clone.clone();
             let _c_id = client_clone.write().await.add_client_from_header(line.clone());
-            let headers = format!(
-                "HTTP/1.1 200 OK\r\n\
-                Cache-Control: no-store, no-cache, must-revalidate, proxy-revalidate, pre-check=0, post-check=0, max-age=0\r\n\
-                Pragma: no-cache\r\n\
-                Expires: Mon, 3 Jan 2000 12:34:56 GMT\r\n\
-                Set-Cookie: stream_client={}/{}; path=/; max-age=30\r\n\
-                Connection: keep-alive\r\n\
-                Content-Type: multipart/x-mixed-replace;boundary=boundarydonotcross\r\n\r\n",
-                _c_id.1, _c_id.0
-            );
+            let headers = http::stream_headers(&_c_id.1, &_c_id.0);
 
             writer.write_all(headers.as_bytes()).await.unwrap();
             writer.flush().await.unwrap();
+            stream_shared.read().await.viewer_joined();
 
             // println!("Sent header {}", headers);
 
@@ -75,6 +71,7 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
             let mut start = Instant::now();
             let mut avg_frame_time = 0;
             let mut df_frame_sent = false;
+            let mut backlog = 0;
             let (dual_final_frame, advance_headers, extra_headers, zero_data) = {
                 if let Some((dff, ah, eh, zd)) = client_clone.read().await.get_client_settings(Some(_c_id.1)) {
                     (dff, ah, eh, zd)
@@ -90,7 +87,14 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
                 if start.elapsed().as_millis() > 1000 {
                     println!("WEB SERVER FRAME TIME {}", avg_frame_time);
                     start = Instant::now();
-                    client_clone.write().await.update_fps_from_header(line.clone(), fps);
+                    let max_backlog = {
+                        let mut clients = client_clone.write().await;
+                        clients.update_fps_from_header(line.clone(), fps);
+                        clients.update_backlog_from_header(line.clone(), backlog);
+                        clients.max_backlog()
+                    };
+                    stream_shared.read().await.report_backlog(max_backlog);
+                    backlog = 0;
                     fps = 0;
                 }
                 fps += 1;
@@ -98,37 +102,10 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
                 if prev_frame.is_none() {
                     let lock = stream_shared.read().await;
                     let img = {
-                        lock.frame.clone().unwrap_or(Vec::new())
+                        lock.frame.clone().unwrap_or_default()
                     };
                     // println!("img lock acquired parent {}/{}", _c_id.1, _c_id.0);
-                    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
-                    frame = Vec::new();
-                    // TODO Temporarily incorrect, add proper implementation 
-                    if !advance_headers {
-                        frame.extend_from_slice(format!(
-                            "--boundarydonotcross\r\n\
-                            Content-Type: image/jpeg\r\n\
-                            Content-Length: {}\r\n\
-                            X-Timestamp: {:.6}\r\n\r\n",
-                            // img.as_ref().map_or(0, |i| i.len()),
-                            img.len(),
-                            timestamp
-                        ).as_bytes());
-                    } else {
-                        frame.extend_from_slice(format!(
-                            "--boundarydonotcross\r\n\
-                            Content-Type: image/jpeg\r\n\
-                            Content-Length: {}\r\n\r\n",
-                            img.len(),
-                        ).as_bytes());
-                    }
-
-                    // println!("frame header: {}", String::from_utf8(frame.clone()).unwrap());
-                    // if let Some(img) = img {
-                        frame.extend_from_slice(&img);
-                    // }
-
-                    frame.extend_from_slice(b"\r\n");
+                    frame = http::stream_part(&img, !advance_headers, &http::dirty_header(extra_headers && !advance_headers, &lock.dirty));
 
                     prev_frame.replace(frame.clone());
                 } else {
@@ -138,37 +115,10 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
                         let frame_start = Instant::now();
                         if lock.skip == false {
                             skip = false;
-                            let img = lock.frame.clone().unwrap_or(Vec::new());
+                            let img = lock.frame.clone().unwrap_or_default();
                             // println!("img length:{}", img.len());
                             // println!("img lock acquired parent {}/{}", _c_id.1, _c_id.0);
-                            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
-                            frame = Vec::new();
-                            // TODO Temporarily incorrect, add proper implementation 
-                            if !advance_headers {
-                                frame.extend_from_slice(format!(
-                                    "--boundarydonotcross\r\n\
-                                    Content-Type: image/jpeg\r\n\
-                                    Content-Length: {}\r\n\
-                                    X-Timestamp: {:.6}\r\n\r\n",
-                                    // img.as_ref().map_or(0, |i| i.len()),
-                                    img.len(),
-                                    timestamp
-                                ).as_bytes());
-                            } else {
-                                frame.extend_from_slice(format!(
-                                    "--boundarydonotcross\r\n\
-                                    Content-Type: image/jpeg\r\n\
-                                    Content-Length: {}\r\n",
-                                    img.len(),
-                                ).as_bytes());
-                            }
-
-                            // println!("frame header: {}", String::from_utf8(frame.clone()).unwrap());
-                            // if let Some(img) = img {
-                                frame.extend_from_slice(&img);
-                            // }
-
-                            frame.extend_from_slice(b"\r\n");
+                            frame = http::stream_part(&img, !advance_headers, &http::dirty_header(extra_headers && !advance_headers, &lock.dirty));
                             prev_frame.replace(frame.clone());
                         } else {
                             skip = true;
@@ -195,6 +145,7 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
                         break;
                     }
 
+                    backlog = backlog.max(send_backlog(writer.as_ref()));
                     println!("Frame send writeall + flush processing time {} with len {} frame num {}", frame_send.elapsed().as_millis(), &frame.len(), count);
                     
                     tokio::time::sleep(Duration::from_millis(30_u64.saturating_sub(frame_send.elapsed().as_millis() as u64))).await;
@@ -229,6 +180,19 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
                 println!("Total Frame time {}", frame_time.elapsed().as_millis());
             }
             client_clone.write().await.remove_client_from_header(line.clone());
+            stream_shared.read().await.viewer_left();
+        } else if line.starts_with("GET /h264") {
+            if writer.write_all(http::h264_headers().as_bytes()).await.is_err() {
+                return;
+            }
+            let mut client = http::H264Client::join(&shared_clone, &client_list, line.clone()).await;
+            while let Some(unit) = client.next().await {
+                if let Err(e) = writer.write_all(&unit).await {
+                    eprintln!("Failed connection, {}", e);
+                    break;
+                }
+            }
+            client.leave().await;
         }  else if line.starts_with("GET /state") {
             let lock = shared_clone.read().await;
             let cframe_num = lock.client_total_frames.load(std::sync::atomic::Ordering::Relaxed);
@@ -261,7 +225,8 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
                     "encoder": {
                         "encoder": encoder,
                         // "pixel format": pixformat,
-                        "quality": 80,
+                        "quality": lock.quality,
+                        "fallback": if lock.encoder_fallback.is_empty() { None } else { Some(&lock.encoder_fallback) },
                     },
                     "source": {
                         "resolution": {
@@ -271,6 +236,7 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
                         "online": true,
                         "desired_fps": fps,
                         "captured_fps": cfps,
+                        "identical_frames": lock.identical_frames,
                     },
                     "stream": json,
                     // "server": {
@@ -281,18 +247,7 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
                 }
             }).to_string();
 
-            let now = Utc::now();
-            let date = now.format_with_items(StrftimeItems::new("%a, %d %b %Y %H:%M:%S GMT")).to_string();
-
-            let mut response = Vec::new();
-            response.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
-            response.extend_from_slice(b"Content-Type: application/json\r\n");
-            response.extend_from_slice(b"Date: ");
-            response.extend_from_slice(date.as_bytes());
-            response.extend_from_slice(b"\r\nContent-Length: ");
-            response.extend_from_slice(json_body.len().to_string().as_bytes());
-            response.extend_from_slice(b"\r\n\r\n");
-            response.extend_from_slice(json_body.as_bytes());
+            let response = http::response("200 OK", "application/json", json_body.as_bytes());
             // println!("Sending response:\n{}", String::from_utf8_lossy(&response));
         
             if let Err(e) = writer.write_all(&response).await {
@@ -336,18 +291,7 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
                     "port": port,
                 }
             })).to_string();
-            let now = Utc::now();
-            let date = now.format_with_items(StrftimeItems::new("%a, %d %b %Y %H:%M:%S GMT")).to_string();
-
-            let mut response = Vec::new();
-            response.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
-            response.extend_from_slice(b"Content-Type: application/json\r\n");
-            response.extend_from_slice(b"Date: ");
-            response.extend_from_slice(date.as_bytes());
-            response.extend_from_slice(b"\r\nContent-Length: ");
-            response.extend_from_slice(json_body.len().to_string().as_bytes());
-            response.extend_from_slice(b"\r\n\r\n");
-            response.extend_from_slice(json_body.as_bytes());
+            let response = http::response("200 OK", "application/json", json_body.as_bytes());
             // println!("Sending response:\n{}", String::from_utf8_lossy(&response));
         
             if let Err(e) = writer.write_all(&response).await {
@@ -356,13 +300,61 @@ pub async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<Ima
             writer.flush().await;
             writer.shutdown().await;
             println!("Ustreamer Status JSON sent and connection closed");
+        } else if line.starts_with("GET /quality") {
+            let (status, json_body) = match crate::quality_from_query(&line) {
+                Some(quality) if shared_clone.read().await.request_quality(quality) => {
+                    ("200 OK", json!({ "ok": true, "result": { "quality": quality } }).to_string())
+                }
+                Some(_) => ("503 Service Unavailable", json!({ "ok": false, "error": "image server not connected" }).to_string()),
+                None => ("400 Bad Request", json!({ "ok": false, "error": "expected /quality?value=1..100" }).to_string()),
+            };
+
+            let response = http::response(status, "application/json", json_body.as_bytes());
+
+            if let Err(e) = writer.write_all(&response).await {
+                eprintln!("Failed to send JSON response: {}", e);
+            }
+            writer.flush().await;
+            writer.shutdown().await;
+        } else if line.starts_with("GET /control") {
+            let (status, json_body) = match crate::control_request_from_query(&line) {
+                Ok(command) => {
+                    let reply = crate::send_control(&shared_clone, &command).await;
+                    let status = match &reply {
+                        Some(Reply::Ok(_)) => "200 OK",
+                        Some(Reply::Error(_)) => "422 Unprocessable Entity",
+                        None => "503 Service Unavailable",
+                    };
+                    (status, crate::control_response(reply.as_ref()).to_string())
+                }
+                Err(e) => ("400 Bad Request", json!({ "ok": false, "error": e }).to_string()),
+            };
+
+            let response = http::response(status, "application/json", json_body.as_bytes());
+
+            if let Err(e) = writer.write_all(&response).await {
+                eprintln!("Failed to send JSON response: {}", e);
+            }
+            writer.flush().await;
+            writer.shutdown().await;
         } else {
             println!("Tried accessing {}", line);
-            let _ = writer.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await;
+            let _ = writer.write_all(&http::empty_response("404 Not Found")).await;
         }
     }
 }
 
+nix::ioctl_read_bad!(outq, nix::libc::TIOCOUTQ, nix::libc::c_int);
+
+/// Bytes written to the socket that the client has not read yet.
+fn send_backlog(stream: &UnixStream) -> usize {
+    let mut queued: nix::libc::c_int = 0;
+    match unsafe { outq(stream.as_raw_fd(), &mut queued) } {
+        Ok(_) => queued.max(0) as usize,
+        Err(_) => 0,
+    }
+}
+
 fn increase_buf_size(stream: &UnixStream, width: u32, height: u32,) -> std::io::Result<()> {
     let fs = stream.as_fd(); 
     setsockopt(&fs, SndBuf, &((width * height * 3) as usize)).map_err(|e| std::io::Error::new(io::ErrorKind::Other, e))
@@ -1,19 +1,51 @@
 // Programming for unix first
 
-use std::{os::unix::fs::PermissionsExt, sync::Arc, time::{Duration, Instant}};
+use std::{sync::Arc, time::{Duration, Instant}};
 
-use bytes::{Bytes, BytesMut};
-use server_next::{Image, ImageMetaData, client::{ClientMessage, ClientState, ClientStates, Clients}, ring::RingBuffer, unix};
-use tokio::{io::AsyncReadExt, net::{UnixListener, UnixStream}, sync::{Mutex, RwLock, broadcast::{self, Sender}, mpsc::{self, Receiver}}, time::sleep};
+use clap::Parser;
+use server_next::{Image, ImageData, client::{ClientMessage, ClientState, ClientStates, Clients}, protocol::{FLAG_SKIP, FrameKind, FrameReader}, ring::RingBuffer, unix};
+use server_next::socket::{DEFAULT_HTTP_SOCKET, DEFAULT_IMAGE_SOCKET, ListenOptions, SocketPath, parse_group, parse_mode, parse_user};
+use tokio::{net::UnixStream, sync::{Mutex, RwLock, broadcast::{self, Sender}, mpsc::{self, Receiver}}, time::sleep};
 
 // To send requests to socket
 // sudo socat - UNIX-CONNECT:/run/kvmd/ustreamer.sock
 // sudo socat TCP4-LISTEN:8080,fork,reuseaddr,bind=0.0.0.0 UNIX-CONNECT:/run/kvmd/ustreamer.sock
 
+#[derive(Parser, Debug)]
+#[command(about, long_about = None)]
+struct Args {
+    /// Socket to serve HTTP on, a path or `@name` for an abstract socket.
+    #[arg(long = "socket", default_value = DEFAULT_HTTP_SOCKET)]
+    socket: SocketPath,
+
+    /// Octal permissions of the HTTP socket.
+    #[arg(long = "socket-mode", default_value = "660", value_parser = parse_mode)]
+    socket_mode: u32,
+
+    /// User name or uid to own the HTTP socket.
+    #[arg(long = "socket-owner", value_parser = parse_user)]
+    socket_owner: Option<u32>,
+
+    /// Group name or gid to own the HTTP socket.
+    #[arg(long = "socket-group", value_parser = parse_group)]
+    socket_group: Option<u32>,
+
+    /// Image server socket to read frames from, a path or `@name` for an abstract socket.
+    #[arg(long = "image-socket", default_value = DEFAULT_IMAGE_SOCKET)]
+    image_socket: SocketPath,
+}
+
+impl Args {
+    fn http_socket(&self) -> ListenOptions {
+        ListenOptions { path: self.socket.clone(), mode: Some(self.socket_mode), owner: self.socket_owner, group: self.socket_group }
+    }
+}
+
 #[tokio::main(flavor = "multi_thread", worker_threads = 4)]
 // #[tokio::main]
 async fn main() {
-    let metadata = Arc::new(RwLock::new(ImageMetaData::new()));
+    let args = Args::parse();
+    let metadata = Arc::new(RwLock::new(ImageData::new()));
     let metadata_clone = Arc::clone(&metadata);
     
     let (image_broadcaster, _) = broadcast::channel(16);
@@ -21,9 +53,7 @@ async fn main() {
 
     let client_list = Arc::new(RwLock::new(Clients::new()));
 
-    let socket_path = "/run/kvmd/ustreamer.sock";
-    eprintln!("Removing old socket...");
-    std::fs::remove_file(socket_path).ok();
+    let http_socket = args.http_socket();
 
     let unix = true;
     eprintln!("Binding to new socket");
@@ -31,13 +61,12 @@ async fn main() {
     let (client_tx, client_rx) = mpsc::channel(100);
 
     tokio::spawn(async move {
-        attach_socket(image_broadcaster.clone(), metadata.clone(), client_rx).await;
+        attach_socket(image_broadcaster.clone(), metadata.clone(), client_rx, args.image_socket).await;
     });
 
-    let sock_listener = UnixListener::bind(socket_path).unwrap();
-    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o660)).unwrap();
+    let sock_listener = http_socket.bind_async().unwrap_or_else(|e| panic!("Failed to bind {}: {}", http_socket.path, e));
 
-    eprintln!("Binded to socket {}", socket_path);
+    eprintln!("Binded to socket {}", http_socket.path);
     
     tokio::spawn(async move {
         loop {
@@ -64,13 +93,13 @@ async fn main() {
     }).await.unwrap();
 }
 
-async fn attach_socket(image_tx: Sender<Arc<Image>>, metadata: Arc<RwLock<ImageMetaData>>, rx: Receiver<ClientMessage>) {
+async fn attach_socket(image_tx: Sender<Arc<Image>>, metadata: Arc<RwLock<ImageData>>, rx: Receiver<ClientMessage>, image_socket: SocketPath) {
     let shared_metadata = Arc::clone(&metadata);
     let arc_rx = Arc::new(Mutex::new(rx));
     loop {
         let mut handle = None;
         let (loop_tx, mut loop_rx) = mpsc::channel(2);
-        match UnixStream::connect(format!("{}/../server/ustreamer_rs.sock", env!("CARGO_MANIFEST_DIR"))).await {
+        match image_socket.connect_async().await {
             Ok(found) => {
                 drain_socket(&found);
                 // let socket = Arc::new(RwLock::new(found));
@@ -104,8 +133,7 @@ async fn attach_socket(image_tx: Sender<Arc<Image>>, metadata: Arc<RwLock<ImageM
     
 }
 
-async fn mjpeg_stream(mut socket: UnixStream, image_sender: Sender<Arc<Image>>, metadata: Arc<RwLock<ImageMetaData>>, rx: Arc<Mutex<Receiver<ClientMessage>>>, loop_rx: Receiver<bool>) {
-    let mut missed = 0;
+async fn mjpeg_stream(socket: UnixStream, image_sender: Sender<Arc<Image>>, metadata: Arc<RwLock<ImageData>>, rx: Arc<Mutex<Receiver<ClientMessage>>>, loop_rx: Receiver<bool>) {
     let mut start = Instant::now();
     let ring_buffer = Arc::new(Mutex::new(RingBuffer::new(15)));
     let ring_clone = ring_buffer.clone();
@@ -113,101 +141,41 @@ async fn mjpeg_stream(mut socket: UnixStream, image_sender: Sender<Arc<Image>>,
     tokio::spawn(async move {
         server_next::bridge::poll_clients(rx, loop_rx, image_sender.clone(), ring_clone).await
     });
-    let mut invalid_metadata_count = 0;
-    let mut invalid_socket_read = 0;
     drain_socket(&socket);
+    // Whatever the drain cut in half is skipped until the next frame header.
+    let mut reader = FrameReader::new(socket);
     loop {
-        let mut len_buf = [0u8; 8];
-        socket.read_exact(&mut len_buf).await.unwrap_or(0);
-        
-        let len = usize::from_be_bytes(len_buf);
-        if len < 10000*10000*3 {
-            let mut buffer = BytesMut::zeroed(len);
-            match socket.read_exact(&mut buffer).await {
-                Ok(n) if n > 0 => {
-                    invalid_socket_read = 0;
-                }
-                _ => {
-                    eprintln!("UnixStream read error");
-                    drain_socket(&socket);
-                    tokio::time::sleep(Duration::from_millis(50)).await;
-                    invalid_socket_read += 1;
-                    invalid_metadata_count = -1;
-                    if invalid_socket_read > 20 {
-                        break
-                    }
-                    continue
-                },
+        let frame = match reader.next_frame().await {
+            Ok(frame) => frame,
+            Err(e) => {
+                eprintln!("UnixStream read error {}", e);
+                break
             }
-            // let test_vec = std::fs::read("ustreaner.jpg").unwrap();
-
-            let mut metadata_buf = [0u8; 1024];
-            let mut ring = ring_buffer.lock().await;
-            match socket.read_exact(&mut metadata_buf).await {
-                Ok(_status) => { 
-                    println!("In metadata");
-                    if metadata_buf[0] != 0 {
-                        if let Ok(mut lock) = metadata.try_write() {
-                            let stripped = metadata_buf.into_iter().take_while(|&b| b != 0).collect::<Vec<u8>>();
-                            let metadata = String::from_utf8(stripped).unwrap_or_default();
-                            let parts: Vec<&str> = metadata.split('x').collect();
-                            if parts.len() == 7 {
-                                lock.width = parts[0].parse::<u32>().unwrap_or(lock.width);
-                                lock.height = parts[1].parse::<u32>().unwrap_or(lock.height);
-                                lock.format = parts[2].to_owned();
-                                lock.encoder = parts[3].to_owned();
-                                lock.server_fps.swap(parts[4].parse::<usize>().unwrap_or(lock.server_fps.load(std::sync::atomic::Ordering::Relaxed)), std::sync::atomic::Ordering::Relaxed);
-                                lock.server_total_frames.swap(parts[5].parse::<usize>().unwrap_or(lock.server_total_frames.load(std::sync::atomic::Ordering::Relaxed)), std::sync::atomic::Ordering::Relaxed);
-                                println!("Checking skip");
-                                if !(match parts[6] {"1" => true, _ => false}) {
-                                    println!("Writing to ring");
-                                    let len =  buffer.len();
-                                    if let Ok(e) = ring.write(Image::new(buffer.freeze())) {
-                                        println!("Sending frame into ring buffer with size {}", len);
-                                    } else {
-                                        println!("Skipping Frame as buffer full");
-                                        tokio::time::sleep(Duration::from_millis(10)).await;
-                                    }
-                                } else {
-                                    println!("Skip is true");
-                                }
-                            }
-                        } else {
-                            println!("Failed to access lock");
-                            let len =  buffer.len();
-                            if let Ok(e) = ring.write(Image::new(buffer.freeze())) {
-                                println!("Sending frame into ring buffer with size {}", len);
-                            } else {
-                                println!("Skipping Frame as buffer full");
-                                tokio::time::sleep(Duration::from_millis(10)).await;
-                            }
-                        }
-                        invalid_metadata_count = 0;
-                    } else {
-                        if invalid_metadata_count > 0 {
-                            if invalid_metadata_count < 5 {
-                                println!("<Metadata invalid but writing image>");
-                                if let Ok(e) = ring.write(Image::new(buffer.freeze())) {
-                                    println!("Sending frame into ring buffer with size {}", len);
-                                } else {
-                                    println!("Skipping Frame as buffer full");
-                                }
-                            }
-                            invalid_metadata_count += 1;
-                        }
-                    }
-                }
+        };
+        // Snapshots and H.264 are only served by the other web server.
+        if frame.kind != FrameKind::Jpeg {
+            continue
+        }
 
-                Err(e) => {
-                    eprintln!("Error reading metadata {}", e);
-                    missed += 1;
-                    if missed > 100 {
-                        break;
-                    }
-                }
+        let mut ring = ring_buffer.lock().await;
+        if let Ok(mut lock) = metadata.try_write() {
+            lock.update(&frame);
+        } else {
+            println!("Failed to access lock");
+        }
+
+        if frame.has_flag(FLAG_SKIP) {
+            println!("Skip is true");
+        } else {
+            let len = frame.payload.len();
+            if ring.write(Image::new(frame.payload)).is_ok() {
+                println!("Sending frame into ring buffer with size {}", len);
+            } else {
+                println!("Skipping Frame as buffer full");
+                tokio::time::sleep(Duration::from_millis(10)).await;
             }
-            println!("Read to buffer");
         }
+        drop(ring);
         tokio::task::yield_now().await;
         // println!("Time since last loop: {}ms", Instant::now().duration_since(start).as_millis());
         start = Instant::now();
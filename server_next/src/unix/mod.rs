@@ -1,6 +1,6 @@
 use std::os::fd::AsFd;
 use std::sync::Arc;
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use nix::sys::socket::setsockopt;
@@ -14,9 +14,10 @@ use tokio::net::UnixStream;
 use nix::sys::socket::sockopt::SndBuf;
 
 use crate::client::{ClientMessage, ClientState, generate_id};
-use crate::{Image, ImageMetaData, client::Clients};
+use crate::{Image, ImageData, client::Clients};
+use shared::http;
 
-pub async fn connection_handler(stream: UnixStream, image_stream: Sender<Arc<Image>>, metadata: Arc<RwLock<ImageMetaData>>, client_list: Arc<RwLock<Clients>>, client_tx: MPSCSender<ClientMessage>) {
+pub async fn connection_handler(stream: UnixStream, image_stream: Sender<Arc<Image>>, metadata: Arc<RwLock<ImageData>>, client_list: Arc<RwLock<Clients>>, client_tx: MPSCSender<ClientMessage>) {
     println!("Inside Connection handler");
     increase_buf_size(&stream, metadata.read().await.width, metadata.read().await.height);
     println!("Increasing buf size");
@@ -50,16 +51,7 @@ pub async fn connection_handler(stream: UnixStream, image_stream: Sender<Arc<Ima
             };
             let stream_shared = metadata.clone();
             
-            let headers = format!(
-                "HTTP/1.1 200 OK\r\n\
-                Cache-Control: no-store, no-cache, must-revalidate, proxy-revalidate, pre-check=0, post-check=0, max-age=0\r\n\
-                Pragma: no-cache\r\n\
-                Expires: Mon, 3 Jan 2000 12:34:56 GMT\r\n\
-                Set-Cookie: stream_client={}/{}; path=/; max-age=30\r\n\
-                Connection: keep-alive\r\n\
-                Content-Type: multipart/x-mixed-replace;boundary=boundarydonotcross\r\n\r\n",
-                _c_id.1, _c_id.0
-            );
+            let headers = http::stream_headers(&_c_id.1, &_c_id.0);
 
             writer.write_all(headers.as_bytes()).await.unwrap();
             println!("Sent header");
@@ -117,34 +109,7 @@ pub async fn connection_handler(stream: UnixStream, image_stream: Sender<Arc<Ima
                         // let lock = stream_shared.read().await;
                         println!("STReAM ShaRED READ");
                         // println!("img lock acquired parent {}/{}", _c_id.1, _c_id.0);
-                        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
-                        frame = Vec::new();
-                        // TODO Temporarily incorrect, add proper implementation 
-                        if !advance_headers {
-                            frame.extend_from_slice(format!(
-                                "--boundarydonotcross\r\n\
-                                Content-Type: image/jpeg\r\n\
-                                Content-Length: {}\r\n\
-                                X-Timestamp: {:.6}\r\n\r\n",
-                                // img.as_ref().map_or(0, |i| i.len()),
-                                img_data.frame.len(),
-                                timestamp
-                            ).as_bytes());
-                        } else {
-                            frame.extend_from_slice(format!(
-                                "--boundarydonotcross\r\n\
-                                Content-Type: image/jpeg\r\n\
-                                Content-Length: {}\r\n\r\n",
-                                img_data.frame.len(),
-                            ).as_bytes());
-                        }
-                        // println!("Frame Receiving MID time {}", start_recv.elapsed().as_millis());
-                        // println!("frame header: {}", String::from_utf8(frame.clone()).unwrap());
-                        // if let Some(img) = img {
-                            frame.extend_from_slice(&img_data.frame);
-                        // }
-
-                        frame.extend_from_slice(b"\r\n");
+                        frame = http::stream_part(&img_data.frame, !advance_headers, "");
 
                         prev_frame.replace(frame.clone());
                         
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone)]
+pub struct ClientDetails {
+    id: String,
+    fps: u32,
+    backlog: usize,
+    extra_headers: bool,
+    advance_headers: bool,
+    dual_final_frames: bool,
+    zero_data: bool,
+    key: String,
+}
+
+impl ClientDetails {
+    /// `key` is the client's key, optionally followed by its settings, e.g. `abc&extra_headers=1`.
+    pub fn new(key: Option<String>) -> Self {
+        Self::with_fps(key.as_deref(), 0)
+    }
+
+    pub fn from_header(header: String) -> Self {
+        Self::with_fps(query_from_header(&header), 30)
+    }
+
+    fn with_fps(query: Option<&str>, fps: u32) -> Self {
+        let mut client = ClientDetails {
+            id: generate_id(),
+            fps,
+            backlog: 0,
+            extra_headers: false,
+            advance_headers: false,
+            dual_final_frames: false,
+            zero_data: false,
+            key: String::from("0"),
+        };
+        let Some(query) = query else {
+            return client;
+        };
+        let mut segments = query.split('&');
+        client.key = segments.next().unwrap_or("0").to_string();
+        for segment in segments {
+            match segment {
+                "dual_final_frames=1" => client.dual_final_frames = true,
+                "advance_headers=1" => client.advance_headers = true,
+                "zero_data=1" => client.zero_data = true,
+                "extra_headers=1" => client.extra_headers = true,
+                _ => println!("Setting not detected"),
+            }
+        }
+        client
+    }
+
+    pub fn update_fps(&mut self, fps: u32) {
+        self.fps = fps;
+    }
+
+    pub fn update_backlog(&mut self, backlog: usize) {
+        self.backlog = backlog;
+    }
+
+    pub fn get_settings(&self) -> (bool, bool, bool, bool) {
+        (self.dual_final_frames, self.advance_headers, self.extra_headers, self.zero_data)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            self.id.clone(): {
+                "fps": self.fps,
+                "backlog": self.backlog,
+                "extra_headers": self.extra_headers,
+                "advance_headers": self.advance_headers,
+                "dual_final_frames": self.dual_final_frames,
+                "zero_data": self.zero_data,
+                "key": self.key,
+            }
+        })
+    }
+}
+
+pub fn generate_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..16].to_string()
+}
+
+/// Stream clients by key, the oldest is dropped once there are more than `max_clients`.
+#[derive(Debug)]
+pub struct Clients {
+    pub queued: u32,
+    pub clients: u32,
+    pub max_clients: u32,
+    pub stats: HashMap<String, ClientDetails>,
+    age: Vec<String>,
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        Clients {
+            queued: 30,
+            clients: 0,
+            max_clients: 10,
+            stats: HashMap::new(),
+            age: Vec::new(),
+        }
+    }
+
+    pub fn add_client(&mut self, key: Option<String>) -> String {
+        let client = ClientDetails::new(key);
+        let id = client.id.clone();
+        self.insert(client);
+        id
+    }
+
+    /// Registers the client requesting `header`, returns its id and key.
+    pub fn add_client_from_header(&mut self, header: String) -> (String, String) {
+        let client = ClientDetails::from_header(header);
+        let id = client.id.clone();
+        let key = client.key.clone();
+        println!("client added {:?}", client);
+        self.insert(client);
+        println!("Client count {}", self.clients);
+        (id, key)
+    }
+
+    fn insert(&mut self, client: ClientDetails) {
+        if !self.stats.contains_key(&client.key) && self.stats.len() as u32 >= self.max_clients && !self.age.is_empty() {
+            let oldest = self.age.remove(0);
+            self.stats.remove(&oldest);
+        }
+        self.age.retain(|key| *key != client.key);
+        self.age.push(client.key.clone());
+        self.stats.insert(client.key.clone(), client);
+        self.clients = self.stats.len() as u32;
+    }
+
+    pub fn get_client_settings(&self, key: Option<String>) -> Option<(bool, bool, bool, bool)> {
+        self.stats.get(&key.unwrap_or(String::from("0"))).map(ClientDetails::get_settings)
+    }
+
+    pub fn remove_client(&mut self, key: Option<String>) {
+        let key = key.unwrap_or(String::from("0"));
+        self.stats.remove(&key);
+        self.age.retain(|age| *age != key);
+        self.clients = self.stats.len() as u32;
+    }
+
+    pub fn remove_client_from_header(&mut self, header: String) {
+        self.remove_client(key_from_header(&header));
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let stats: Vec<Value> = self.stats.values().map(ClientDetails::to_json).collect();
+        json!({
+            "queued_fps": self.queued,
+            "clients": self.clients,
+            "clients_stat": merge_json(stats),
+        })
+    }
+
+    pub fn get_client_from_header(&mut self, header: String) -> Option<&mut ClientDetails> {
+        let key = key_from_header(&header).unwrap_or("0".to_owned());
+        self.stats.get_mut(&key)
+    }
+
+    pub fn update_fps_from_header(&mut self, header: String, fps: u32) {
+        if let Some(client) = self.get_client_from_header(header) {
+            client.update_fps(fps);
+        }
+    }
+
+    pub fn update_backlog_from_header(&mut self, header: String, backlog: usize) {
+        if let Some(client) = self.get_client_from_header(header) {
+            client.update_backlog(backlog);
+        }
+    }
+
+    /// Largest send backlog over all connected clients, the slowest link decides the bandwidth budget.
+    pub fn max_backlog(&self) -> usize {
+        self.stats.values().map(|client| client.backlog).max().unwrap_or(0)
+    }
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Everything after `?key=` in a request line, e.g. `abc&extra_headers=1` for
+/// `GET /stream?key=abc&extra_headers=1 HTTP/1.1`.
+fn query_from_header(header: &str) -> Option<&str> {
+    let target = header.split_whitespace().nth(1)?;
+    target.split_once("?key=").map(|(_, query)| query)
+}
+
+/// The client key of a request line, without the settings that may follow it.
+fn key_from_header(header: &str) -> Option<String> {
+    query_from_header(header).map(|query| query.split('&').next().unwrap_or("0").to_string())
+}
+
+fn merge_json(json_vals: Vec<serde_json::Value>) -> serde_json::Value {
+    let mut merged = json!({});
+    for json_val in json_vals {
+        if let Value::Object(map) = json_val
+            && let Some((key, value)) = map.iter().next()
+        {
+            merged[key] = value.clone();
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn settings_come_from_the_request_line() {
+        let mut clients = Clients::new();
+        let (_, key) = clients.add_client_from_header("GET /stream?key=abc&extra_headers=1&zero_data=1 HTTP/1.1".to_string());
+        assert_eq!(key, "abc");
+        assert_eq!(clients.get_client_settings(Some(key)), Some((false, false, true, true)));
+
+        clients.remove_client_from_header("GET /stream?key=abc&extra_headers=1 HTTP/1.1".to_string());
+        assert_eq!(clients.clients, 0);
+    }
+
+    #[test]
+    fn oldest_client_is_dropped() {
+        let mut clients = Clients::new();
+        clients.max_clients = 2;
+        for key in ["a", "b", "c"] {
+            clients.add_client(Some(key.to_string()));
+        }
+        assert_eq!(clients.clients, 2);
+        assert!(!clients.stats.contains_key("a"));
+    }
+}
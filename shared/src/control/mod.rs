@@ -0,0 +1,79 @@
+//! Control requests from a web server to the image server and the replies to them.
+//!
+//! Requests are text lines on the image server socket, e.g. `quality=90`. A line may end in
+//! ` id=N`, the image server then answers it with a `Reply` frame carrying the same id in
+//! `Metadata::request`. Lines without an id are fire and forget, as they always were.
+
+/// Largest width or height a snapshot may be scaled to, both sides refuse anything bigger.
+pub const MAX_SNAPSHOT_SIDE: usize = 8192;
+
+/// Appends the request id, e.g. `fps=15 id=3\n`.
+pub fn request_line(command: &str, id: u32) -> String {
+    format!("{} id={}\n", command.trim(), id)
+}
+
+/// Splits a request line into the command and the id it should be answered with, if any.
+pub fn split_request(line: &str) -> (&str, Option<u32>) {
+    let line = line.trim();
+    if let Some((command, id)) = line.rsplit_once(' ')
+        && let Some(id) = id.strip_prefix("id=").and_then(|id| id.parse().ok())
+    {
+        return (command.trim_end(), Some(id));
+    }
+    (line, None)
+}
+
+/// The image server's answer to a request with an id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Done, with the outcome as JSON, e.g. the resolution the device settled on, or nothing.
+    Ok(String),
+    /// Not done, with the reason.
+    Error(String),
+}
+
+impl Reply {
+    /// Payload of the `Reply` frame, `ok <details>` or `error <reason>`.
+    pub fn to_payload(&self) -> Vec<u8> {
+        let (status, text) = match self {
+            Reply::Ok(text) => ("ok", text),
+            Reply::Error(text) => ("error", text),
+        };
+        if text.is_empty() {
+            status.as_bytes().to_vec()
+        } else {
+            format!("{} {}", status, text).into_bytes()
+        }
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Self {
+        let payload = String::from_utf8_lossy(payload);
+        let (status, text) = payload.split_once(' ').unwrap_or((&payload, ""));
+        match status {
+            "ok" => Reply::Ok(text.to_string()),
+            "error" => Reply::Error(text.to_string()),
+            _ => Reply::Error(format!("malformed reply {:?}", payload)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requests_carry_their_id() {
+        assert_eq!(request_line("fps=15", 3), "fps=15 id=3\n");
+        assert_eq!(split_request("fps=15 id=3\n"), ("fps=15", Some(3)));
+        assert_eq!(split_request("snapshot=jpeg,id=2"), ("snapshot=jpeg,id=2", None));
+        assert_eq!(split_request("keyframe"), ("keyframe", None));
+    }
+
+    #[test]
+    fn replies_round_trip() {
+        for reply in [Reply::Ok(String::new()), Reply::Ok("{\"fps\": 30}".to_string()), Reply::Error("unknown command".to_string())] {
+            assert_eq!(Reply::from_payload(&reply.to_payload()), reply);
+        }
+        assert!(matches!(Reply::from_payload(b"maybe"), Reply::Error(_)));
+    }
+}
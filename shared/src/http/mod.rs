@@ -0,0 +1,196 @@
+//! Raw HTTP/1.1 responses for the web servers that answer on a plain socket instead of through axum,
+//! and the stream loops both kinds of front end share.
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use chrono::{Utc, format::strftime::StrftimeItems};
+use tokio::sync::{RwLock, broadcast::{self, error::RecvError}};
+
+use crate::client::Clients;
+use crate::image::ImageData;
+
+/// Separates the JPEGs of an MJPEG stream.
+pub const BOUNDARY: &str = "boundarydonotcross";
+
+/// `Cache-Control` of streams, so nothing along the way holds on to a frame.
+pub const NO_CACHE: &str = "no-store, no-cache, must-revalidate, proxy-revalidate, pre-check=0, post-check=0, max-age=0";
+
+/// `Expires` of streams, a date long gone.
+pub const EXPIRED: &str = "Mon, 3 Jan 2000 12:34:56 GMT";
+
+/// The current time as a `Date` header value, e.g. `Mon, 03 Jan 2000 12:34:56 GMT`.
+pub fn date() -> String {
+    Utc::now().format_with_items(StrftimeItems::new("%a, %d %b %Y %H:%M:%S GMT")).to_string()
+}
+
+/// A complete response, `status` being e.g. `200 OK`.
+pub fn response(status: &str, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\n\
+        Content-Type: {}\r\n\
+        Date: {}\r\n\
+        Content-Length: {}\r\n\r\n",
+        status,
+        content_type,
+        date(),
+        body.len()
+    ).into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// A response without headers or body, e.g. for `503 Service Unavailable`.
+pub fn empty_response(status: &str) -> Vec<u8> {
+    format!("HTTP/1.1 {}\r\n\r\n", status).into_bytes()
+}
+
+/// Headers that start an MJPEG stream. The cookie tells the client the key and id it was registered under.
+pub fn stream_headers(key: &str, id: &str) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\n\
+        Cache-Control: {}\r\n\
+        Pragma: no-cache\r\n\
+        Expires: {}\r\n\
+        Set-Cookie: stream_client={}/{}; path=/; max-age=30\r\n\
+        Connection: keep-alive\r\n\
+        Content-Type: multipart/x-mixed-replace;boundary={}\r\n\r\n",
+        NO_CACHE, EXPIRED, key, id, BOUNDARY
+    )
+}
+
+/// `Content-Type` of `/h264`, a raw Annex-B stream.
+pub const H264_CONTENT_TYPE: &str = "video/h264";
+
+/// Headers that start a `/h264` stream.
+pub fn h264_headers() -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\n\
+        Cache-Control: {}\r\n\
+        Pragma: no-cache\r\n\
+        Connection: close\r\n\
+        Content-Type: {}\r\n\r\n",
+        NO_CACHE, H264_CONTENT_TYPE
+    )
+}
+
+/// Whether an Annex-B access unit contains an IDR slice, i.e. decoding can start from it.
+pub fn is_keyframe(frame: &[u8]) -> bool {
+    frame.windows(4).any(|w| w[..3] == [0, 0, 1] && w[3] & 0x1F == 5)
+}
+
+/// One `/h264` client, which counts as a viewer and is listed under its request line until `leave`.
+///
+/// Access units are skipped until the first keyframe, which is asked for on `join`, and again
+/// after the client fell behind.
+pub struct H264Client<'a> {
+    image: &'a RwLock<ImageData>,
+    clients: &'a RwLock<Clients>,
+    line: String,
+    units: broadcast::Receiver<Bytes>,
+    synced: bool,
+    fps: u32,
+    start: Instant,
+}
+
+impl<'a> H264Client<'a> {
+    pub async fn join(image: &'a RwLock<ImageData>, clients: &'a RwLock<Clients>, line: String) -> Self {
+        clients.write().await.add_client_from_header(line.clone());
+        let units = {
+            let lock = image.read().await;
+            lock.viewer_joined();
+            lock.request_keyframe();
+            lock.h264.subscribe()
+        };
+        H264Client { image, clients, line, units, synced: false, fps: 0, start: Instant::now() }
+    }
+
+    /// The next access unit to send, `None` once the image server stream ended.
+    pub async fn next(&mut self) -> Option<Bytes> {
+        if self.start.elapsed().as_millis() > 1000 {
+            self.clients.write().await.update_fps_from_header(self.line.clone(), self.fps);
+            self.start = Instant::now();
+            self.fps = 0;
+        }
+        loop {
+            let unit = match self.units.recv().await {
+                Ok(unit) => unit,
+                Err(RecvError::Lagged(missed)) => {
+                    eprintln!("H.264 client fell {} access units behind, waiting for a keyframe", missed);
+                    self.synced = false;
+                    self.image.read().await.request_keyframe();
+                    continue;
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            if !self.synced && !is_keyframe(&unit) {
+                continue;
+            }
+            self.synced = true;
+            self.fps += 1;
+            return Some(unit);
+        }
+    }
+
+    pub async fn leave(self) {
+        self.clients.write().await.remove_client_from_header(self.line);
+        self.image.read().await.viewer_left();
+    }
+}
+
+/// One JPEG of an MJPEG stream with its part headers. With `timestamp` an `X-Timestamp` of the
+/// current time is added, `extra` holds further header lines, each ending in `\r\n`.
+pub fn stream_part(jpeg: &[u8], timestamp: bool, extra: &str) -> Vec<u8> {
+    let mut part = format!(
+        "--{}\r\n\
+        Content-Type: image/jpeg\r\n\
+        Content-Length: {}\r\n",
+        BOUNDARY,
+        jpeg.len()
+    );
+    if timestamp {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        part.push_str(&format!("X-Timestamp: {:.6}\r\n", now));
+    }
+    part.push_str(extra);
+    part.push_str("\r\n");
+
+    let mut part = part.into_bytes();
+    part.extend_from_slice(jpeg);
+    part.extend_from_slice(b"\r\n");
+    part
+}
+
+/// `X-UStreamer-Dirty` header line for clients that asked for `extra_headers`, empty otherwise.
+pub fn dirty_header(extra_headers: bool, dirty: &Option<String>) -> String {
+    match dirty {
+        Some(map) if extra_headers => format!("X-UStreamer-Dirty: {}\r\n", map),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn idr_slices_are_keyframes() {
+        assert!(is_keyframe(&[0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x65, 0x88]));
+        assert!(!is_keyframe(&[0, 0, 0, 1, 0x41, 0x9A]));
+    }
+
+    #[test]
+    fn part_headers_end_before_the_jpeg() {
+        let part = stream_part(&[0xFF, 0xD8], false, &dirty_header(true, &Some("64:1x1:8".to_string())));
+        let expected = b"--boundarydonotcross\r\nContent-Type: image/jpeg\r\nContent-Length: 2\r\n\
+            X-UStreamer-Dirty: 64:1x1:8\r\n\r\n\xFF\xD8\r\n";
+        assert_eq!(part, expected);
+    }
+
+    #[test]
+    fn response_has_length() {
+        let response = String::from_utf8(response("200 OK", "application/json", b"{}")).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n"));
+        assert!(response.ends_with("Content-Length: 2\r\n\r\n{}"));
+    }
+}
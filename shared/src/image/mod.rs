@@ -0,0 +1,186 @@
+use std::sync::{Arc, atomic::{AtomicU32, AtomicUsize, Ordering}};
+
+use bytes::Bytes;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::control::{self, Reply};
+use crate::protocol::{FLAG_SKIP, Frame};
+
+/// H.264 access units buffered for a slow `/h264` client before it has to wait for a keyframe.
+pub const H264_BACKLOG: usize = 64;
+
+/// What a web server knows about the stream: the latest frame and the details the image server
+/// sent with it, plus the channel back to the image server.
+pub struct ImageData {
+    pub skip: bool,
+    pub frame: Option<Bytes>,
+    pub width: u32,
+    pub height: u32,
+    pub client_fps: Arc<AtomicUsize>,
+    pub client_total_frames: Arc<AtomicUsize>,
+    pub server_fps: Arc<AtomicUsize>,
+    pub server_total_frames: Arc<AtomicUsize>,
+    pub encoder: String,
+    pub format: String,
+    pub quality: u8,
+    pub encoder_fallback: String,
+    /// Frames the image server dropped because they showed the same picture.
+    pub identical_frames: u64,
+    /// Changed tiles of `frame` as sent by the image server with `--dirty-tiles`.
+    pub dirty: Option<String>,
+    pub control: Option<mpsc::UnboundedSender<String>>,
+    /// Every H.264 access unit from the image server, unlike `frame` which only keeps the latest JPEG.
+    pub h264: broadcast::Sender<Bytes>,
+    /// Snapshots the image server encoded on request, with the id of the request.
+    pub stills: broadcast::Sender<(u32, Bytes)>,
+    pub next_still: AtomicU32,
+    /// Answers to control requests, with the id of the request.
+    pub replies: broadcast::Sender<(u32, Arc<Reply>)>,
+    pub next_request: AtomicU32,
+    /// Open stream connections, reported to the image server so it can idle while there are none.
+    pub viewers: AtomicUsize,
+}
+
+impl ImageData {
+    pub fn new() -> Self {
+        ImageData {
+            skip: false,
+            frame: None,
+            width: 1920,
+            height: 1080,
+            client_fps: Arc::new(AtomicUsize::new(0)),
+            client_total_frames: Arc::new(AtomicUsize::new(0)),
+            server_fps: Arc::new(AtomicUsize::new(0)),
+            server_total_frames: Arc::new(AtomicUsize::new(0)),
+            encoder: String::new(),
+            format: String::new(),
+            quality: 80,
+            encoder_fallback: String::new(),
+            identical_frames: 0,
+            dirty: None,
+            control: None,
+            h264: broadcast::channel(H264_BACKLOG).0,
+            stills: broadcast::channel(4).0,
+            next_still: AtomicU32::new(1),
+            replies: broadcast::channel(16).0,
+            next_request: AtomicU32::new(1),
+            viewers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes over the stream details sent along with a stream frame. Fields the image server left
+    /// out keep their last value, except those that only describe this one frame.
+    pub fn update(&mut self, frame: &Frame) {
+        let metadata = &frame.metadata;
+        self.width = metadata.width.unwrap_or(self.width);
+        self.height = metadata.height.unwrap_or(self.height);
+        if let Some(format) = &metadata.pixel_format {
+            self.format = format.clone();
+        }
+        if let Some(encoder) = &metadata.encoder {
+            self.encoder = encoder.clone();
+        }
+        if let Some(fps) = metadata.fps {
+            self.server_fps.store(fps as usize, Ordering::Relaxed);
+        }
+        self.server_total_frames.store(frame.sequence as usize, Ordering::Relaxed);
+        self.skip = frame.has_flag(FLAG_SKIP);
+        self.quality = metadata.quality.unwrap_or(self.quality);
+        self.identical_frames = metadata.identical.unwrap_or(self.identical_frames);
+        self.encoder_fallback = metadata.fallback.clone().unwrap_or_default();
+        self.dirty = metadata.dirty.clone();
+    }
+
+    /// Asks the image server to switch JPEG quality, returns false if it is not connected.
+    pub fn request_quality(&self, quality: u8) -> bool {
+        match &self.control {
+            Some(tx) => tx.send(format!("quality={}\n", quality)).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Asks the H.264 encoder for a keyframe so a new or lagging client can start decoding.
+    pub fn request_keyframe(&self) -> bool {
+        match &self.control {
+            Some(tx) => tx.send("keyframe\n".to_string()).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Asks the image server to encode its next raw frame, e.g. `jpeg,quality=95`.
+    /// Returns the id the still will come back with, or `None` if it is not connected.
+    pub fn request_snapshot(&self, request: &str) -> Option<u32> {
+        let id = self.next_still.fetch_add(1, Ordering::Relaxed);
+        let tx = self.control.as_ref()?;
+        tx.send(format!("snapshot={},id={}\n", request, id)).ok()?;
+        Some(id)
+    }
+
+    /// Sends a control request that the image server answers, e.g. `fps=15` or `stats`.
+    /// Returns the id the reply will come back with, or `None` if it is not connected.
+    pub fn send_request(&self, command: &str) -> Option<u32> {
+        let id = self.next_request.fetch_add(1, Ordering::Relaxed);
+        let tx = self.control.as_ref()?;
+        tx.send(control::request_line(command, id)).ok()?;
+        Some(id)
+    }
+
+    /// Counts a new stream connection and tells the image server.
+    pub fn viewer_joined(&self) -> bool {
+        self.viewers.fetch_add(1, Ordering::Relaxed);
+        self.report_viewers()
+    }
+
+    /// Counts a closed stream connection and tells the image server.
+    pub fn viewer_left(&self) -> bool {
+        let _ = self.viewers.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |viewers| viewers.checked_sub(1));
+        self.report_viewers()
+    }
+
+    /// Tells the image server how many stream connections are open, e.g. right after connecting to it.
+    pub fn report_viewers(&self) -> bool {
+        match &self.control {
+            Some(tx) => tx.send(format!("clients={}\n", self.viewers.load(Ordering::Relaxed))).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Reports the slowest client's send backlog so adaptive quality can back off.
+    pub fn report_backlog(&self, backlog: usize) -> bool {
+        match &self.control {
+            Some(tx) => tx.send(format!("backlog={}\n", backlog)).is_ok(),
+            None => false,
+        }
+    }
+}
+
+impl Default for ImageData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::{FrameKind, Metadata};
+
+    #[test]
+    fn update_keeps_missing_fields() {
+        let mut image = ImageData::new();
+        let mut frame = Frame::new(FrameKind::Jpeg, Vec::new());
+        frame.sequence = 7;
+        frame.flags = FLAG_SKIP;
+        frame.metadata = Metadata { width: Some(640), fallback: Some("no device".to_string()), ..Metadata::default() };
+        image.update(&frame);
+        assert_eq!((image.width, image.height), (640, 1080));
+        assert!(image.skip);
+        assert_eq!(image.encoder_fallback, "no device");
+        assert_eq!(image.server_total_frames.load(Ordering::Relaxed), 7);
+
+        image.update(&Frame::new(FrameKind::Jpeg, Vec::new()));
+        assert_eq!(image.width, 640);
+        assert!(!image.skip);
+        assert!(image.encoder_fallback.is_empty());
+    }
+}
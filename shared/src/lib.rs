@@ -0,0 +1,13 @@
+//! Pieces the image server and the web servers (`server`, `server_next` and the one built into
+//! `ustreamer`) have in common: the socket protocol between them, the stream state a web server
+//! keeps, its client registry, the control requests it sends back, the raw HTTP responses it writes
+//! and the sockets and TCP transport they talk over.
+
+pub mod client;
+pub mod control;
+pub mod http;
+pub mod image;
+pub mod protocol;
+pub mod socket;
+pub mod transport;
+pub mod memfd;
@@ -0,0 +1,292 @@
+//! Frames passed as sealed memfds over the image server socket instead of inline.
+//!
+//! A consumer that sends `memfd=1` gets every frame of at least `MIN_LEN` bytes as a memfd,
+//! sealed against any further change and shared by all consumers that asked. The frame header
+//! then announces an empty payload, `Metadata::memfd` holds the real length, and the descriptor
+//! travels along with the header as `SCM_RIGHTS`. The reader maps the memfd and uses the mapping
+//! as the payload instead of pulling megabytes through the socket buffer. Descriptors arrive
+//! with the first bytes of their header, so a reader pairs each with the frame whose header it
+//! came in, and closes those of frames it skipped.
+//!
+//! Only Unix sockets can pass descriptors, consumers on TCP keep getting frames inline.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, IoSlice, IoSliceMut, Write};
+use std::num::NonZeroUsize;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, ready};
+
+use bytes::Bytes;
+use nix::fcntl::{FcntlArg, SealFlag, fcntl};
+use nix::sys::memfd::{MFdFlags, memfd_create};
+use nix::sys::mman::{MapFlags, ProtFlags, mmap, munmap};
+use nix::sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, UnixAddr, recvmsg, sendmsg};
+use tokio::io::{AsyncRead, Interest, ReadBuf};
+use tokio::net::unix::OwnedReadHalf;
+
+use crate::protocol::Frame;
+
+/// Frames smaller than this are cheaper to copy through the socket than to pass as a memfd.
+pub const MIN_LEN: usize = 64 << 10;
+
+/// The seals a memfd must carry before its contents can be trusted not to change under a mapping.
+fn seals() -> SealFlag {
+    SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_GROW | SealFlag::F_SEAL_WRITE | SealFlag::F_SEAL_SEAL
+}
+
+fn io_error(e: nix::Error) -> io::Error {
+    io::Error::from_raw_os_error(e as i32)
+}
+
+/// Copies `data` into a new memfd and seals it.
+pub fn seal(data: &[u8]) -> io::Result<OwnedFd> {
+    let fd = memfd_create("ustreamer-frame", MFdFlags::MFD_CLOEXEC | MFdFlags::MFD_ALLOW_SEALING).map_err(io_error)?;
+    let mut file = File::from(fd);
+    file.write_all(data)?;
+    fcntl(&file, FcntlArg::F_ADD_SEALS(seals())).map_err(io_error)?;
+    Ok(file.into())
+}
+
+/// A frame's payload sealed into a memfd the first time a consumer wants it. Every consumer the
+/// frame goes to is then passed the same descriptor, the frame is copied once however many there are.
+#[derive(Debug, Default)]
+pub struct Sealed(OnceLock<Option<OwnedFd>>);
+
+impl Sealed {
+    fn get(&self, payload: &[u8]) -> Option<&OwnedFd> {
+        self.0
+            .get_or_init(|| seal(payload).map_err(|e| eprintln!("Failed to seal frame into a memfd: {}", e)).ok())
+            .as_ref()
+    }
+}
+
+/// A clone may get another payload, so it seals its own.
+impl Clone for Sealed {
+    fn clone(&self) -> Self {
+        Sealed::default()
+    }
+}
+
+/// Writes `frame` with `payload` to a consumer that asked for memfds, sealing the payload into
+/// `sealed` unless another consumer did already. Small frames, and frames that failed to seal, go inline.
+pub fn send_frame(stream: &mut UnixStream, frame: &Frame, payload: &[u8], sealed: &Sealed) -> io::Result<()> {
+    if payload.len() < MIN_LEN {
+        return frame.write_with(payload, stream);
+    }
+    let Some(fd) = sealed.get(payload) else {
+        return frame.write_with(payload, stream);
+    };
+    let mut metadata = frame.metadata.clone();
+    metadata.memfd = Some(payload.len() as u32);
+    let header = Frame { metadata, payload: Bytes::new(), ..*frame }.header();
+    let sent = sendmsg::<UnixAddr>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&header)],
+        &[ControlMessage::ScmRights(&[fd.as_raw_fd()])],
+        MsgFlags::MSG_NOSIGNAL,
+        None,
+    )
+    .map_err(io_error)?;
+    // The descriptor went with the first byte, the rest of a header cut short follows plainly.
+    stream.write_all(&header[sent..])
+}
+
+/// Descriptors received on a connection and not yet claimed by a frame, oldest first. Each comes
+/// with the stream offset just past the read it arrived with. The kernel ends a read right after
+/// the bytes a descriptor was sent with, so that offset lies within the header of its frame.
+pub type Received = Arc<Mutex<VecDeque<(u64, OwnedFd)>>>;
+
+/// The reading half of an image server connection that keeps the descriptors passed along.
+pub struct FdReader {
+    stream: OwnedReadHalf,
+    received: Received,
+    /// Bytes read so far.
+    read: AtomicU64,
+}
+
+impl FdReader {
+    pub fn new(stream: OwnedReadHalf) -> Self {
+        FdReader { stream, received: Received::default(), read: AtomicU64::new(0) }
+    }
+
+    /// The descriptors this reader collects, to hand to `FrameReader::with_fds`.
+    pub fn received(&self) -> Received {
+        self.received.clone()
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // Room for a few descriptors in case the kernel merged several headers into one read.
+        let mut space = nix::cmsg_space!([std::os::fd::RawFd; 8]);
+        let mut iov = [IoSliceMut::new(buf)];
+        let message = recvmsg::<()>(self.stream.as_ref().as_raw_fd(), &mut iov, Some(&mut space), MsgFlags::MSG_CMSG_CLOEXEC)
+            .map_err(io_error)?;
+        let read = self.read.fetch_add(message.bytes as u64, Ordering::Relaxed) + message.bytes as u64;
+        let mut received = self.received.lock().unwrap();
+        for message in message.cmsgs().map_err(io_error)? {
+            if let ControlMessageOwned::ScmRights(fds) = message {
+                // SAFETY: the kernel just installed these descriptors for us, nobody else owns them.
+                received.extend(fds.into_iter().map(|fd| (read, unsafe { OwnedFd::from_raw_fd(fd) })));
+            }
+        }
+        Ok(message.bytes)
+    }
+}
+
+impl AsyncRead for FdReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.stream.as_ref().poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match self.stream.as_ref().try_io(Interest::READABLE, || self.recv(unfilled)) {
+                Ok(read) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+/// A read only mapping of a sealed memfd, unmapped on drop.
+pub struct Mapping {
+    ptr: NonNull<std::ffi::c_void>,
+    len: usize,
+}
+
+// The mapping is read only and its memfd sealed, so nothing can change it while it is shared.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Maps the first `len` bytes of a memfd, refusing one that could still shrink or change.
+    pub fn new(fd: &OwnedFd, len: usize) -> io::Result<Self> {
+        let sealed = SealFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GET_SEALS).map_err(io_error)?);
+        if !sealed.contains(SealFlag::F_SEAL_SHRINK | SealFlag::F_SEAL_WRITE) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "memfd is not sealed"));
+        }
+        if File::from(fd.try_clone()?).metadata()?.len() < len as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "memfd is shorter than its frame"));
+        }
+        let Some(size) = NonZeroUsize::new(len) else {
+            return Ok(Mapping { ptr: NonNull::dangling(), len: 0 });
+        };
+        // SAFETY: a read only mapping of a file that is sealed against shrinking and writes.
+        let ptr = unsafe { mmap(None, size, ProtFlags::PROT_READ, MapFlags::MAP_SHARED, fd.as_fd(), 0) }.map_err(io_error)?;
+        Ok(Mapping { ptr, len })
+    }
+}
+
+impl std::ops::Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` maps `len` readable bytes for as long as `self` lives.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr() as *const u8, self.len) }
+    }
+}
+
+impl AsRef<[u8]> for Mapping {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: `ptr` and `len` are exactly what mmap returned and nothing borrows them any more.
+            unsafe { munmap(self.ptr, self.len) }.ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::{FrameKind, FrameReader};
+
+    #[test]
+    fn sealed_memfds_map_back() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let fd = seal(&data).unwrap();
+        assert_eq!(&*Mapping::new(&fd, data.len()).unwrap(), &data[..]);
+        assert!(File::from(fd.try_clone().unwrap()).write_all(b"changed").is_err());
+        assert!(Mapping::new(&fd, data.len() + 1).is_err());
+
+        let unsealed = memfd_create("unsealed", MFdFlags::MFD_CLOEXEC).unwrap();
+        File::from(unsealed.try_clone().unwrap()).write_all(&data).unwrap();
+        assert!(Mapping::new(&unsealed, data.len()).is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_pass_as_descriptors() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let big = Frame { sequence: 1, ..Frame::new(FrameKind::Jpeg, vec![7; MIN_LEN * 2]) };
+        let small = Frame { sequence: 2, ..Frame::new(FrameKind::Jpeg, vec![8; 16]) };
+        let sealed = Sealed::default();
+        send_frame(&mut sender, &big, &big.payload, &sealed).unwrap();
+        send_frame(&mut sender, &small, &small.payload, &Sealed::default()).unwrap();
+        // A second consumer of the same frame gets the descriptor sealed for the first.
+        let first = sealed.get(&big.payload).unwrap().as_raw_fd();
+        send_frame(&mut sender, &big, &big.payload, &sealed).unwrap();
+        assert_eq!(sealed.get(&big.payload).unwrap().as_raw_fd(), first);
+
+        receiver.set_nonblocking(true).unwrap();
+        let (reader, _writer) = tokio::net::UnixStream::from_std(receiver).unwrap().into_split();
+        let reader = FdReader::new(reader);
+        let received = reader.received();
+        let mut frames = FrameReader::with_fds(reader, received.clone());
+        for expected in [&big, &small, &big] {
+            let frame = frames.next_frame().await.unwrap();
+            assert_eq!(frame.payload, expected.payload);
+        }
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn skipped_frames_close_their_descriptors() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let first = Frame { sequence: 1, ..Frame::new(FrameKind::Jpeg, vec![7; MIN_LEN * 2]) };
+        let last = Frame { sequence: 3, ..Frame::new(FrameKind::Jpeg, vec![9; MIN_LEN * 2]) };
+        send_frame(&mut sender, &first, &first.payload, &Sealed::default()).unwrap();
+
+        // A memfd frame whose metadata doesn't decode, the reader skips it along with its descriptor.
+        let mut bad = Frame { sequence: 2, ..Frame::new(FrameKind::Jpeg, Bytes::new()) };
+        bad.metadata.pixel_format = Some("NV24".to_string());
+        bad.metadata.memfd = Some(MIN_LEN as u32);
+        let mut header = bad.header();
+        let at = header.windows(4).position(|text| text == b"NV24").unwrap();
+        header[at..at + 4].fill(0xFF);
+        let fd = seal(&vec![8; MIN_LEN]).unwrap();
+        sendmsg::<UnixAddr>(
+            sender.as_raw_fd(),
+            &[IoSlice::new(&header)],
+            &[ControlMessage::ScmRights(&[fd.as_raw_fd()])],
+            MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+
+        send_frame(&mut sender, &last, &last.payload, &Sealed::default()).unwrap();
+
+        receiver.set_nonblocking(true).unwrap();
+        let (reader, _writer) = tokio::net::UnixStream::from_std(receiver).unwrap().into_split();
+        let reader = FdReader::new(reader);
+        let received = reader.received();
+        let mut frames = FrameReader::with_fds(reader, received.clone());
+        for expected in [&first, &last] {
+            let frame = frames.next_frame().await.unwrap();
+            assert_eq!(frame.sequence, expected.sequence);
+            assert_eq!(frame.payload, expected.payload);
+        }
+        assert!(received.lock().unwrap().is_empty());
+    }
+}
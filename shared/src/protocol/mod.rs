@@ -0,0 +1,538 @@
+//! Framing of the stream from the image server to the web servers.
+//!
+//! Every frame is a fixed header, `metadata_len` bytes of typed metadata fields and
+//! `payload_len` bytes of payload (a JPEG, an H.264 access unit, a snapshot or a control reply). Integers are big endian.
+//!
+//! | offset | size | field                                      |
+//! |--------|------|--------------------------------------------|
+//! | 0      | 4    | magic `USRS`                               |
+//! | 4      | 1    | protocol version                           |
+//! | 5      | 1    | frame kind                                 |
+//! | 6      | 2    | flags                                      |
+//! | 8      | 8    | sequence number                            |
+//! | 16     | 8    | capture time, µs since the UNIX epoch      |
+//! | 24     | 8    | send time, µs since the UNIX epoch         |
+//! | 32     | 4    | metadata length                            |
+//! | 36     | 4    | payload length                             |
+//! | 40     | 4    | reserved, 0                                |
+//! | 44     | 4    | FNV-1a hash of bytes 0..44                 |
+//!
+//! Each metadata field is a one byte tag, a two byte length and the value. Readers skip tags
+//! they don't know, so fields can be added without a new version. A header that doesn't check
+//! out means the reader lost its place, it then skips ahead to the next magic instead of
+//! trusting the lengths.
+//!
+//! On a Unix socket the payload may instead come as a memfd passed with the header, see `crate::memfd`.
+
+use std::io::Write;
+
+use bytes::Bytes;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::memfd::{Mapping, Received};
+
+pub const MAGIC: [u8; 4] = *b"USRS";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 48;
+
+/// Largest payload a header may announce, anything above is taken as a desync.
+pub const MAX_PAYLOAD: usize = 256 << 20;
+pub const MAX_METADATA: usize = 64 << 10;
+
+/// The picture didn't change since the previous frame, see `--drop-same-frames`.
+pub const FLAG_SKIP: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Jpeg,
+    H264,
+    /// A snapshot requested over the control channel, `Metadata::still` holds the request id.
+    Still,
+    /// Answer to a control request, `Metadata::request` holds its id, see `crate::control`.
+    Reply,
+    /// Sent by a newer image server, passed on so readers can skip it.
+    Other(u8),
+}
+
+impl FrameKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            FrameKind::Jpeg => 1,
+            FrameKind::H264 => 2,
+            FrameKind::Still => 3,
+            FrameKind::Reply => 4,
+            FrameKind::Other(kind) => kind,
+        }
+    }
+
+    fn from_byte(kind: u8) -> Self {
+        match kind {
+            1 => FrameKind::Jpeg,
+            2 => FrameKind::H264,
+            3 => FrameKind::Still,
+            4 => FrameKind::Reply,
+            kind => FrameKind::Other(kind),
+        }
+    }
+}
+
+const TAG_WIDTH: u8 = 1;
+const TAG_HEIGHT: u8 = 2;
+const TAG_PIXEL_FORMAT: u8 = 3;
+const TAG_ENCODER: u8 = 4;
+const TAG_FPS: u8 = 5;
+const TAG_QUALITY: u8 = 6;
+const TAG_IDENTICAL: u8 = 7;
+const TAG_FALLBACK: u8 = 8;
+const TAG_DIRTY: u8 = 9;
+const TAG_STILL: u8 = 10;
+const TAG_REQUEST: u8 = 11;
+const TAG_MEMFD: u8 = 12;
+
+/// Details about the stream sent along with each frame.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub pixel_format: Option<String>,
+    pub encoder: Option<String>,
+    pub fps: Option<u32>,
+    pub quality: Option<u8>,
+    /// Frames dropped so far as identical.
+    pub identical: Option<u64>,
+    /// Why the encoder in use is not the first choice.
+    pub fallback: Option<String>,
+    /// Changed tiles, see `DirtyMap`.
+    pub dirty: Option<String>,
+    /// Id of the snapshot request a `Still` frame answers.
+    pub still: Option<u32>,
+    /// Id of the control request a `Reply` frame answers.
+    pub request: Option<u32>,
+    /// Length of a payload passed as a memfd along with the header, the frame itself carries none.
+    pub memfd: Option<u32>,
+}
+
+impl Metadata {
+    fn encode(&self, out: &mut Vec<u8>) {
+        fn field(out: &mut Vec<u8>, tag: u8, value: &[u8]) {
+            let value = &value[..value.len().min(u16::MAX as usize)];
+            out.push(tag);
+            out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            out.extend_from_slice(value);
+        }
+        let numbers = [
+            (TAG_WIDTH, self.width.map(u64::from), 4),
+            (TAG_HEIGHT, self.height.map(u64::from), 4),
+            (TAG_FPS, self.fps.map(u64::from), 4),
+            (TAG_QUALITY, self.quality.map(u64::from), 1),
+            (TAG_IDENTICAL, self.identical, 8),
+            (TAG_STILL, self.still.map(u64::from), 4),
+            (TAG_REQUEST, self.request.map(u64::from), 4),
+            (TAG_MEMFD, self.memfd.map(u64::from), 4),
+        ];
+        for (tag, value, size) in numbers {
+            if let Some(value) = value {
+                field(out, tag, &value.to_be_bytes()[8 - size..]);
+            }
+        }
+        let texts = [
+            (TAG_PIXEL_FORMAT, &self.pixel_format),
+            (TAG_ENCODER, &self.encoder),
+            (TAG_FALLBACK, &self.fallback),
+            (TAG_DIRTY, &self.dirty),
+        ];
+        for (tag, value) in texts {
+            if let Some(value) = value {
+                field(out, tag, value.as_bytes());
+            }
+        }
+    }
+
+    fn decode(mut data: &[u8]) -> Result<Self, ProtocolError> {
+        let mut metadata = Metadata::default();
+        while !data.is_empty() {
+            if data.len() < 3 {
+                return Err(ProtocolError::Metadata("truncated field"));
+            }
+            let tag = data[0];
+            let len = u16::from_be_bytes([data[1], data[2]]) as usize;
+            let value = data.get(3..3 + len).ok_or(ProtocolError::Metadata("truncated field"))?;
+            data = &data[3 + len..];
+
+            let number = || -> Result<u64, ProtocolError> {
+                if value.len() > 8 {
+                    return Err(ProtocolError::Metadata("number too long"));
+                }
+                Ok(value.iter().fold(0u64, |number, &byte| number << 8 | byte as u64))
+            };
+            let text = || String::from_utf8(value.to_vec()).map_err(|_| ProtocolError::Metadata("text is not UTF-8"));
+            match tag {
+                TAG_WIDTH => metadata.width = Some(number()? as u32),
+                TAG_HEIGHT => metadata.height = Some(number()? as u32),
+                TAG_PIXEL_FORMAT => metadata.pixel_format = Some(text()?),
+                TAG_ENCODER => metadata.encoder = Some(text()?),
+                TAG_FPS => metadata.fps = Some(number()? as u32),
+                TAG_QUALITY => metadata.quality = Some(number()? as u8),
+                TAG_IDENTICAL => metadata.identical = Some(number()?),
+                TAG_FALLBACK => metadata.fallback = Some(text()?),
+                TAG_DIRTY => metadata.dirty = Some(text()?),
+                TAG_STILL => metadata.still = Some(number()? as u32),
+                TAG_REQUEST => metadata.request = Some(number()? as u32),
+                TAG_MEMFD => metadata.memfd = Some(number()? as u32),
+                _ => {}
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub flags: u16,
+    pub sequence: u64,
+    pub captured_us: u64,
+    pub sent_us: u64,
+    pub metadata: Metadata,
+    /// Shared rather than owned, so a payload mapped from a memfd is handed on without a copy.
+    pub payload: Bytes,
+}
+
+impl Frame {
+    pub fn new(kind: FrameKind, payload: impl Into<Bytes>) -> Self {
+        Frame { kind, flags: 0, sequence: 0, captured_us: 0, sent_us: 0, metadata: Metadata::default(), payload: payload.into() }
+    }
+
+    pub fn has_flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// The header and metadata, everything that goes before the payload.
+    pub fn header(&self) -> Vec<u8> {
+        self.header_for(self.payload.len())
+    }
+
+    /// The header for `payload_len` bytes of payload kept outside the frame.
+    pub fn header_for(&self, payload_len: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + 128);
+        out.resize(HEADER_LEN, 0);
+        self.metadata.encode(&mut out);
+        let metadata_len = (out.len() - HEADER_LEN) as u32;
+
+        out[0..4].copy_from_slice(&MAGIC);
+        out[4] = VERSION;
+        out[5] = self.kind.to_byte();
+        out[6..8].copy_from_slice(&self.flags.to_be_bytes());
+        out[8..16].copy_from_slice(&self.sequence.to_be_bytes());
+        out[16..24].copy_from_slice(&self.captured_us.to_be_bytes());
+        out[24..32].copy_from_slice(&self.sent_us.to_be_bytes());
+        out[32..36].copy_from_slice(&metadata_len.to_be_bytes());
+        out[36..40].copy_from_slice(&(payload_len as u32).to_be_bytes());
+        let check = fnv1a(&out[..44]);
+        out[44..48].copy_from_slice(&check.to_be_bytes());
+        out
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        self.write_with(&self.payload, writer)
+    }
+
+    /// Writes the frame with `payload` in place of its own, e.g. one shared by several consumers.
+    pub fn write_with(&self, payload: &[u8], writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&self.header_for(payload.len()))?;
+        writer.write_all(payload)
+    }
+}
+
+/// Current time in µs since the UNIX epoch, the unit of the header timestamps.
+pub fn now_us() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |since| since.as_micros() as u64)
+}
+
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5u32, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The stream lost its framing, `skipped` bytes were dropped to get back in step.
+    Desync { skipped: usize },
+    /// A frame of a protocol version this reader doesn't speak was skipped.
+    Version(u8),
+    /// A frame with malformed metadata was skipped.
+    Metadata(&'static str),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Desync { skipped } => write!(f, "stream out of sync, skipped {} bytes", skipped),
+            ProtocolError::Version(version) => write!(f, "skipped frame of unsupported protocol version {}", version),
+            ProtocolError::Metadata(e) => write!(f, "skipped frame with bad metadata: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+struct Header {
+    version: u8,
+    kind: FrameKind,
+    flags: u16,
+    sequence: u64,
+    captured_us: u64,
+    sent_us: u64,
+    metadata_len: usize,
+    payload_len: usize,
+}
+
+fn parse_header(data: &[u8]) -> Option<Header> {
+    let u32_at = |at: usize| u32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+    let u64_at = |at: usize| u64::from_be_bytes(data[at..at + 8].try_into().unwrap());
+    if data[0..4] != MAGIC || u32_at(44) != fnv1a(&data[..44]) {
+        return None;
+    }
+    let header = Header {
+        version: data[4],
+        kind: FrameKind::from_byte(data[5]),
+        flags: u16::from_be_bytes([data[6], data[7]]),
+        sequence: u64_at(8),
+        captured_us: u64_at(16),
+        sent_us: u64_at(24),
+        metadata_len: u32_at(32) as usize,
+        payload_len: u32_at(36) as usize,
+    };
+    (header.metadata_len <= MAX_METADATA && header.payload_len <= MAX_PAYLOAD).then_some(header)
+}
+
+/// Splits a byte stream back into frames, independent of where the bytes come from.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    start: usize,
+    /// Bytes drained from the front of `buffer`.
+    drained: u64,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        if self.start > 0 && self.start * 2 >= self.buffer.len() {
+            self.buffer.drain(..self.start);
+            self.drained += self.start as u64;
+            self.start = 0;
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Stream offset of the next byte to decode, where the next frame starts.
+    pub fn position(&self) -> u64 {
+        self.drained + self.start as u64
+    }
+
+    /// Returns the next complete frame, or `None` until more bytes arrive.
+    ///
+    /// Errors are reported once the decoder has already recovered from them, so calling
+    /// `decode` again continues with the next frame.
+    pub fn decode(&mut self) -> Result<Option<Frame>, ProtocolError> {
+        let data = &self.buffer[self.start..];
+        if data.len() < HEADER_LEN {
+            if MAGIC.starts_with(&data[..data.len().min(MAGIC.len())]) {
+                return Ok(None);
+            }
+            return Err(ProtocolError::Desync { skipped: self.resync() });
+        }
+        let Some(header) = parse_header(&data[..HEADER_LEN]) else {
+            return Err(ProtocolError::Desync { skipped: self.resync() });
+        };
+        let total = HEADER_LEN + header.metadata_len + header.payload_len;
+        if data.len() < total {
+            return Ok(None);
+        }
+        let frame = &data[..total];
+        self.start += total;
+
+        if header.version != VERSION {
+            return Err(ProtocolError::Version(header.version));
+        }
+        let (metadata, payload) = frame[HEADER_LEN..].split_at(header.metadata_len);
+        Ok(Some(Frame {
+            kind: header.kind,
+            flags: header.flags,
+            sequence: header.sequence,
+            captured_us: header.captured_us,
+            sent_us: header.sent_us,
+            metadata: Metadata::decode(metadata)?,
+            payload: Bytes::copy_from_slice(payload),
+        }))
+    }
+
+    /// Drops bytes up to the next place a header could start, returns how many.
+    fn resync(&mut self) -> usize {
+        let data = &self.buffer[self.start..];
+        let skipped = data
+            .windows(MAGIC.len())
+            .skip(1)
+            .position(|window| window == MAGIC)
+            .map_or(data.len().saturating_sub(MAGIC.len() - 1).max(1), |at| at + 1)
+            .min(data.len());
+        self.start += skipped;
+        skipped
+    }
+}
+
+/// Reads frames from an async stream such as the image server socket.
+pub struct FrameReader<R> {
+    reader: R,
+    decoder: FrameDecoder,
+    chunk: Vec<u8>,
+    /// Descriptors passed along with the stream, for frames whose payload is a memfd.
+    fds: Option<Received>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        FrameReader { reader, decoder: FrameDecoder::new(), chunk: vec![0; 256 << 10], fds: None }
+    }
+
+    /// Reads frames from a `memfd::FdReader`, taking the payloads passed as memfds from `fds`.
+    pub fn with_fds(reader: R, fds: Received) -> Self {
+        FrameReader { fds: Some(fds), ..Self::new(reader) }
+    }
+
+    /// Fills in a payload that came as a memfd, the mapping itself becomes the payload.
+    /// Frames whose memfd is missing or unusable are dropped.
+    ///
+    /// `at` is the stream offset of the frame. Descriptors that arrived before it belong to
+    /// frames that were skipped, e.g. with bad metadata, and are closed.
+    fn take_memfd(&self, frame: &mut Frame, len: u32, at: u64) -> Result<(), String> {
+        let fd = self.fds.as_ref()
+            .and_then(|fds| {
+                let mut fds = fds.lock().ok()?;
+                while fds.front().is_some_and(|&(arrived, _)| arrived <= at) {
+                    fds.pop_front();
+                }
+                fds.pop_front().map(|(_, fd)| fd)
+            })
+            .ok_or_else(|| format!("frame {} came without its memfd", frame.sequence))?;
+        let mapping = Mapping::new(&fd, len as usize).map_err(|e| format!("failed to map memfd of frame {}: {}", frame.sequence, e))?;
+        frame.payload = Bytes::from_owner(mapping);
+        Ok(())
+    }
+
+    /// Waits for the next frame. Desyncs and unreadable frames are logged and skipped,
+    /// only a failed or closed stream is returned as an error.
+    pub async fn next_frame(&mut self) -> std::io::Result<Frame> {
+        loop {
+            let at = self.decoder.position();
+            match self.decoder.decode() {
+                Ok(Some(mut frame)) => match frame.metadata.memfd {
+                    Some(len) => match self.take_memfd(&mut frame, len, at) {
+                        Ok(()) => return Ok(frame),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            continue;
+                        }
+                    },
+                    None => return Ok(frame),
+                },
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("{}", e);
+                    continue;
+                }
+            }
+            let read = self.reader.read(&mut self.chunk).await?;
+            if read == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            self.decoder.extend(&self.chunk[..read]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(sequence: u64, payload: &[u8]) -> Frame {
+        Frame {
+            sequence,
+            flags: FLAG_SKIP,
+            captured_us: 1_000,
+            sent_us: 2_000,
+            metadata: Metadata {
+                width: Some(1920),
+                height: Some(1080),
+                pixel_format: Some("NV24".to_string()),
+                quality: Some(80),
+                identical: Some(1 << 40),
+                dirty: Some("64:1x1:8".to_string()),
+                ..Metadata::default()
+            },
+            ..Frame::new(FrameKind::Jpeg, payload.to_vec())
+        }
+    }
+
+    fn bytes(frame: &Frame) -> Vec<u8> {
+        let mut out = Vec::new();
+        frame.write_to(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn frames_round_trip_in_pieces() {
+        let frames = [frame(1, &[0xFF, 0xD8, 0xFF, 0xD9]), frame(2, &[]), Frame::new(FrameKind::Other(9), vec![1; 1000])];
+        let stream: Vec<u8> = frames.iter().flat_map(bytes).collect();
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        for piece in stream.chunks(7) {
+            decoder.extend(piece);
+            while let Some(frame) = decoder.decode().unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn garbage_is_skipped() {
+        let mut stream = vec![0xAB; 10];
+        stream.extend(bytes(&frame(1, b"one")));
+        let mut corrupt = bytes(&frame(2, b"two"));
+        corrupt[40] ^= 1;
+        stream.extend(corrupt);
+        stream.extend(bytes(&frame(3, b"three")));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&stream);
+        assert_eq!(decoder.decode(), Err(ProtocolError::Desync { skipped: 10 }));
+        assert_eq!(decoder.decode().unwrap().unwrap().sequence, 1);
+        assert!(matches!(decoder.decode(), Err(ProtocolError::Desync { .. })));
+        assert_eq!(decoder.decode().unwrap().unwrap().sequence, 3);
+        assert_eq!(decoder.decode(), Ok(None));
+    }
+
+    #[test]
+    fn other_versions_and_unknown_fields_are_skipped() {
+        let mut newer = bytes(&frame(1, b"new"));
+        newer[4] = VERSION + 1;
+        let check = fnv1a(&newer[..44]);
+        newer[44..48].copy_from_slice(&check.to_be_bytes());
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&newer);
+        decoder.extend(&bytes(&frame(2, b"current")));
+        assert_eq!(decoder.decode(), Err(ProtocolError::Version(VERSION + 1)));
+        assert_eq!(decoder.decode().unwrap().unwrap().payload, &b"current"[..]);
+
+        let mut fields = vec![200, 0, 2, 1, 2];
+        Metadata { fps: Some(30), ..Metadata::default() }.encode(&mut fields);
+        assert_eq!(Metadata::decode(&fields), Ok(Metadata { fps: Some(30), ..Metadata::default() }));
+    }
+}
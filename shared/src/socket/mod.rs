@@ -0,0 +1,183 @@
+//! Unix sockets between the image server, the web servers and their HTTP clients.
+//!
+//! A socket lives at a file path, or in the Linux abstract namespace when written `@name`.
+//! Abstract sockets need no writable directory and vanish with the process, but have no file
+//! mode or owner, anyone in the same network namespace can connect to them.
+
+use std::fmt;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use nix::unistd::{Group, User};
+
+/// Where the image server listens for web servers unless told otherwise, next to the web server sources.
+pub const DEFAULT_IMAGE_SOCKET: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../server/ustreamer_rs.sock");
+
+/// Where the web servers listen for HTTP unless told otherwise, kvmd's nginx proxies this one.
+pub const DEFAULT_HTTP_SOCKET: &str = "/run/kvmd/ustreamer.sock";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketPath {
+    File(PathBuf),
+    /// A name in the abstract namespace, without the leading `@`.
+    Abstract(String),
+}
+
+impl SocketPath {
+    fn addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            SocketPath::File(path) => SocketAddr::from_pathname(path),
+            SocketPath::Abstract(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        }
+    }
+
+    /// Connects to a server listening here.
+    pub fn connect(&self) -> io::Result<UnixStream> {
+        UnixStream::connect_addr(&self.addr()?)
+    }
+
+    /// Connects to a server listening here, for tokio. Local connects don't block, so this doesn't either.
+    pub async fn connect_async(&self) -> io::Result<tokio::net::UnixStream> {
+        let stream = self.connect()?;
+        stream.set_nonblocking(true)?;
+        tokio::net::UnixStream::from_std(stream)
+    }
+}
+
+impl FromStr for SocketPath {
+    type Err = String;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        match path.strip_prefix('@') {
+            Some("") => Err("abstract socket name is empty".to_string()),
+            Some(name) => Ok(SocketPath::Abstract(name.to_string())),
+            None if path.is_empty() => Err("socket path is empty".to_string()),
+            None => Ok(SocketPath::File(PathBuf::from(path))),
+        }
+    }
+}
+
+impl fmt::Display for SocketPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocketPath::File(path) => write!(f, "{}", path.display()),
+            SocketPath::Abstract(name) => write!(f, "@{}", name),
+        }
+    }
+}
+
+/// A socket to listen on and who may connect to it. Mode and owner only apply to file sockets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenOptions {
+    pub path: SocketPath,
+    /// Permissions of the socket file, the umask decides when `None`.
+    pub mode: Option<u32>,
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+}
+
+impl ListenOptions {
+    pub fn new(path: SocketPath) -> Self {
+        ListenOptions { path, mode: None, owner: None, group: None }
+    }
+
+    /// Binds the socket, replacing a file left behind by an earlier run, and applies mode and owner.
+    pub fn bind(&self) -> io::Result<UnixListener> {
+        let SocketPath::File(path) = &self.path else {
+            return UnixListener::bind_addr(&self.path.addr()?);
+        };
+        std::fs::remove_file(path).ok();
+        let listener = UnixListener::bind(path)?;
+        if let Some(mode) = self.mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        if self.owner.is_some() || self.group.is_some() {
+            std::os::unix::fs::chown(path, self.owner, self.group)?;
+        }
+        Ok(listener)
+    }
+
+    /// `bind` for tokio, has to be called from within the runtime.
+    pub fn bind_async(&self) -> io::Result<tokio::net::UnixListener> {
+        let listener = self.bind()?;
+        listener.set_nonblocking(true)?;
+        tokio::net::UnixListener::from_std(listener)
+    }
+}
+
+/// Parses an octal file mode such as `660`.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8).ok().filter(|mode| *mode <= 0o777).ok_or_else(|| format!("{} is not an octal file mode", mode))
+}
+
+/// Parses a user name or uid.
+pub fn parse_user(user: &str) -> Result<u32, String> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    match User::from_name(user) {
+        Ok(Some(user)) => Ok(user.uid.as_raw()),
+        Ok(None) => Err(format!("no user named {}", user)),
+        Err(e) => Err(format!("failed to look up user {}: {}", user, e)),
+    }
+}
+
+/// Parses a group name or gid.
+pub fn parse_group(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    match Group::from_name(group) {
+        Ok(Some(group)) => Ok(group.gid.as_raw()),
+        Ok(None) => Err(format!("no group named {}", group)),
+        Err(e) => Err(format!("failed to look up group {}: {}", group, e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn parse_paths() {
+        assert_eq!("/run/kvmd/ustreamer.sock".parse(), Ok(SocketPath::File(PathBuf::from("/run/kvmd/ustreamer.sock"))));
+        assert_eq!("@ustreamer".parse(), Ok(SocketPath::Abstract("ustreamer".to_string())));
+        assert!("@".parse::<SocketPath>().is_err());
+        assert!("".parse::<SocketPath>().is_err());
+        assert_eq!(SocketPath::Abstract("ustreamer".to_string()).to_string(), "@ustreamer");
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert!(parse_mode("999").is_err());
+        assert_eq!(parse_user("0"), Ok(0));
+        assert_eq!(parse_group("root"), Ok(0));
+    }
+
+    fn round_trip(options: &ListenOptions) {
+        let listener = options.bind().unwrap();
+        let mut client = options.path.connect().unwrap();
+        client.write_all(b"keyframe\n").unwrap();
+        let mut line = [0; 9];
+        listener.accept().unwrap().0.read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"keyframe\n");
+    }
+
+    #[test]
+    fn file_sockets_get_their_mode() {
+        let path = std::env::temp_dir().join(format!("ustreamer-rs-test-{}.sock", std::process::id()));
+        let options = ListenOptions { mode: Some(0o600), ..ListenOptions::new(SocketPath::File(path.clone())) };
+        // A file left behind by an earlier run is replaced.
+        std::fs::write(&path, b"").unwrap();
+        round_trip(&options);
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn abstract_sockets_need_no_file() {
+        round_trip(&ListenOptions::new(SocketPath::Abstract(format!("ustreamer-rs-test-{}", std::process::id()))));
+    }
+}
@@ -0,0 +1,450 @@
+//! TCP transport between an image server and a web server on another host.
+//!
+//! After connecting, and after the TLS handshake when TLS is on, both sides prove they know the
+//! pre-shared key before anything else is sent:
+//!
+//! | from   | line                                   |
+//! |--------|----------------------------------------|
+//! | server | `USTR-AUTH 1 <server nonce>`           |
+//! | client | `<client nonce> <client proof>`        |
+//! | server | `ok <server proof>` or `error <reason>`|
+//!
+//! Nonces are 16 random bytes, proofs HMAC-SHA256 over the role (`client` or `server`) and both
+//! nonces, everything hex encoded. Then the connection carries frames and control lines exactly
+//! like the Unix socket.
+
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use nix::sys::socket::{setsockopt, sockopt};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::memfd::FdReader;
+use crate::protocol::FrameReader;
+use crate::socket::SocketPath;
+
+/// Shortest key accepted, anything shorter can be guessed.
+pub const PSK_MIN_LEN: usize = 16;
+
+/// How long either side waits for the other during the handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const GREETING: &str = "USTR-AUTH 1";
+const NONCE_LEN: usize = 16;
+/// Longest handshake line either side reads, so a stranger can't make it buffer forever.
+const MAX_LINE: usize = 256;
+
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// The key both sides share, read from a file so it doesn't show up in the process list.
+#[derive(Clone)]
+pub struct Psk(Vec<u8>);
+
+impl Psk {
+    pub fn new(key: impl Into<Vec<u8>>) -> io::Result<Self> {
+        let key = key.into();
+        if key.len() < PSK_MIN_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("key is shorter than {} bytes", PSK_MIN_LEN)));
+        }
+        Ok(Psk(key))
+    }
+
+    /// Reads the key from a file, ignoring a trailing newline.
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut key = std::fs::read(path)?;
+        while key.last().is_some_and(|byte| byte.is_ascii_whitespace()) {
+            key.pop();
+        }
+        Self::new(key)
+    }
+
+    fn proof(&self, role: &str, server_nonce: &[u8], client_nonce: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any length");
+        mac.update(role.as_bytes());
+        mac.update(server_nonce);
+        mac.update(client_nonce);
+        mac
+    }
+
+    fn verify(&self, role: &str, server_nonce: &[u8], client_nonce: &[u8], proof: &str) -> bool {
+        match from_hex(proof) {
+            Some(proof) => self.proof(role, server_nonce, client_nonce).verify_slice(&proof).is_ok(),
+            None => false,
+        }
+    }
+}
+
+impl std::fmt::Debug for Psk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Psk(..)")
+    }
+}
+
+fn denied(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, reason.to_string())
+}
+
+fn nonce() -> io::Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0; NONCE_LEN];
+    std::io::Read::read_exact(&mut std::fs::File::open("/dev/urandom")?, &mut nonce)?;
+    Ok(nonce)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Reads one handshake line byte by byte, so nothing that follows it is consumed.
+async fn read_line<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut line = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == b'\n' {
+            return String::from_utf8(line).map_err(|_| denied("handshake is not text"));
+        }
+        if line.len() >= MAX_LINE {
+            return Err(denied("handshake line too long"));
+        }
+        line.push(byte);
+    }
+}
+
+/// The image server's side of the handshake, fails if the client doesn't know the key.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, psk: &Psk) -> io::Result<()> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let server_nonce = nonce()?;
+        stream.write_all(format!("{} {}\n", GREETING, to_hex(&server_nonce)).as_bytes()).await?;
+        let line = read_line(stream).await?;
+        let (client_nonce, proof) = line.split_once(' ').ok_or_else(|| denied("malformed handshake"))?;
+        let client_nonce = from_hex(client_nonce).filter(|nonce| nonce.len() == NONCE_LEN).ok_or_else(|| denied("malformed nonce"))?;
+        if !psk.verify("client", &server_nonce, &client_nonce, proof) {
+            stream.write_all(b"error wrong key\n").await?;
+            return Err(denied("client used the wrong key"));
+        }
+        let proof = psk.proof("server", &server_nonce, &client_nonce).finalize().into_bytes();
+        stream.write_all(format!("ok {}\n", to_hex(&proof)).as_bytes()).await?;
+        stream.flush().await
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+}
+
+/// The web server's side of the handshake, fails if the server doesn't know the key.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, psk: &Psk) -> io::Result<()> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let line = read_line(stream).await?;
+        let server_nonce = line
+            .strip_prefix(GREETING)
+            .and_then(|nonce| from_hex(nonce.trim_start()))
+            .filter(|nonce| nonce.len() == NONCE_LEN)
+            .ok_or_else(|| denied("not an image server"))?;
+        let client_nonce = nonce()?;
+        let proof = psk.proof("client", &server_nonce, &client_nonce).finalize().into_bytes();
+        stream.write_all(format!("{} {}\n", to_hex(&client_nonce), to_hex(&proof)).as_bytes()).await?;
+        stream.flush().await?;
+        let line = read_line(stream).await?;
+        match line.split_once(' ') {
+            Some(("ok", proof)) if psk.verify("server", &server_nonce, &client_nonce, proof) => Ok(()),
+            Some(("ok", _)) => Err(denied("server used the wrong key")),
+            Some(("error", reason)) => Err(denied(reason)),
+            _ => Err(denied("malformed handshake")),
+        }
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?
+}
+
+/// Turns off Nagle and has the kernel notice a peer that went away without closing, e.g. a
+/// host that lost power while capture is idle and no frames flow.
+pub fn tune(stream: &TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let fd = stream.as_fd();
+    setsockopt(&fd, sockopt::KeepAlive, &true)?;
+    setsockopt(&fd, sockopt::TcpKeepIdle, &10)?;
+    setsockopt(&fd, sockopt::TcpKeepInterval, &5)?;
+    setsockopt(&fd, sockopt::TcpKeepCount, &3)?;
+    Ok(())
+}
+
+/// Waits between connection attempts, longer after every failure up to `max`.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff { min, max, next: min }
+    }
+
+    /// The wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Starts over after a connection that worked.
+    pub fn reset(&mut self) {
+        self.next = self.min;
+    }
+}
+
+/// TCP address of an image server and how to authenticate it.
+#[derive(Clone)]
+pub struct TcpTarget {
+    /// `host:port`
+    pub addr: String,
+    pub psk: Psk,
+    pub tls: Option<tls::Client>,
+}
+
+/// Where a web server gets its frames from.
+#[derive(Clone)]
+pub enum ImageServer {
+    Unix(SocketPath),
+    Tcp(TcpTarget),
+}
+
+impl ImageServer {
+    /// Connects and authenticates, returns the halves frames are read from and control lines written to.
+    pub async fn connect(&self) -> io::Result<(Reader, Writer)> {
+        match self {
+            ImageServer::Unix(path) => {
+                let (reader, writer) = path.connect_async().await?.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+            ImageServer::Tcp(target) => {
+                let mut stream = TcpStream::connect(&target.addr).await?;
+                tune(&stream)?;
+                if let Some(tls) = &target.tls {
+                    let mut stream = tls.connect(stream).await?;
+                    connect(&mut stream, &target.psk).await?;
+                    let (reader, writer) = tokio::io::split(stream);
+                    return Ok((Box::new(reader), Box::new(writer)));
+                }
+                connect(&mut stream, &target.psk).await?;
+                let (reader, writer) = stream.into_split();
+                Ok((Box::new(reader), Box::new(writer)))
+            }
+        }
+    }
+
+    /// `connect` with the frames read from the image server already split. With `memfd` set a Unix
+    /// socket also takes the memfds frames are passed in, they still have to be asked for with `memfd=1`.
+    pub async fn connect_frames(&self, memfd: bool) -> io::Result<(FrameReader<Reader>, Writer)> {
+        if let (ImageServer::Unix(path), true) = (self, memfd) {
+            let (reader, writer) = path.connect_async().await?.into_split();
+            let reader = FdReader::new(reader);
+            let received = reader.received();
+            return Ok((FrameReader::with_fds(Box::new(reader), received), Box::new(writer)));
+        }
+        let (reader, writer) = self.connect().await?;
+        Ok((FrameReader::new(reader), writer))
+    }
+}
+
+impl std::fmt::Display for ImageServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageServer::Unix(path) => write!(f, "{}", path),
+            ImageServer::Tcp(target) => write!(f, "tcp://{}", target.addr),
+        }
+    }
+}
+
+/// A consumer that connected over TCP and proved it knows the key.
+pub struct Authenticated {
+    pub peer: SocketAddr,
+    pub reader: Reader,
+    pub writer: Writer,
+}
+
+/// Image server side: tunes, wraps in TLS if configured, then authenticates a freshly accepted connection.
+pub async fn authenticate(stream: TcpStream, peer: SocketAddr, psk: &Psk, tls: Option<&tls::Server>) -> io::Result<Authenticated> {
+    tune(&stream)?;
+    if let Some(tls) = tls {
+        let mut stream = tls.accept(stream).await?;
+        accept(&mut stream, psk).await?;
+        let (reader, writer) = tokio::io::split(stream);
+        return Ok(Authenticated { peer, reader: Box::new(reader), writer: Box::new(writer) });
+    }
+    let mut stream = stream;
+    accept(&mut stream, psk).await?;
+    let (reader, writer) = stream.into_split();
+    Ok(Authenticated { peer, reader: Box::new(reader), writer: Box::new(writer) })
+}
+
+/// Certificates for the TCP transport. The image server presents a certificate and key, the web
+/// server checks it against a CA, which may simply be the image server's self-signed certificate.
+#[cfg(feature = "tls")]
+pub mod tls {
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, ServerConfig};
+    use tokio_rustls::{TlsAcceptor, TlsConnector, client, server};
+
+    fn invalid(e: impl std::fmt::Display) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+    }
+
+    fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+        Arc::new(rustls::crypto::ring::default_provider())
+    }
+
+    fn certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+        CertificateDer::pem_file_iter(path).map_err(invalid)?.collect::<Result<_, _>>().map_err(invalid)
+    }
+
+    #[derive(Clone)]
+    pub struct Server(TlsAcceptor);
+
+    impl Server {
+        /// Loads a PEM certificate chain and its private key.
+        pub fn from_pem(cert: &Path, key: &Path) -> io::Result<Self> {
+            let key = PrivateKeyDer::from_pem_file(key).map_err(invalid)?;
+            let config = ServerConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .map_err(invalid)?
+                .with_no_client_auth()
+                .with_single_cert(certificates(cert)?, key)
+                .map_err(invalid)?;
+            Ok(Server(TlsAcceptor::from(Arc::new(config))))
+        }
+
+        pub async fn accept(&self, stream: TcpStream) -> io::Result<server::TlsStream<TcpStream>> {
+            self.0.accept(stream).await
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Client {
+        connector: TlsConnector,
+        name: ServerName<'static>,
+    }
+
+    impl Client {
+        /// Trusts the PEM certificates in `ca` and expects the server's certificate to be issued for `name`.
+        pub fn from_pem(ca: &Path, name: &str) -> io::Result<Self> {
+            let mut roots = RootCertStore::empty();
+            for cert in certificates(ca)? {
+                roots.add(cert).map_err(invalid)?;
+            }
+            let config = ClientConfig::builder_with_provider(provider())
+                .with_safe_default_protocol_versions()
+                .map_err(invalid)?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let name = ServerName::try_from(name.to_string()).map_err(invalid)?;
+            Ok(Client { connector: TlsConnector::from(Arc::new(config)), name })
+        }
+
+        pub async fn connect(&self, stream: TcpStream) -> io::Result<client::TlsStream<TcpStream>> {
+            self.connector.connect(self.name.clone(), stream).await
+        }
+    }
+}
+
+/// Stand-ins that refuse to load, so `--tls-*` options fail with a clear message.
+#[cfg(not(feature = "tls"))]
+pub mod tls {
+    use std::io;
+    use std::path::Path;
+
+    use tokio::net::TcpStream;
+
+    fn not_compiled_in() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "TLS support is not compiled in, build with --features tls")
+    }
+
+    #[derive(Clone)]
+    pub struct Server(());
+
+    impl Server {
+        pub fn from_pem(_cert: &Path, _key: &Path) -> io::Result<Self> {
+            Err(not_compiled_in())
+        }
+
+        pub async fn accept(&self, _stream: TcpStream) -> io::Result<TcpStream> {
+            Err(not_compiled_in())
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Client(());
+
+    impl Client {
+        pub fn from_pem(_ca: &Path, _name: &str) -> io::Result<Self> {
+            Err(not_compiled_in())
+        }
+
+        pub async fn connect(&self, _stream: TcpStream) -> io::Result<TcpStream> {
+            Err(not_compiled_in())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn psk(key: &str) -> Psk {
+        Psk::new(key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn both_sides_prove_the_key() {
+        let (mut server, mut client) = tokio::io::duplex(1024);
+        let key = psk("correct horse battery staple");
+        let (accepted, connected) = tokio::join!(accept(&mut server, &key), connect(&mut client, &key));
+        assert!(accepted.is_ok() && connected.is_ok());
+
+        // Frames that follow the handshake are left for the frame reader.
+        server.write_all(b"frame").await.unwrap();
+        let mut frame = [0; 5];
+        client.read_exact(&mut frame).await.unwrap();
+        assert_eq!(&frame, b"frame");
+    }
+
+    #[tokio::test]
+    async fn wrong_key_is_refused() {
+        let (mut server, mut client) = tokio::io::duplex(1024);
+        let (key, wrong) = (psk("correct horse battery staple"), psk("incorrect horse battery staple"));
+        let (accepted, connected) = tokio::join!(accept(&mut server, &key), connect(&mut client, &wrong));
+        assert_eq!(accepted.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(connected.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn short_keys_and_backoff() {
+        assert!(Psk::new("hunter2").is_err());
+        assert_eq!(from_hex(&to_hex(&[0, 0xAB, 0xFF])), Some(vec![0, 0xAB, 0xFF]));
+        assert_eq!(from_hex("abc"), None);
+
+        let mut backoff = Backoff::new(Duration::from_millis(200), Duration::from_secs(1));
+        let delays: Vec<_> = (0..4).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, [200, 400, 800, 1000]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(200));
+    }
+}
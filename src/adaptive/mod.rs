@@ -0,0 +1,118 @@
+/// What the adaptive quality controller aims for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateTarget {
+    /// Average encoded bytes per second.
+    BytesPerSecond(u64),
+    /// Encoded bytes per frame.
+    FrameSize(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveConfig {
+    pub target: RateTarget,
+    pub min_quality: u8,
+    pub max_quality: u8,
+}
+
+/// Adjusts JPEG quality per frame so the encoded stream stays within a bandwidth budget.
+///
+/// The size of recent frames is tracked as a moving average and compared to the per-frame
+/// budget. A send backlog reported by the web server larger than a couple of frames is treated
+/// as being over budget, since the link clearly can't keep up whatever the frame size.
+pub struct AdaptiveQuality {
+    config: AdaptiveConfig,
+    quality: u8,
+    avg_size: f64,
+}
+
+impl AdaptiveQuality {
+    pub fn new(config: AdaptiveConfig, initial: u8) -> Self {
+        AdaptiveQuality {
+            config,
+            quality: initial.clamp(config.min_quality, config.max_quality),
+            avg_size: 0.0,
+        }
+    }
+
+    pub fn quality(&self) -> u8 {
+        self.quality
+    }
+
+    /// Bytes one frame may use at the given frame rate.
+    pub fn frame_budget(&self, fps: u32) -> u64 {
+        match self.config.target {
+            RateTarget::BytesPerSecond(rate) => rate / fps.max(1) as u64,
+            RateTarget::FrameSize(size) => size,
+        }
+    }
+
+    /// Feeds the size of the last encoded frame and returns the quality for the next one.
+    /// `current` is the quality in use right now, so manual changes are picked up as the new starting point.
+    pub fn update(&mut self, current: u8, frame_size: usize, fps: u32, backlog: usize) -> u8 {
+        self.quality = current.clamp(self.config.min_quality, self.config.max_quality);
+        if frame_size == 0 {
+            return self.quality;
+        }
+
+        if self.avg_size == 0.0 {
+            self.avg_size = frame_size as f64;
+        } else {
+            self.avg_size = self.avg_size * 0.8 + frame_size as f64 * 0.2;
+        }
+
+        let budget = self.frame_budget(fps).max(1) as f64;
+        let ratio = self.avg_size / budget;
+        let congested = backlog as f64 > budget * 2.0;
+
+        let step: i16 = if congested || ratio > 1.5 {
+            -5
+        } else if ratio > 1.1 {
+            -2
+        } else if ratio < 0.7 {
+            2
+        } else if ratio < 0.9 {
+            1
+        } else {
+            0
+        };
+
+        self.quality = (self.quality as i16 + step).clamp(self.config.min_quality as i16, self.config.max_quality as i16) as u8;
+        self.quality
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn controller(target: RateTarget) -> AdaptiveQuality {
+        AdaptiveQuality::new(AdaptiveConfig { target, min_quality: 20, max_quality: 90 }, 80)
+    }
+
+    #[test]
+    fn lowers_quality_over_budget() {
+        let mut adaptive = controller(RateTarget::BytesPerSecond(3_000_000));
+        let mut quality = adaptive.quality();
+        for _ in 0..50 {
+            quality = adaptive.update(quality, 400_000, 30, 0);
+        }
+        assert_eq!(quality, 20);
+    }
+
+    #[test]
+    fn raises_quality_under_budget() {
+        let mut adaptive = controller(RateTarget::FrameSize(200_000));
+        let mut quality = 40;
+        for _ in 0..50 {
+            quality = adaptive.update(quality, 50_000, 30, 0);
+        }
+        assert_eq!(quality, 90);
+    }
+
+    #[test]
+    fn backlog_forces_quality_down() {
+        let mut adaptive = controller(RateTarget::FrameSize(100_000));
+        let quality = adaptive.update(80, 95_000, 30, 1_000_000);
+        assert_eq!(quality, 75);
+    }
+}
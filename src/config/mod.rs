@@ -1,6 +1,23 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize};
 use std::time::Duration;
 
 use clap::Parser;
+use clap::builder::TypedValueParser;
+
+use crate::{EncoderType, Subsampling};
+use crate::adaptive::{AdaptiveConfig, RateTarget};
+use crate::consumer::{ConsumerConfig, DEFAULT_QUEUE, DropPolicy};
+use crate::control::{CaptureStats, ResolutionChange};
+use crate::dedup::{ChangeConfig, DEFAULT_MAX_UNCHANGED};
+use crate::demand::{Demand, IdlePolicy};
+use crate::encoder::EncoderOptions;
+use crate::encoder::snapshot::RetainedFrame;
+use crate::memsink::SinkOptions;
+use crate::packet::FrameMetadata;
+use crate::socket::{DEFAULT_IMAGE_SOCKET, ListenOptions, SocketPath, parse_group, parse_mode, parse_user};
+use crate::transport::{Psk, TcpOptions, tls};
 
 #[derive(Parser, Debug)]
 #[command(about, long_about = None)]
@@ -15,11 +32,268 @@ pub struct Args {
     #[arg(short = 'd', long = "device", default_value = "/dev/video0")]
     pub device: String,
 
+    /// Drop captured frames that show the same picture as the last one sent.
     #[arg(short = 'e', long = "drop-same-frames")]
     pub drop_frames: bool,
 
+    /// Average change per byte, in levels, a part of the frame may show and still count as the same picture.
+    #[arg(long = "same-frame-tolerance", default_value_t = 0.0)]
+    pub same_frame_tolerance: f32,
+
+    /// Send one frame after this many dropped identical ones, 0 to keep dropping them.
+    #[arg(long = "max-unchanged-frames", default_value_t = DEFAULT_MAX_UNCHANGED)]
+    pub max_unchanged_frames: u32,
+
     #[arg(long = "exit-on-parent-death")]
     pub exit_on_parent_death: bool,
+
+    /// Encoders to try in order, the next one takes over when one fails. `auto` benchmarks them at startup.
+    #[arg(short = 'c', long = "encoder", value_enum, value_delimiter = ',', default_value = "mpp,cpu-pool,cpu")]
+    pub encoder: Vec<EncoderType>,
+
+    #[arg(short = 'q', long = "quality", default_value_t = 80, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    #[arg(short = 's', long = "subsampling", value_enum, default_value = "420")]
+    pub subsampling: Subsampling,
+
+    /// Number of cpu-pool encoder threads, 0 for one per CPU.
+    #[arg(short = 'w', long = "workers", default_value_t = 0)]
+    pub workers: usize,
+
+    /// Drop cpu-pool frames that would be shown later than this many milliseconds after capture.
+    #[arg(long = "max-latency")]
+    pub max_latency: Option<u64>,
+
+    /// Split each frame into this many strips encoded in parallel by the cpu encoder, 0 for 4K and above only.
+    #[arg(long = "strips", default_value_t = 0)]
+    pub strips: usize,
+
+    /// H.264 target bitrate in kbit/s.
+    #[arg(long = "bitrate", default_value_t = 4000)]
+    pub bitrate: u32,
+
+    /// H.264 keyframe interval in frames.
+    #[arg(long = "gop", default_value_t = 60)]
+    pub gop: u32,
+
+    /// Adapt quality to keep the stream under this many bytes per second.
+    #[arg(long = "target-rate", conflicts_with = "target_frame_size")]
+    pub target_rate: Option<u64>,
+
+    /// Adapt quality to keep each frame under this many bytes.
+    #[arg(long = "target-frame-size")]
+    pub target_frame_size: Option<u64>,
+
+    #[arg(long = "min-quality", default_value_t = 20, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub min_quality: u8,
+
+    #[arg(long = "max-quality", default_value_t = 95, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub max_quality: u8,
+
+    /// Track which tiles of each frame changed, send the map along with the frame and only re-encode changed cpu strips.
+    #[arg(long = "dirty-tiles")]
+    pub dirty_tiles: bool,
+
+    /// Embed sequence, capture time, resolution, pixel format and encoder in each JPEG as a `com` or `app` segment.
+    #[arg(long = "frame-metadata", value_enum)]
+    pub frame_metadata: Option<FrameMetadata>,
+
+    /// Keep capturing at the full rate while nobody is watching.
+    #[arg(long = "persistent", conflicts_with = "idle_fps")]
+    pub persistent: bool,
+
+    /// Frames per second to encode while nobody is watching, 0 to stop the device streaming until a viewer connects.
+    #[arg(long = "idle-fps", default_value_t = 0)]
+    pub idle_fps: u32,
+
+    /// Frames queued for each consumer of the image server socket before its drop policy applies.
+    #[arg(long = "consumer-queue", default_value_t = DEFAULT_QUEUE, value_parser = clap::value_parser!(u64).range(1..).map(|queue| queue as usize))]
+    pub consumer_queue: usize,
+
+    /// What a consumer that falls behind loses, consumers can pick their own with a `policy=` control line.
+    #[arg(long = "drop-policy", value_enum, default_value = "oldest")]
+    pub drop_policy: DropPolicy,
+
+    /// Socket the web servers and other consumers connect to, a path or `@name` for an abstract socket.
+    #[arg(long = "image-socket", default_value = DEFAULT_IMAGE_SOCKET)]
+    pub image_socket: SocketPath,
+
+    /// Octal permissions of the image server socket, the umask decides if not given.
+    #[arg(long = "image-socket-mode", value_parser = parse_mode)]
+    pub image_socket_mode: Option<u32>,
+
+    /// User name or uid to own the image server socket.
+    #[arg(long = "image-socket-owner", value_parser = parse_user)]
+    pub image_socket_owner: Option<u32>,
+
+    /// Group name or gid to own the image server socket.
+    #[arg(long = "image-socket-group", value_parser = parse_group)]
+    pub image_socket_group: Option<u32>,
+
+    /// Also serve consumers over TCP on this address, e.g. `0.0.0.0:7879`, for web servers on other hosts.
+    #[arg(long = "tcp", requires = "psk_file")]
+    pub tcp: Option<String>,
+
+    /// File holding the key TCP consumers have to prove they know, at least 16 bytes.
+    #[arg(long = "psk-file", requires = "tcp")]
+    pub psk_file: Option<PathBuf>,
+
+    /// PEM certificate chain to encrypt the TCP transport with.
+    #[arg(long = "tls-cert", requires_all = ["tls_key", "tcp"])]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`.
+    #[arg(long = "tls-key", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Shared-memory object to write JPEG frames to for kvmd, e.g. `kvmd::ustreamer::jpeg`.
+    #[arg(long = "jpeg-sink", alias = "sink")]
+    pub jpeg_sink: Option<String>,
+
+    /// Octal permissions of the JPEG sink.
+    #[arg(long = "jpeg-sink-mode", default_value = "660", value_parser = parse_mode)]
+    pub jpeg_sink_mode: u32,
+
+    /// Remove the JPEG sink on exit.
+    #[arg(long = "jpeg-sink-rm")]
+    pub jpeg_sink_rm: bool,
+
+    /// Seconds a JPEG sink client counts as connected after its last read.
+    #[arg(long = "jpeg-sink-client-ttl", default_value_t = 10)]
+    pub jpeg_sink_client_ttl: u64,
+
+    /// Seconds to wait for a JPEG sink client to release the lock.
+    #[arg(long = "jpeg-sink-timeout", default_value_t = 1)]
+    pub jpeg_sink_timeout: u64,
+
+    /// Shared-memory object to write raw frames to for kvmd, e.g. `kvmd::ustreamer::raw`.
+    #[arg(long = "raw-sink")]
+    pub raw_sink: Option<String>,
+
+    /// Octal permissions of the raw sink.
+    #[arg(long = "raw-sink-mode", default_value = "660", value_parser = parse_mode)]
+    pub raw_sink_mode: u32,
+
+    /// Remove the raw sink on exit.
+    #[arg(long = "raw-sink-rm")]
+    pub raw_sink_rm: bool,
+
+    /// Seconds a raw sink client counts as connected after its last read.
+    #[arg(long = "raw-sink-client-ttl", default_value_t = 10)]
+    pub raw_sink_client_ttl: u64,
+
+    /// Seconds to wait for a raw sink client to release the lock.
+    #[arg(long = "raw-sink-timeout", default_value_t = 1)]
+    pub raw_sink_timeout: u64,
+
+    /// Shared-memory object to write H.264 frames to for kvmd, e.g. `kvmd::ustreamer::h264`.
+    #[arg(long = "h264-sink")]
+    pub h264_sink: Option<String>,
+
+    /// Octal permissions of the H.264 sink.
+    #[arg(long = "h264-sink-mode", default_value = "660", value_parser = parse_mode)]
+    pub h264_sink_mode: u32,
+
+    /// Remove the H.264 sink on exit.
+    #[arg(long = "h264-sink-rm")]
+    pub h264_sink_rm: bool,
+
+    /// Seconds a H.264 sink client counts as connected after its last read.
+    #[arg(long = "h264-sink-client-ttl", default_value_t = 10)]
+    pub h264_sink_client_ttl: u64,
+
+    /// Seconds to wait for a H.264 sink client to release the lock.
+    #[arg(long = "h264-sink-timeout", default_value_t = 1)]
+    pub h264_sink_timeout: u64,
+}
+
+impl Args {
+    pub fn encoder_options(&self) -> EncoderOptions {
+        EncoderOptions {
+            workers: self.workers,
+            max_latency: self.max_latency.map(Duration::from_millis),
+            strips: self.strips,
+            bitrate: self.bitrate,
+            gop: self.gop,
+        }
+    }
+
+    pub fn change_config(&self) -> Option<ChangeConfig> {
+        self.drop_frames.then_some(ChangeConfig {
+            tolerance: self.same_frame_tolerance.max(0.0),
+            max_unchanged: self.max_unchanged_frames,
+        })
+    }
+
+    pub fn adaptive_config(&self) -> Option<AdaptiveConfig> {
+        let target = match (self.target_rate, self.target_frame_size) {
+            (Some(rate), _) => RateTarget::BytesPerSecond(rate),
+            (None, Some(size)) => RateTarget::FrameSize(size),
+            (None, None) => return None,
+        };
+        Some(AdaptiveConfig {
+            target,
+            min_quality: self.min_quality.min(self.max_quality),
+            max_quality: self.max_quality,
+        })
+    }
+
+    pub fn idle_policy(&self) -> IdlePolicy {
+        match self.idle_fps {
+            _ if self.persistent => IdlePolicy::Persistent,
+            0 => IdlePolicy::StreamOff,
+            fps => IdlePolicy::Slowdown(fps),
+        }
+    }
+
+    pub fn consumer_config(&self) -> ConsumerConfig {
+        ConsumerConfig { queue: self.consumer_queue, policy: self.drop_policy }
+    }
+
+    pub fn image_socket(&self) -> ListenOptions {
+        ListenOptions {
+            path: self.image_socket.clone(),
+            mode: self.image_socket_mode,
+            owner: self.image_socket_owner,
+            group: self.image_socket_group,
+        }
+    }
+
+    /// Loads the key and certificates for `--tcp`, `None` without it.
+    pub fn tcp_options(&self) -> Result<Option<TcpOptions>, String> {
+        let (Some(listen), Some(psk_file)) = (&self.tcp, &self.psk_file) else {
+            return Ok(None);
+        };
+        let psk = Psk::read(psk_file).map_err(|e| format!("Failed to read key {}: {}", psk_file.display(), e))?;
+        let tls = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::Server::from_pem(cert, key).map_err(|e| format!("Failed to load TLS certificate: {}", e))?),
+            _ => None,
+        };
+        Ok(Some(TcpOptions { listen: listen.clone(), psk, tls }))
+    }
+
+    pub fn jpeg_sink(&self) -> Option<SinkOptions> {
+        sink_options(&self.jpeg_sink, self.jpeg_sink_mode, self.jpeg_sink_rm, self.jpeg_sink_client_ttl, self.jpeg_sink_timeout)
+    }
+
+    pub fn raw_sink(&self) -> Option<SinkOptions> {
+        sink_options(&self.raw_sink, self.raw_sink_mode, self.raw_sink_rm, self.raw_sink_client_ttl, self.raw_sink_timeout)
+    }
+
+    pub fn h264_sink(&self) -> Option<SinkOptions> {
+        sink_options(&self.h264_sink, self.h264_sink_mode, self.h264_sink_rm, self.h264_sink_client_ttl, self.h264_sink_timeout)
+    }
+}
+
+fn sink_options(name: &Option<String>, mode: u32, remove: bool, client_ttl: u64, timeout: u64) -> Option<SinkOptions> {
+    name.as_ref().map(|name| SinkOptions {
+        name: name.clone(),
+        mode,
+        remove,
+        client_ttl: Duration::from_secs(client_ttl),
+        timeout: Duration::from_secs(timeout),
+    })
 }
 
 pub struct StreamConfig {
@@ -28,5 +302,19 @@ pub struct StreamConfig {
     pub embedded: bool,
     pub port: u32,
     pub timeout: Duration,
-    pub socket_path: String,
+    pub socket: ListenOptions,
+    /// Serve consumers over TCP too.
+    pub tcp: Option<TcpOptions>,
+    pub quality: Arc<AtomicU8>,
+    pub backlog: Arc<AtomicUsize>,
+    pub keyframe: Arc<AtomicBool>,
+    /// Raw frame handed over by capture when the web server asks for a snapshot.
+    pub retained: Arc<RetainedFrame>,
+    /// Viewers reported by the socket consumers.
+    pub demand: Arc<Demand>,
+    /// Encoded frames per second asked for with `fps=`, 0 for no limit.
+    pub max_fps: Arc<AtomicU32>,
+    pub stats: Arc<Mutex<CaptureStats>>,
+    /// Resolution asked for with `resolution=`, taken by the capture loop.
+    pub resolution: Arc<Mutex<Option<ResolutionChange>>>,
 }
\ No newline at end of file
@@ -0,0 +1,314 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Frames queued for a consumer by default, the old single-consumer ring held as many.
+pub const DEFAULT_QUEUE: usize = 2;
+
+/// What a consumer's queue does with a new frame once it is full.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, clap::ValueEnum)]
+pub enum DropPolicy {
+    /// Drop the oldest queued frame, the consumer always gets the newest picture.
+    #[value(name = "oldest")]
+    Oldest,
+    /// Drop the new frame, the consumer gets every frame up to the point it fell behind, e.g. a recorder.
+    #[value(name = "newest")]
+    Newest,
+    /// Disconnect the consumer, for clients that would rather reconnect than miss a frame.
+    #[value(name = "disconnect")]
+    Disconnect,
+}
+
+impl DropPolicy {
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy.trim() {
+            "oldest" => Some(DropPolicy::Oldest),
+            "newest" => Some(DropPolicy::Newest),
+            "disconnect" => Some(DropPolicy::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+/// Queue length and drop policy a new consumer starts with, it may change both over its control channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsumerConfig {
+    pub queue: usize,
+    pub policy: DropPolicy,
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> Self {
+        ConsumerConfig { queue: DEFAULT_QUEUE, policy: DropPolicy::Oldest }
+    }
+}
+
+struct QueueState<T> {
+    /// Queued items, each flagged whether it must be delivered.
+    items: VecDeque<(T, bool)>,
+    /// Queued stream frames, the items the capacity and drop policy apply to.
+    frames: usize,
+    capacity: usize,
+    policy: DropPolicy,
+    closed: bool,
+    dropped: u64,
+}
+
+impl<T> QueueState<T> {
+    /// Drops the oldest stream frame, leaving items that must be delivered in place.
+    fn evict(&mut self) {
+        if let Some(oldest) = self.items.iter().position(|&(_, keep)| !keep) {
+            self.items.remove(oldest);
+            self.frames -= 1;
+        }
+    }
+}
+
+/// Frames waiting to be written to one consumer. Capture pushes without ever waiting, the
+/// consumer's writer thread pops, so a slow consumer only ever loses its own frames.
+pub struct FrameQueue<T> {
+    state: Mutex<QueueState<T>>,
+    ready: Condvar,
+}
+
+impl<T> FrameQueue<T> {
+    pub fn new(config: ConsumerConfig) -> Self {
+        FrameQueue {
+            state: Mutex::new(QueueState {
+                items: VecDeque::new(),
+                frames: 0,
+                capacity: config.queue.max(1),
+                policy: config.policy,
+                closed: false,
+                dropped: 0,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Queues a stream frame, applying the drop policy when full. Returns false once the queue is closed.
+    pub fn push(&self, item: T) -> bool {
+        let Ok(mut state) = self.state.lock() else {
+            return false;
+        };
+        if state.closed {
+            return false;
+        }
+        if state.frames >= state.capacity {
+            state.dropped += 1;
+            match state.policy {
+                DropPolicy::Oldest => state.evict(),
+                DropPolicy::Newest => return true,
+                DropPolicy::Disconnect => {
+                    state.closed = true;
+                    state.items.clear();
+                    state.frames = 0;
+                    self.ready.notify_all();
+                    return false;
+                }
+            }
+        }
+        state.items.push_back((item, false));
+        state.frames += 1;
+        self.ready.notify_one();
+        true
+    }
+
+    /// Queues a frame that must not be dropped, such as a snapshot, regardless of the capacity.
+    /// It doesn't count against the capacity and the drop policy passes it over.
+    pub fn push_always(&self, item: T) -> bool {
+        let Ok(mut state) = self.state.lock() else {
+            return false;
+        };
+        if state.closed {
+            return false;
+        }
+        state.items.push_back((item, true));
+        self.ready.notify_one();
+        true
+    }
+
+    /// Waits for the next frame, `None` once the queue is closed.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().ok()?;
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some((item, keep)) = state.items.pop_front() {
+                if !keep {
+                    state.frames -= 1;
+                }
+                return Some(item);
+            }
+            state = self.ready.wait(state).ok()?;
+        }
+    }
+
+    /// Stops the queue, e.g. when writing to the consumer failed. Queued frames are discarded.
+    pub fn close(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.closed = true;
+            state.items.clear();
+            state.frames = 0;
+        }
+        self.ready.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().map(|state| state.closed).unwrap_or(true)
+    }
+
+    pub fn set_policy(&self, policy: DropPolicy) {
+        if let Ok(mut state) = self.state.lock() {
+            state.policy = policy;
+        }
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        if let Ok(mut state) = self.state.lock() {
+            state.capacity = capacity.max(1);
+            while state.frames > state.capacity {
+                state.evict();
+                state.dropped += 1;
+            }
+        }
+    }
+
+    /// Frames dropped by the policy so far.
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().map(|state| state.dropped).unwrap_or(0)
+    }
+}
+
+/// Everyone connected to the image server socket: the web server, recorders, debug viewers.
+pub struct Consumers<T> {
+    config: ConsumerConfig,
+    queues: Mutex<Vec<(u32, Arc<FrameQueue<T>>)>>,
+    next_id: AtomicU32,
+}
+
+impl<T: Clone> Consumers<T> {
+    pub fn new(config: ConsumerConfig) -> Self {
+        Consumers { config, queues: Mutex::new(Vec::new()), next_id: AtomicU32::new(1) }
+    }
+
+    /// Adds a consumer with the default queue, returns its id and the queue its writer pops from.
+    pub fn register(&self) -> (u32, Arc<FrameQueue<T>>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let queue = Arc::new(FrameQueue::new(self.config));
+        if let Ok(mut queues) = self.queues.lock() {
+            queues.push((id, queue.clone()));
+        }
+        (id, queue)
+    }
+
+    /// Closes and forgets a consumer whose connection is gone.
+    pub fn remove(&self, id: u32) {
+        if let Ok(mut queues) = self.queues.lock() {
+            queues.retain(|(consumer, queue)| {
+                if *consumer == id {
+                    queue.close();
+                }
+                *consumer != id
+            });
+        }
+    }
+
+    /// Queues a frame for every consumer and forgets those that were closed.
+    /// Each queue gets a clone of `item`, so frames are broadcast behind an `Arc`.
+    pub fn broadcast(&self, item: &T) {
+        if let Ok(mut queues) = self.queues.lock() {
+            queues.retain(|(id, queue)| {
+                let open = queue.push(item.clone());
+                if !open {
+                    println!("Consumer {} disconnected, {} frames dropped", id, queue.dropped());
+                }
+                open
+            });
+        }
+    }
+
+    /// Number of connected consumers.
+    pub fn count(&self) -> usize {
+        self.queues.lock().map(|queues| queues.len()).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(queue: usize, policy: DropPolicy) -> ConsumerConfig {
+        ConsumerConfig { queue, policy }
+    }
+
+    #[test]
+    fn full_queue_follows_policy() {
+        let oldest = FrameQueue::new(config(2, DropPolicy::Oldest));
+        let newest = FrameQueue::new(config(2, DropPolicy::Newest));
+        for frame in 1..=3 {
+            assert!(oldest.push(frame));
+            assert!(newest.push(frame));
+        }
+        assert_eq!((oldest.pop(), oldest.pop()), (Some(2), Some(3)));
+        assert_eq!((newest.pop(), newest.pop()), (Some(1), Some(2)));
+        assert_eq!((oldest.dropped(), newest.dropped()), (1, 1));
+
+        let disconnect = FrameQueue::new(config(1, DropPolicy::Disconnect));
+        assert!(disconnect.push(1));
+        assert!(!disconnect.push(2));
+        assert!(disconnect.is_closed());
+        assert_eq!(disconnect.pop(), None);
+    }
+
+    #[test]
+    fn stills_ignore_capacity() {
+        let queue = FrameQueue::new(config(1, DropPolicy::Newest));
+        queue.push(1);
+        assert!(queue.push_always(2));
+        assert_eq!((queue.pop(), queue.pop()), (Some(1), Some(2)));
+    }
+
+    #[test]
+    fn stills_survive_eviction() {
+        let queue = FrameQueue::new(config(2, DropPolicy::Oldest));
+        queue.push(1);
+        queue.push(2);
+        assert!(queue.push_always(10));
+        // The queue is full, so each new frame drops the oldest frame but never the still.
+        queue.push(3);
+        queue.push(4);
+        assert_eq!(queue.dropped(), 2);
+        queue.push_always(11);
+        queue.set_capacity(1);
+        assert_eq!(queue.dropped(), 3);
+        assert_eq!((queue.pop(), queue.pop(), queue.pop()), (Some(10), Some(4), Some(11)));
+    }
+
+    #[test]
+    fn slow_consumer_does_not_hold_back_others() {
+        let consumers = Consumers::new(config(2, DropPolicy::Oldest));
+        let (_, slow) = consumers.register();
+        let (fast_id, fast) = consumers.register();
+        for frame in 1..=5 {
+            consumers.broadcast(&frame);
+            assert_eq!(fast.pop(), Some(frame));
+        }
+        assert_eq!((slow.pop(), slow.pop()), (Some(4), Some(5)));
+
+        consumers.remove(fast_id);
+        assert!(fast.is_closed());
+        assert_eq!(consumers.count(), 1);
+    }
+
+    #[test]
+    fn closed_consumers_are_forgotten() {
+        let consumers = Consumers::new(config(1, DropPolicy::Disconnect));
+        let (_, queue) = consumers.register();
+        consumers.broadcast(&1);
+        consumers.broadcast(&2);
+        assert!(queue.is_closed());
+        assert_eq!(consumers.count(), 0);
+    }
+}
@@ -0,0 +1,166 @@
+use serde_json::json;
+
+use crate::consumer::DropPolicy;
+use crate::encoder::snapshot::SnapshotRequest;
+
+pub use shared::control::{Reply, split_request};
+
+/// Commands the web server can send back over the image server socket, one per line.
+/// A line ending in ` id=N` is answered with a reply, see `shared::control`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    SetQuality(u8),
+    /// Bytes the web server still has queued for its slowest client.
+    Backlog(usize),
+    /// A client joined mid-stream and needs a keyframe to start decoding.
+    Keyframe,
+    /// Re-encode the retained raw frame and send it alongside the stream.
+    Snapshot(SnapshotRequest),
+    /// What to do with this consumer's frames once its queue is full.
+    DropPolicy(DropPolicy),
+    /// Frames to queue for this consumer.
+    Queue(usize),
+    /// Stream clients of this consumer, capture idles while all consumers report none.
+    Clients(usize),
+    /// Encode at most this many frames per second, 0 for as many as the device captures.
+    Fps(u32),
+    /// Ask the device to capture at another resolution.
+    Resolution(u32, u32),
+    /// Reply with the current `CaptureStats`.
+    Stats,
+    /// Pass this consumer's frames as sealed memfds (`memfd=1`) or inline again (`memfd=0`).
+    Memfd(bool),
+}
+
+impl ControlCommand {
+    pub fn parse(line: &str) -> Option<Self> {
+        match line.trim() {
+            "keyframe" => return Some(ControlCommand::Keyframe),
+            "stats" => return Some(ControlCommand::Stats),
+            _ => {}
+        }
+        let (key, value) = line.trim().split_once('=')?;
+        match key {
+            "quality" => {
+                let quality = value.parse::<u8>().ok()?;
+                if (1..=100).contains(&quality) {
+                    Some(ControlCommand::SetQuality(quality))
+                } else {
+                    None
+                }
+            }
+            "backlog" => value.parse::<usize>().ok().map(ControlCommand::Backlog),
+            "snapshot" => SnapshotRequest::parse(value).map(ControlCommand::Snapshot),
+            "policy" => DropPolicy::parse(value).map(ControlCommand::DropPolicy),
+            "fps" => value.parse::<u32>().ok().map(ControlCommand::Fps),
+            "resolution" => {
+                let (width, height) = value.split_once('x')?;
+                let (width, height) = (width.parse::<u32>().ok()?, height.parse::<u32>().ok()?);
+                (width > 0 && height > 0).then_some(ControlCommand::Resolution(width, height))
+            }
+            "clients" => value.parse::<usize>().ok().map(ControlCommand::Clients),
+            "queue" => value.parse::<usize>().ok().filter(|queue| *queue > 0).map(ControlCommand::Queue),
+            "memfd" => match value.trim() {
+                "1" => Some(ControlCommand::Memfd(true)),
+                "0" => Some(ControlCommand::Memfd(false)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// What the capture loop last reported, sent to web servers that ask with `stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CaptureStats {
+    pub fps: u32,
+    pub total_frames: u32,
+    pub width: usize,
+    pub height: usize,
+    pub format: String,
+    pub encoder: String,
+    pub quality: u8,
+    pub identical: u64,
+    /// Whether the device is streaming, it stops while nobody watches.
+    pub streaming: bool,
+    pub viewers: usize,
+}
+
+impl CaptureStats {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "fps": self.fps,
+            "total_frames": self.total_frames,
+            "width": self.width,
+            "height": self.height,
+            "format": self.format,
+            "encoder": self.encoder,
+            "quality": self.quality,
+            "identical_frames": self.identical,
+            "streaming": self.streaming,
+            "viewers": self.viewers,
+        })
+    }
+}
+
+/// A resolution change for the capture loop, which answers it once the device took it or refused.
+pub struct ResolutionChange {
+    pub width: u32,
+    pub height: u32,
+    pub reply: Box<dyn FnOnce(Reply) + Send>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_quality() {
+        assert_eq!(ControlCommand::parse("quality=95\n"), Some(ControlCommand::SetQuality(95)));
+        assert_eq!(ControlCommand::parse("quality=0"), None);
+        assert_eq!(ControlCommand::parse("quality=101"), None);
+        assert_eq!(ControlCommand::parse("quality"), None);
+        assert_eq!(ControlCommand::parse("brightness=30"), None);
+    }
+
+    #[test]
+    fn parse_backlog() {
+        assert_eq!(ControlCommand::parse("backlog=65536"), Some(ControlCommand::Backlog(65536)));
+        assert_eq!(ControlCommand::parse("backlog=-1"), None);
+    }
+
+    #[test]
+    fn parse_keyframe() {
+        assert_eq!(ControlCommand::parse("keyframe\n"), Some(ControlCommand::Keyframe));
+        assert_eq!(ControlCommand::parse("keyframe=1"), None);
+    }
+
+    #[test]
+    fn parse_snapshot() {
+        let Some(ControlCommand::Snapshot(request)) = ControlCommand::parse("snapshot=jpeg,id=2,quality=95\n") else {
+            panic!("snapshot command not parsed");
+        };
+        assert_eq!((request.id, request.quality), (2, Some(95)));
+        assert_eq!(ControlCommand::parse("snapshot=bmp"), None);
+    }
+
+    #[test]
+    fn parse_consumer_settings() {
+        assert_eq!(ControlCommand::parse("policy=newest\n"), Some(ControlCommand::DropPolicy(DropPolicy::Newest)));
+        assert_eq!(ControlCommand::parse("policy=never"), None);
+        assert_eq!(ControlCommand::parse("queue=8"), Some(ControlCommand::Queue(8)));
+        assert_eq!(ControlCommand::parse("queue=0"), None);
+        assert_eq!(ControlCommand::parse("clients=0\n"), Some(ControlCommand::Clients(0)));
+        assert_eq!(ControlCommand::parse("memfd=1\n"), Some(ControlCommand::Memfd(true)));
+        assert_eq!(ControlCommand::parse("memfd=yes"), None);
+    }
+
+    #[test]
+    fn parse_capture_settings() {
+        assert_eq!(ControlCommand::parse("fps=15"), Some(ControlCommand::Fps(15)));
+        assert_eq!(ControlCommand::parse("resolution=1280x720"), Some(ControlCommand::Resolution(1280, 720)));
+        assert_eq!(ControlCommand::parse("resolution=0x720"), None);
+        assert_eq!(ControlCommand::parse("resolution=1280"), None);
+        assert_eq!(ControlCommand::parse("stats\n"), Some(ControlCommand::Stats));
+    }
+}
@@ -204,14 +204,30 @@ pub fn bgr3_888_to_nv12(buf: &[u8], width: usize, height: usize) -> Vec<u8> {
     out
 }
 
+/// Splits the interleaved UV plane of an NV12 frame into separate U and V planes (I420).
+pub fn nv12_to_i420(buf: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let luma = width * height;
+    let chroma = &buf[luma..luma + luma / 2];
+    let mut out = Vec::with_capacity(luma * 3 / 2);
+    out.extend_from_slice(&buf[..luma]);
+    out.extend(chroma.iter().step_by(2));
+    out.extend(chroma.iter().skip(1).step_by(2));
+    out
+}
+
 #[cfg(test)]
 mod test {
-    use crate::converters::yuyv_to_rgb;
+    use crate::converters::{nv12_to_i420, yuyv_to_rgb};
 
     #[test]
     fn conv_factor() {
         yuyv_to_rgb(0, 0, 0);
         assert!(false); 
     }
-}
 
+    #[test]
+    fn nv12_chroma_is_deinterleaved() {
+        let nv12 = [0, 1, 2, 3, 4, 5, 6, 7, 10, 20, 11, 21];
+        assert_eq!(nv12_to_i420(&nv12, 4, 2), vec![0, 1, 2, 3, 4, 5, 6, 7, 10, 11, 20, 21]);
+    }
+}
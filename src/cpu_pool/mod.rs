@@ -1,129 +1,464 @@
-use crossbeam_channel::{bounded, unbounded, Sender, Receiver};
-use std::collections::BTreeMap;
-use std::sync::{OnceLock, Mutex};
-use std::sync::atomic::{AtomicU32, Ordering};
-use turbojpeg::{Image, Subsamp, compress};
-
-use rayon::ThreadPoolBuilder; 
-
-static CPU_TX: OnceLock<Sender<(u32, Vec<u8>, usize, usize, bool, u8)>> = OnceLock::new();
-static CPU_RX: OnceLock<Receiver<(Vec<u8>, u32)>> = OnceLock::new();
-
-static NEXT: AtomicU32 = AtomicU32::new(0);
-static CURRENT: OnceLock<Mutex<u32>> = OnceLock::new();
-static READY: OnceLock<Mutex<BTreeMap<u32, Vec<u8>>>> = OnceLock::new();
-
-static BUSY: OnceLock<Mutex<usize>> = OnceLock::new();
-static MAX_WORKERS: OnceLock<usize> = OnceLock::new();
-
-pub fn init_pool() {
-    let workers = num_cpus::get();
-    MAX_WORKERS.set(workers).ok();
-    println!("WORKERS {workers}");
-    let pool = ThreadPoolBuilder::new()
-        .num_threads(workers)
-        .build()
-        .unwrap();
-
-    let (tx, rx) = bounded::<(u32, Vec<u8>, usize, usize, bool, u8)>(workers);
-    let (tx_out, rx_out) = unbounded::<(Vec<u8>, u32)>();
-
-    pool.spawn(move || {
-        rayon::scope(|s| {
-            while let Ok((index, data, w, h, format_bgr, quality)) = rx.recv() {
-            
-                let out_tx = tx_out.clone();
-
-                s.spawn(move |_| {
-                    let time = std::time::Instant::now();
-                    
-                    let image = turbojpeg::Image {
-                        pixels: data.as_slice(),
-                        width: w,
-                        height: h,
-                        pitch: w * 3,
-                        format: if format_bgr {
-                            turbojpeg::PixelFormat::BGR
-                        } else {
-                            turbojpeg::PixelFormat::RGB
-                        },
-                    };
-
-                    let jpeg_data = turbojpeg::compress(
-                        image,
-                        quality as i32,
-                        turbojpeg::Subsamp::Sub2x2,
-                    )
-                    .unwrap()
-                    .to_vec();
-                    // println!("TIME ENCODING {}", time.elapsed().as_millis());
-                    let _ = out_tx.send((jpeg_data, index));
-                })
-            }
+use crossbeam_channel::{bounded, select, unbounded, Sender, Receiver};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::encoder::{EncodeStats, EncodedFrame, EncoderConfig, compress_frame};
+
+enum Job {
+    Encode { seq: u32, frame: Vec<u8>, config: EncoderConfig, submitted: Instant },
+    Stop,
+}
+
+struct JobResult {
+    seq: u32,
+    frame: Option<EncodedFrame>,
+    submitted: Instant,
+}
+
+/// How the pool decides which completed frame to hand out next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderingPolicy {
+    /// Every frame in submission order, a slow frame holds back the ones after it.
+    Strict,
+    /// Always the newest completed frame. Older completed frames are superseded, frames still
+    /// being encoded behind it are dropped when they finish, and anything older than the
+    /// deadline is dropped instead of being shown late.
+    LatencyBounded { deadline: Duration },
+}
+
+/// Counters kept by each worker thread.
+#[derive(Debug, Default)]
+pub struct WorkerStats {
+    frames: AtomicU64,
+    failures: AtomicU64,
+    busy_micros: AtomicU64,
+    last_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerStatsSnapshot {
+    pub frames: u64,
+    pub failures: u64,
+    pub busy: Duration,
+    pub last: Duration,
+}
+
+impl WorkerStatsSnapshot {
+    pub fn average(&self) -> Duration {
+        Duration::from_micros((self.busy.as_micros() as u64).checked_div(self.frames).unwrap_or(0))
+    }
+}
+
+impl WorkerStats {
+    fn record(&self, elapsed: Duration, ok: bool) {
+        let micros = elapsed.as_micros() as u64;
+        if ok {
+            self.frames.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.busy_micros.fetch_add(micros, Ordering::Relaxed);
+        self.last_micros.store(micros, Ordering::Relaxed);
+    }
 
-        })
-    });
+    fn snapshot(&self) -> WorkerStatsSnapshot {
+        WorkerStatsSnapshot {
+            frames: self.frames.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            busy: Duration::from_micros(self.busy_micros.load(Ordering::Relaxed)),
+            last: Duration::from_micros(self.last_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
 
-    CPU_TX.set(tx).ok();
-    CPU_RX.set(rx_out).ok();
-    CURRENT.set(Mutex::new(0)).ok();
-    READY.set(Mutex::new(BTreeMap::new())).ok();
-    BUSY.set(Mutex::new(0)).ok();
+struct Worker {
+    handle: JoinHandle<()>,
+    stats: Arc<WorkerStats>,
+    /// Dropping this stops the worker once it finished the frame it is encoding.
+    stop: Sender<()>,
 }
 
-// TODO Fix possible overflow of index
-pub fn encode_jpeg_pool(
-    data: Vec<u8>,
-    width: usize,
-    height: usize,
-    format_bgr: bool,
-    quality: u8,
-) -> Vec<u8> {
+impl Worker {
+    fn join(self, id: usize) {
+        drop(self.stop);
+        self.handle.join().ok();
+        let stats = self.stats.snapshot();
+        println!("Encoder worker {}: {} frames, {} failed, avg {} ms", id, stats.frames, stats.failures, stats.average().as_millis());
+    }
+}
+
+/// A pool of turbojpeg worker threads that returns frames in submission order.
+///
+/// Sequence numbers wrap around, so only distances between them matter. A frame that fails to
+/// encode is skipped rather than holding back everything submitted after it.
+pub struct CpuEncoderPool {
+    jobs: Sender<Job>,
+    job_rx: Receiver<Job>,
+    results: Receiver<JobResult>,
+    result_tx: Sender<JobResult>,
+    workers: Vec<Worker>,
+    next: u32,
+    current: u32,
+    ready: HashMap<u32, (Option<EncodedFrame>, Instant)>,
+    in_flight: usize,
+    ordering: OrderingPolicy,
+    dropped: u64,
+    /// Frames that failed to encode since the last `take_failures`.
+    failed: u64,
+}
+
+impl CpuEncoderPool {
+    /// Starts a pool with `workers` threads, or one per CPU when `workers` is 0.
+    pub fn new(workers: usize) -> Self {
+        let workers = if workers == 0 { num_cpus::get() } else { workers };
+        let (jobs, job_rx) = unbounded::<Job>();
+        let (result_tx, results) = unbounded::<JobResult>();
+        let mut pool = CpuEncoderPool {
+            jobs,
+            job_rx,
+            results,
+            result_tx,
+            workers: Vec::new(),
+            next: 0,
+            current: 0,
+            ready: HashMap::new(),
+            in_flight: 0,
+            ordering: OrderingPolicy::Strict,
+            dropped: 0,
+            failed: 0,
+        };
+        pool.resize(workers);
+        pool
+    }
+
+    pub fn set_ordering(&mut self, ordering: OrderingPolicy) {
+        self.ordering = ordering;
+    }
+
+    /// Frames dropped by the latency-bounded ordering so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Frames that failed to encode since the last call, they are skipped by `poll`.
+    pub fn take_failures(&mut self) -> u64 {
+        std::mem::take(&mut self.failed)
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Whether every worker already has a frame to encode.
+    pub fn is_full(&self) -> bool {
+        self.in_flight >= self.workers.len()
+    }
+
+    /// Grows or shrinks the pool. Frames already submitted are still encoded.
+    pub fn resize(&mut self, workers: usize) {
+        let workers = workers.max(1);
+        while self.workers.len() < workers {
+            let id = self.workers.len();
+            let stats = Arc::new(WorkerStats::default());
+            let worker_stats = stats.clone();
+            let rx = self.job_rx.clone();
+            let tx = self.result_tx.clone();
+            let (stop, stop_rx) = bounded(0);
+            let handle = std::thread::Builder::new()
+                .name(format!("jpeg-worker-{id}"))
+                .spawn(move || worker_loop(rx, stop_rx, tx, worker_stats))
+                .expect("Failed to spawn encoder worker");
+            self.workers.push(Worker { handle, stats, stop });
+        }
+        while self.workers.len() > workers {
+            // Stops exactly this worker and waits for it, a frame it was encoding still comes in
+            // through `results` and is counted like any other. The others pick up the queue.
+            let id = self.workers.len() - 1;
+            if let Some(worker) = self.workers.pop() {
+                worker.join(id);
+            }
+        }
+    }
 
-    if CPU_TX.get().is_none() {
-        init_pool();
+    /// Queues a frame for encoding. Returns false when all workers are busy.
+    pub fn submit(&mut self, frame: Vec<u8>, config: EncoderConfig) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        let seq = self.next;
+        self.next = self.next.wrapping_add(1);
+        if self.jobs.send(Job::Encode { seq, frame, config, submitted: Instant::now() }).is_err() {
+            return false;
+        }
+        self.in_flight += 1;
+        true
     }
 
-    let tx = CPU_TX.get().unwrap();
-    let rx = CPU_RX.get().unwrap();
+    /// Returns the next frame in submission order if it has been encoded.
+    pub fn poll(&mut self) -> Option<EncodedFrame> {
+        while let Ok(result) = self.results.try_recv() {
+            self.complete(result);
+        }
+        self.take_current()
+    }
+
+    /// Blocks until a worker finishes a frame or `timeout` passes, returns whether the pool can take another frame.
+    pub fn wait_ready(&mut self, timeout: Duration) -> bool {
+        if !self.is_full() {
+            return true;
+        }
+        if let Ok(result) = self.results.recv_timeout(timeout) {
+            self.complete(result);
+            while let Ok(result) = self.results.try_recv() {
+                self.complete(result);
+            }
+        }
+        !self.is_full()
+    }
 
-    let mut busy = BUSY.get().unwrap().lock().unwrap();
-    let max_workers = *MAX_WORKERS.get().unwrap();
+    /// Waits for every submitted frame and returns them in order.
+    pub fn drain(&mut self) -> Vec<EncodedFrame> {
+        let mut frames = Vec::new();
+        while self.in_flight > 0 {
+            match self.results.recv() {
+                Ok(result) => self.complete(result),
+                Err(_) => break,
+            }
+        }
+        while self.current != self.next {
+            if let Some(frame) = self.take_current() {
+                frames.push(frame);
+            } else {
+                break;
+            }
+        }
+        frames
+    }
 
-   if !data.is_empty() && *busy < max_workers {
-        let index = NEXT.fetch_add(1, Ordering::Relaxed);
+    pub fn stats(&self) -> Vec<WorkerStatsSnapshot> {
+        self.workers.iter().map(|worker| worker.stats.snapshot()).collect()
+    }
 
-        tx.send((index, data, width, height, format_bgr, quality))
-            .unwrap();
+    /// Stops all workers after they finish the frames already queued.
+    pub fn shutdown(mut self) {
+        self.stop_workers();
+    }
 
-        *busy += 1;
+    fn complete(&mut self, result: JobResult) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        if result.frame.is_none() {
+            self.failed += 1;
+        }
+        // Anything behind `current` was already skipped by the latency-bounded ordering.
+        if result.seq.wrapping_sub(self.current) >= self.next.wrapping_sub(self.current) {
+            if result.frame.is_some() {
+                self.dropped += 1;
+            }
+            return;
+        }
+        self.ready.insert(result.seq, (result.frame, result.submitted));
     }
 
-    while let Ok((jpeg, index)) = rx.try_recv() {
-        *busy -= 1;
+    fn take_current(&mut self) -> Option<EncodedFrame> {
+        match self.ordering {
+            OrderingPolicy::Strict => {
+                while let Some((slot, _)) = self.ready.remove(&self.current) {
+                    self.current = self.current.wrapping_add(1);
+                    if slot.is_some() {
+                        return slot;
+                    }
+                }
+                None
+            }
+            OrderingPolicy::LatencyBounded { deadline } => {
+                while matches!(self.ready.get(&self.current), Some((None, _))) {
+                    self.ready.remove(&self.current);
+                    self.current = self.current.wrapping_add(1);
+                }
+                let current = self.current;
+                let newest = self.ready.iter()
+                    .filter(|(_, (frame, _))| frame.is_some())
+                    .map(|(seq, _)| *seq)
+                    .max_by_key(|seq| seq.wrapping_sub(current))?;
+                let distance = newest.wrapping_sub(current);
+                let (frame, submitted) = self.ready.remove(&newest)?;
 
-        READY
-            .get()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .insert(index, jpeg);
+                let superseded = self.ready.iter()
+                    .filter(|(seq, (frame, _))| frame.is_some() && seq.wrapping_sub(current) < distance)
+                    .count();
+                self.ready.retain(|seq, _| seq.wrapping_sub(current) > distance);
+                self.dropped += superseded as u64;
+                self.current = newest.wrapping_add(1);
+
+                if submitted.elapsed() > deadline {
+                    self.dropped += 1;
+                    return None;
+                }
+                frame
+            }
+        }
     }
 
-    let mut current =  CURRENT.get().unwrap().lock().unwrap();
-    let mut ready = READY.get().unwrap().lock().unwrap();
+    fn stop_workers(&mut self) {
+        for _ in &self.workers {
+            self.jobs.send(Job::Stop).ok();
+        }
+        for (id, worker) in self.workers.drain(..).enumerate() {
+            worker.join(id);
+        }
+    }
+}
 
-    if let Some(jpeg_data) = ready.remove(&current) {
-        *current += 1;
-        jpeg_data
-    } else {
-        Vec::new()
+impl Drop for CpuEncoderPool {
+    fn drop(&mut self) {
+        self.stop_workers();
     }
 }
 
-pub fn workers_full() -> bool {
-    let busy = BUSY.get().unwrap().lock().unwrap();
-    *busy >= *MAX_WORKERS.get().unwrap()
+fn worker_loop(rx: Receiver<Job>, stop: Receiver<()>, tx: Sender<JobResult>, stats: Arc<WorkerStats>) {
+    loop {
+        // Nothing is ever sent on `stop`, it only disconnects. Checked first so a stopped
+        // worker doesn't take another job while the queue is busy.
+        if stop.try_recv() != Err(crossbeam_channel::TryRecvError::Empty) {
+            break;
+        }
+        let job = select! {
+            recv(stop) -> _ => break,
+            recv(rx) -> job => job,
+        };
+        let (seq, frame, config, submitted) = match job {
+            Ok(Job::Encode { seq, frame, config, submitted }) => (seq, frame, config, submitted),
+            Ok(Job::Stop) | Err(_) => break,
+        };
+        let started = Instant::now();
+        let encoded = match compress_frame(&frame, &config) {
+            Ok(data) => {
+                let output_size = data.len();
+                Some(EncodedFrame {
+                    data,
+                    stats: EncodeStats { encode_time: started.elapsed(), input_size: frame.len(), output_size },
+                })
+            }
+            Err(e) => {
+                eprintln!("Failed to encode frame {}: {}", seq, e);
+                None
+            }
+        };
+        stats.record(started.elapsed(), encoded.is_some());
+        if tx.send(JobResult { seq, frame: encoded, submitted }).is_err() {
+            break;
+        }
+    }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{StreamPixelFormat, Subsampling};
+
+    fn config() -> EncoderConfig {
+        EncoderConfig { width: 4, height: 4, format: StreamPixelFormat::MJPG, quality: 80, subsampling: Subsampling::S420 }
+    }
+
+    #[test]
+    fn failed_frame_is_skipped() {
+        let mut pool = CpuEncoderPool::new(2);
+        let bad = EncoderConfig { format: StreamPixelFormat::NV24, subsampling: Subsampling::S444, ..config() };
+        assert!(pool.submit(vec![0u8; 4], bad));
+        assert!(pool.submit(vec![0u8; 4], bad));
+        assert!(!pool.submit(vec![0u8; 4], bad));
+        assert!(pool.drain().is_empty());
+        assert_eq!(pool.in_flight(), 0);
+        let failures: u64 = pool.stats().iter().map(|s| s.failures).sum();
+        assert_eq!(failures, 2);
+        assert_eq!(pool.take_failures(), 2);
+        assert_eq!(pool.take_failures(), 0);
+    }
+
+    #[test]
+    fn sequence_wraps_around() {
+        let mut pool = CpuEncoderPool::new(1);
+        pool.next = u32::MAX;
+        pool.current = u32::MAX;
+        let bad = EncoderConfig { format: StreamPixelFormat::NV24, subsampling: Subsampling::S444, ..config() };
+        for _ in 0..3 {
+            assert!(pool.submit(vec![0u8; 4], bad));
+            pool.drain();
+        }
+        assert_eq!(pool.current, 2);
+        assert_eq!(pool.next, 2);
+    }
 
+    fn encoded(size: usize) -> Option<EncodedFrame> {
+        Some(EncodedFrame { data: vec![0u8; size], stats: EncodeStats::default() })
+    }
+
+    #[test]
+    fn newer_frame_supersedes_slow_one() {
+        let mut pool = CpuEncoderPool::new(1);
+        pool.set_ordering(OrderingPolicy::LatencyBounded { deadline: Duration::from_secs(1) });
+        pool.next = 3;
+        pool.in_flight = 3;
+        pool.complete(JobResult { seq: 1, frame: encoded(1), submitted: Instant::now() });
+        pool.complete(JobResult { seq: 2, frame: encoded(2), submitted: Instant::now() });
+        assert_eq!(pool.take_current().unwrap().data.len(), 2);
+        assert_eq!(pool.current, 3);
+        pool.complete(JobResult { seq: 0, frame: encoded(0), submitted: Instant::now() });
+        assert!(pool.take_current().is_none());
+        assert_eq!(pool.dropped(), 2);
+        assert_eq!(pool.in_flight(), 0);
+    }
+
+    #[test]
+    fn late_frame_is_dropped() {
+        let mut pool = CpuEncoderPool::new(1);
+        pool.set_ordering(OrderingPolicy::LatencyBounded { deadline: Duration::from_millis(10) });
+        pool.next = 1;
+        pool.in_flight = 1;
+        let submitted = Instant::now() - Duration::from_millis(50);
+        pool.complete(JobResult { seq: 0, frame: encoded(1), submitted });
+        assert!(pool.take_current().is_none());
+        assert_eq!(pool.dropped(), 1);
+    }
+
+    #[test]
+    fn wait_ready_blocks_until_completion() {
+        let mut pool = CpuEncoderPool::new(1);
+        let bad = EncoderConfig { format: StreamPixelFormat::NV24, subsampling: Subsampling::S444, ..config() };
+        assert!(pool.submit(vec![0u8; 4], bad));
+        assert!(pool.is_full());
+        assert!(pool.wait_ready(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn resize_changes_capacity() {
+        let mut pool = CpuEncoderPool::new(1);
+        pool.resize(3);
+        assert_eq!(pool.workers(), 3);
+        pool.resize(1);
+        assert_eq!(pool.workers(), 1);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn shrinking_keeps_frames_in_flight() {
+        let mut pool = CpuEncoderPool::new(3);
+        let bad = EncoderConfig { format: StreamPixelFormat::NV24, subsampling: Subsampling::S444, ..config() };
+        for _ in 0..3 {
+            assert!(pool.submit(vec![0u8; 4], bad));
+        }
+        pool.resize(1);
+        assert_eq!(pool.workers(), 1);
+        // Every submitted frame still comes back, whichever worker had it.
+        assert!(pool.drain().is_empty());
+        assert_eq!(pool.in_flight(), 0);
+        assert_eq!(pool.current, pool.next);
+    }
+}
@@ -0,0 +1,200 @@
+/// Number of horizontal bands a frame is split into for change detection.
+const TILES: usize = 64;
+
+/// Frames passed to the encoder unchanged before `--max-unchanged-frames` sends one anyway, by default.
+pub const DEFAULT_MAX_UNCHANGED: u32 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChangeConfig {
+    /// How far the average byte of a tile may drift, in levels, before the tile counts as changed.
+    /// Covers the noise of analog capture. 0 compares a hash of the tile instead, which catches
+    /// any change of a byte's value or position but ignores no noise at all.
+    pub tolerance: f32,
+    /// Unchanged frames dropped in a row before one is sent regardless, 0 to drop them indefinitely.
+    pub max_unchanged: u32,
+}
+
+/// What to do with a captured frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameChange {
+    /// The picture changed, encode and send it.
+    Changed,
+    /// Same picture as the last frame sent, drop it.
+    Unchanged,
+    /// Same picture, but nothing was sent for `max_unchanged` frames, so send it to keep clients alive.
+    Refresh,
+}
+
+/// FNV-1a offset basis and prime, applied to whole 8 byte words.
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+/// Checksums of one part of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Tile {
+    /// Position dependent hash of the bytes, for exact comparisons.
+    hash: u64,
+    /// Sum of all bytes.
+    sum: u64,
+    /// Sum of the running `sum` after each 8 byte word, so content moving within a tile changes it too.
+    weighted: u64,
+    len: usize,
+    /// Sum of `len` after each word, how far `weighted` can move per level of noise.
+    weight: u64,
+}
+
+impl Default for Tile {
+    fn default() -> Self {
+        Tile { hash: FNV_OFFSET, sum: 0, weighted: 0, len: 0, weight: 0 }
+    }
+}
+
+impl Tile {
+    pub(crate) fn new(bytes: &[u8]) -> Self {
+        let mut tile = Tile::default();
+        tile.add(bytes);
+        tile
+    }
+
+    /// Adds more bytes of the same tile, e.g. its next row.
+    pub(crate) fn add(&mut self, bytes: &[u8]) {
+        const LANES: u64 = 0x00FF_00FF_00FF_00FF;
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            let word = u64::from_ne_bytes(word.try_into().unwrap());
+            self.hash = (self.hash ^ word).wrapping_mul(FNV_PRIME);
+            // Adds the 8 bytes of the word in 16 bit lanes, then folds the lanes together.
+            let pairs = (word & LANES) + ((word >> 8) & LANES);
+            self.sum += pairs.wrapping_mul(0x0001_0001_0001_0001) >> 48;
+            self.len += 8;
+            self.weighted += self.sum;
+            self.weight += self.len as u64;
+        }
+        for &byte in words.remainder() {
+            self.hash = (self.hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+            self.sum += byte as u64;
+            self.len += 1;
+            self.weighted += self.sum;
+            self.weight += self.len as u64;
+        }
+    }
+
+    /// Whether every byte moving by at most `tolerance` could turn `self` into `other`.
+    /// Without tolerance the tiles have to hash the same.
+    pub(crate) fn matches(&self, other: &Tile, tolerance: f32) -> bool {
+        if self.len != other.len || self.weight != other.weight {
+            return false;
+        }
+        if tolerance <= 0.0 {
+            return self.hash == other.hash;
+        }
+        let max_sum = tolerance as f64 * self.len as f64;
+        let max_weighted = tolerance as f64 * self.weight as f64;
+        (self.sum.abs_diff(other.sum) as f64) <= max_sum && (self.weighted.abs_diff(other.weighted) as f64) <= max_weighted
+    }
+}
+
+/// Finds captured frames showing the same picture as the last one sent, for `--drop-same-frames`.
+///
+/// Each frame is split into bands whose checksums are compared against those of the last frame
+/// that was sent, instead of keeping and comparing a copy of the whole frame. Small drifts in a
+/// band's checksums are tolerated so capture noise doesn't count as a change.
+pub struct ChangeDetector {
+    config: ChangeConfig,
+    tiles: Vec<Tile>,
+    unchanged: u32,
+    skipped: u64,
+}
+
+impl ChangeDetector {
+    pub fn new(config: ChangeConfig) -> Self {
+        ChangeDetector {
+            config,
+            tiles: Vec::with_capacity(TILES),
+            unchanged: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Total frames dropped as identical.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    pub fn check(&mut self, frame: &[u8]) -> FrameChange {
+        let tile_len = frame.len().div_ceil(TILES).next_multiple_of(8).max(8);
+        let tiles = frame.chunks(tile_len).map(Tile::new);
+        let same = self.tiles.len() == frame.len().div_ceil(tile_len)
+            && self.tiles.iter().zip(tiles.clone()).all(|(last, tile)| last.matches(&tile, self.config.tolerance));
+
+        if !same {
+            self.tiles.clear();
+            self.tiles.extend(tiles);
+            self.unchanged = 0;
+            FrameChange::Changed
+        } else if self.config.max_unchanged != 0 && self.unchanged >= self.config.max_unchanged {
+            self.unchanged = 0;
+            FrameChange::Refresh
+        } else {
+            self.unchanged += 1;
+            self.skipped += 1;
+            FrameChange::Unchanged
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn detector(tolerance: f32, max_unchanged: u32) -> ChangeDetector {
+        ChangeDetector::new(ChangeConfig { tolerance, max_unchanged })
+    }
+
+    #[test]
+    fn repeats_are_dropped_until_refresh() {
+        let mut detector = detector(0.0, 3);
+        let frame = vec![100u8; 4096];
+        assert_eq!(detector.check(&frame), FrameChange::Changed);
+        assert_eq!(detector.check(&frame), FrameChange::Unchanged);
+        assert_eq!(detector.check(&frame), FrameChange::Unchanged);
+        assert_eq!(detector.check(&frame), FrameChange::Unchanged);
+        assert_eq!(detector.check(&frame), FrameChange::Refresh);
+        assert_eq!(detector.check(&frame), FrameChange::Unchanged);
+        assert_eq!(detector.skipped(), 4);
+    }
+
+    #[test]
+    fn small_changes_are_detected() {
+        let mut detector = detector(0.0, 0);
+        let mut frame = vec![100u8; 4096];
+        detector.check(&frame);
+        frame[1000] = 101;
+        assert_eq!(detector.check(&frame), FrameChange::Changed);
+
+        // Same bytes, moved within a tile.
+        frame[1000] = 100;
+        frame[8] = 200;
+        assert_eq!(detector.check(&frame), FrameChange::Changed);
+        frame[8] = 100;
+        frame[24] = 200;
+        assert_eq!(detector.check(&frame), FrameChange::Changed);
+
+        // Two bytes swapped within one 8 byte word.
+        frame[25] = 50;
+        detector.check(&frame);
+        frame.swap(24, 25);
+        assert_eq!(detector.check(&frame), FrameChange::Changed);
+    }
+
+    #[test]
+    fn noise_within_tolerance_is_ignored() {
+        let mut detector = detector(1.0, 0);
+        let frame = vec![100u8; 4096];
+        detector.check(&frame);
+        let noisy: Vec<u8> = frame.iter().enumerate().map(|(i, &b)| if i % 3 == 0 { b + 1 } else { b }).collect();
+        assert_eq!(detector.check(&noisy), FrameChange::Unchanged);
+        let changed: Vec<u8> = frame.iter().enumerate().map(|(i, &b)| if i < 256 { 0 } else { b }).collect();
+        assert_eq!(detector.check(&changed), FrameChange::Changed);
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// What capture does while nobody is watching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdlePolicy {
+    /// Keep capturing and encoding at the full rate.
+    Persistent,
+    /// Keep the device streaming but only encode this many frames per second.
+    Slowdown(u32),
+    /// Stop the device streaming until a viewer connects.
+    StreamOff,
+}
+
+impl IdlePolicy {
+    /// Time between encoded frames while idle, `None` when idle capture encodes nothing or everything.
+    pub fn interval(self) -> Option<Duration> {
+        match self {
+            IdlePolicy::Slowdown(fps) => fps_interval(fps),
+            _ => None,
+        }
+    }
+}
+
+/// Time between frames at `fps`, `None` for 0, which means no limit.
+pub fn fps_interval(fps: u32) -> Option<Duration> {
+    (fps > 0).then(|| Duration::from_secs(1) / fps)
+}
+
+/// Viewers behind each consumer of the image server socket. A web server reports how many
+/// stream clients it has with `clients=N`, a consumer that never does, such as a recorder,
+/// counts as one viewer itself.
+#[derive(Default)]
+pub struct Demand {
+    consumers: Mutex<HashMap<u32, Option<usize>>>,
+    changed: Condvar,
+}
+
+impl Demand {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connect(&self, consumer: u32) {
+        self.update(|consumers| {
+            consumers.insert(consumer, None);
+        });
+    }
+
+    /// Takes over the client count a consumer reported.
+    pub fn report(&self, consumer: u32, clients: usize) {
+        self.update(|consumers| {
+            consumers.insert(consumer, Some(clients));
+        });
+    }
+
+    pub fn disconnect(&self, consumer: u32) {
+        self.update(|consumers| {
+            consumers.remove(&consumer);
+        });
+    }
+
+    fn update(&self, change: impl FnOnce(&mut HashMap<u32, Option<usize>>)) {
+        if let Ok(mut consumers) = self.consumers.lock() {
+            change(&mut consumers);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Viewers over all consumers.
+    pub fn viewers(&self) -> usize {
+        self.consumers.lock().map(|consumers| Self::count(&consumers)).unwrap_or(0)
+    }
+
+    fn count(consumers: &HashMap<u32, Option<usize>>) -> usize {
+        consumers.values().map(|clients| clients.unwrap_or(1)).sum()
+    }
+
+    /// Waits until there is a viewer or the timeout passed, returns whether there is one.
+    /// Wakes up as soon as a consumer connects or reports clients, so capture resumes within a frame.
+    pub fn wait_for_viewers(&self, timeout: Duration) -> bool {
+        let Ok(consumers) = self.consumers.lock() else {
+            return false;
+        };
+        match self.changed.wait_timeout_while(consumers, timeout, |consumers| Self::count(consumers) == 0) {
+            Ok((consumers, _)) => Self::count(&consumers) > 0,
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[test]
+    fn silent_consumers_count_as_a_viewer() {
+        let demand = Demand::new();
+        demand.connect(1);
+        demand.connect(2);
+        assert_eq!(demand.viewers(), 2);
+        demand.report(1, 0);
+        assert_eq!(demand.viewers(), 1);
+        demand.report(1, 3);
+        demand.disconnect(2);
+        assert_eq!(demand.viewers(), 3);
+    }
+
+    #[test]
+    fn waiting_ends_when_a_client_joins() {
+        let demand = Arc::new(Demand::new());
+        demand.connect(1);
+        demand.report(1, 0);
+        assert!(!demand.wait_for_viewers(Duration::from_millis(10)));
+
+        let reporter = demand.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            reporter.report(1, 1);
+        });
+        let started = Instant::now();
+        assert!(demand.wait_for_viewers(Duration::from_secs(5)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn idle_interval() {
+        assert_eq!(IdlePolicy::Slowdown(4).interval(), Some(Duration::from_millis(250)));
+        assert_eq!(IdlePolicy::StreamOff.interval(), None);
+        assert_eq!(IdlePolicy::Slowdown(0).interval(), None);
+    }
+}
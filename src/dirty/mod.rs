@@ -0,0 +1,204 @@
+use crate::StreamPixelFormat;
+use crate::dedup::Tile;
+use crate::encoder::EncoderConfig;
+
+/// Smallest tile edge in pixels.
+const MIN_TILE_SIZE: usize = 64;
+
+/// Tiles are made larger until a frame has at most this many, so the map fits the socket metadata block.
+const MAX_TILES: usize = 2048;
+
+/// Which tiles of a frame changed since the previous one.
+///
+/// Shown as `<tile size>:<columns>x<rows>:<bits>`, the bits in hex row by row starting at the top
+/// left tile, most significant bit first, e.g. `64:30x17:0000c000...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirtyMap {
+    pub tile_size: usize,
+    pub columns: usize,
+    pub rows: usize,
+    tiles: Vec<bool>,
+}
+
+impl DirtyMap {
+    /// A map with every tile of a `width`x`height` frame marked as changed.
+    pub fn all(width: usize, height: usize) -> Self {
+        let tile_size = tile_size(width, height);
+        let (columns, rows) = (width.div_ceil(tile_size), height.div_ceil(tile_size));
+        DirtyMap { tile_size, columns, rows, tiles: vec![true; columns * rows] }
+    }
+
+    pub fn is_dirty(&self, column: usize, row: usize) -> bool {
+        self.tiles.get(row * self.columns + column).copied().unwrap_or(true)
+    }
+
+    pub fn is_clean(&self) -> bool {
+        !self.tiles.contains(&true)
+    }
+
+    pub fn dirty_tiles(&self) -> usize {
+        self.tiles.iter().filter(|&&dirty| dirty).count()
+    }
+
+    /// Whether anything changed in pixel rows `y..y + height`.
+    pub fn rows_dirty(&self, y: usize, height: usize) -> bool {
+        let first = y / self.tile_size;
+        let last = (y + height.max(1) - 1) / self.tile_size;
+        (first..=last.min(self.rows.saturating_sub(1))).any(|row| {
+            self.tiles[row * self.columns..(row + 1) * self.columns].contains(&true)
+        })
+    }
+}
+
+impl std::fmt::Display for DirtyMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}x{}:", self.tile_size, self.columns, self.rows)?;
+        for nibble in self.tiles.chunks(4) {
+            let bits = nibble.iter().enumerate().fold(0u8, |bits, (i, &dirty)| bits | ((dirty as u8) << (3 - i)));
+            write!(f, "{:x}", bits)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tile edge for a frame, doubled from `MIN_TILE_SIZE` until the frame has at most `MAX_TILES`.
+fn tile_size(width: usize, height: usize) -> usize {
+    let mut size = MIN_TILE_SIZE;
+    while width.div_ceil(size) * height.div_ceil(size) > MAX_TILES {
+        size *= 2;
+    }
+    size
+}
+
+/// One plane of a raw frame: where it starts, its bytes per pixel along a row, and how many frame
+/// rows share one of its rows.
+struct Plane {
+    offset: usize,
+    bytes_per_pixel: usize,
+    row_step: usize,
+}
+
+fn planes(config: &EncoderConfig) -> Option<Vec<Plane>> {
+    let luma = config.width * config.height;
+    let planes = match config.format {
+        StreamPixelFormat::NV12 => vec![
+            Plane { offset: 0, bytes_per_pixel: 1, row_step: 1 },
+            Plane { offset: luma, bytes_per_pixel: 1, row_step: 2 },
+        ],
+        StreamPixelFormat::NV24 => vec![
+            Plane { offset: 0, bytes_per_pixel: 1, row_step: 1 },
+            Plane { offset: luma, bytes_per_pixel: 2, row_step: 1 },
+        ],
+        StreamPixelFormat::YUYV => vec![Plane { offset: 0, bytes_per_pixel: 2, row_step: 1 }],
+        StreamPixelFormat::BGR3 => vec![Plane { offset: 0, bytes_per_pixel: 3, row_step: 1 }],
+        StreamPixelFormat::MJPG => return None,
+    };
+    Some(planes)
+}
+
+/// Compares each raw frame with the previous one tile by tile, for `--dirty-tiles`.
+///
+/// Only tile checksums of the previous frame are kept. MJPG frames and the first frame after a
+/// format change come out as entirely dirty.
+#[derive(Default)]
+pub struct DirtyTracker {
+    config: Option<EncoderConfig>,
+    tiles: Vec<Tile>,
+}
+
+impl DirtyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, frame: &[u8], config: &EncoderConfig) -> DirtyMap {
+        let mut map = DirtyMap::all(config.width, config.height);
+        let tiles = match planes(config) {
+            Some(planes) => checksums(frame, config, &planes, &map),
+            None => None,
+        };
+        let Some(tiles) = tiles else {
+            self.tiles.clear();
+            return map;
+        };
+
+        let same_geometry = self.config.is_some_and(|last| {
+            (last.width, last.height, last.format) == (config.width, config.height, config.format)
+        });
+        if same_geometry && self.tiles.len() == tiles.len() {
+            for ((dirty, last), tile) in map.tiles.iter_mut().zip(&self.tiles).zip(&tiles) {
+                *dirty = !last.matches(tile, 0.0);
+            }
+        }
+        self.config = Some(*config);
+        self.tiles = tiles;
+        map
+    }
+}
+
+/// Checksums of every tile in `map`'s grid, or `None` if the frame is too short.
+fn checksums(frame: &[u8], config: &EncoderConfig, planes: &[Plane], map: &DirtyMap) -> Option<Vec<Tile>> {
+    let mut tiles = vec![Tile::default(); map.columns * map.rows];
+    for plane in planes {
+        let stride = config.width * plane.bytes_per_pixel;
+        let plane_rows = config.height.div_ceil(plane.row_step);
+        let data = frame.get(plane.offset..plane.offset + stride * plane_rows)?;
+        let tile_bytes = map.tile_size * plane.bytes_per_pixel;
+        for (plane_row, row) in data.chunks_exact(stride).enumerate() {
+            let tile_row = plane_row * plane.row_step / map.tile_size;
+            let tiles = &mut tiles[tile_row * map.columns..(tile_row + 1) * map.columns];
+            for (tile, bytes) in tiles.iter_mut().zip(row.chunks(tile_bytes)) {
+                tile.add(bytes);
+            }
+        }
+    }
+    Some(tiles)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Subsampling;
+
+    fn config(width: usize, height: usize, format: StreamPixelFormat) -> EncoderConfig {
+        EncoderConfig { width, height, format, quality: 80, subsampling: Subsampling::S420 }
+    }
+
+    #[test]
+    fn changed_pixels_mark_their_tile() {
+        let config = config(256, 128, StreamPixelFormat::BGR3);
+        let mut tracker = DirtyTracker::new();
+        let mut frame = vec![0u8; 256 * 128 * 3];
+        assert_eq!(tracker.update(&frame, &config).dirty_tiles(), 8);
+        assert!(tracker.update(&frame, &config).is_clean());
+
+        // Pixel (130, 70) is in column 2, row 1.
+        frame[(70 * 256 + 130) * 3] = 255;
+        let map = tracker.update(&frame, &config);
+        assert_eq!(map.dirty_tiles(), 1);
+        assert!(map.is_dirty(2, 1));
+        assert!(map.rows_dirty(64, 16) && !map.rows_dirty(0, 64));
+        assert_eq!(map.to_string(), "64:4x2:02");
+    }
+
+    #[test]
+    fn nv12_chroma_changes_are_seen() {
+        let config = config(128, 128, StreamPixelFormat::NV12);
+        let mut tracker = DirtyTracker::new();
+        let mut frame = vec![128u8; 128 * 128 * 3 / 2];
+        tracker.update(&frame, &config);
+        // Chroma row 40 covers frame rows 80 and 81, the second tile row.
+        frame[128 * 128 + 40 * 128 + 10] = 0;
+        let map = tracker.update(&frame, &config);
+        assert_eq!(map.dirty_tiles(), 1);
+        assert!(map.is_dirty(0, 1));
+    }
+
+    #[test]
+    fn large_frames_use_larger_tiles() {
+        assert_eq!(DirtyMap::all(1920, 1080).tile_size, 64);
+        let map = DirtyMap::all(7680, 4320);
+        assert_eq!(map.tile_size, 128);
+        assert!(map.to_string().len() < 1024);
+    }
+}
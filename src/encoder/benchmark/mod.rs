@@ -0,0 +1,143 @@
+use std::time::{Duration, Instant};
+
+use crate::EncoderType;
+use super::{Encoder, EncoderConfig, EncoderError, EncoderOptions, fallback, raw_frame_size, try_create_encoder};
+
+/// Synthetic frames encoded by each backend.
+pub const BENCHMARK_FRAMES: usize = 10;
+
+/// Frame rates above this don't make an encoder more useful, only its latency matters then.
+pub const TARGET_FPS: f64 = 60.0;
+
+/// Backends `auto` chooses from, in the order used to break ties.
+pub const CANDIDATES: [EncoderType; 3] = [EncoderType::RockchipMpp, EncoderType::CpuPool, EncoderType::Cpu];
+
+#[derive(Debug, Clone)]
+pub struct BenchmarkResult {
+    pub kind: EncoderType,
+    pub fps: f64,
+    pub latency: Duration,
+    pub error: Option<String>,
+}
+
+impl std::fmt::Display for BenchmarkResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            Some(e) => write!(f, "{}: unavailable ({})", self.kind, e),
+            None => write!(f, "{}: {:.1} fps, {} ms per frame", self.kind, self.fps, self.latency.as_millis()),
+        }
+    }
+}
+
+/// Fills a raw frame with a gradient so the encoders see something less trivial than a flat colour.
+fn test_pattern(config: &EncoderConfig) -> Vec<u8> {
+    let size = raw_frame_size(config.format, config.width, config.height);
+    let width = config.width.max(1);
+    (0..size).map(|i| ((i % width) + (i / width) * 3) as u8).collect()
+}
+
+fn run(encoder: &mut dyn Encoder, config: &EncoderConfig, frames: usize) -> Result<(f64, Duration), EncoderError> {
+    encoder.configure(*config)?;
+    fallback::probe(encoder, config)?;
+
+    let frame = test_pattern(config);
+    let mut encoded = Vec::new();
+    let started = Instant::now();
+    for _ in 0..frames {
+        encoder.wait_ready(Duration::from_secs(1));
+        if let Some(output) = encoder.encode(&frame)? {
+            encoded.push(output);
+        }
+    }
+    encoded.extend(encoder.flush());
+    let elapsed = started.elapsed();
+
+    if encoded.is_empty() {
+        return Err(EncoderError::Compression("no frames came out".to_string()));
+    }
+    let latency = encoded.iter().map(|frame| frame.stats.encode_time).sum::<Duration>() / encoded.len() as u32;
+    Ok((encoded.len() as f64 / elapsed.as_secs_f64(), latency))
+}
+
+/// Encodes a few synthetic frames with every candidate backend.
+pub fn benchmark(candidates: &[EncoderType], config: &EncoderConfig, options: &EncoderOptions, frames: usize) -> Vec<BenchmarkResult> {
+    candidates.iter().map(|&kind| {
+        let measured = try_create_encoder(kind, options).and_then(|mut encoder| run(encoder.as_mut(), config, frames));
+        match measured {
+            Ok((fps, latency)) => BenchmarkResult { kind, fps, latency, error: None },
+            Err(e) => BenchmarkResult { kind, fps: 0.0, latency: Duration::ZERO, error: Some(e.to_string()) },
+        }
+    }).collect()
+}
+
+/// Orders working backends best first: highest frame rate up to `TARGET_FPS`, then lowest latency.
+pub fn rank(results: &[BenchmarkResult]) -> Vec<EncoderType> {
+    let mut working: Vec<&BenchmarkResult> = results.iter().filter(|result| result.error.is_none()).collect();
+    working.sort_by(|a, b| {
+        b.fps.min(TARGET_FPS).total_cmp(&a.fps.min(TARGET_FPS))
+            .then(a.latency.cmp(&b.latency))
+    });
+    working.iter().map(|result| result.kind).collect()
+}
+
+/// Replaces `auto` in an encoder chain with the benchmarked backends, best first.
+pub fn resolve_chain(chain: &[EncoderType], config: &EncoderConfig, options: &EncoderOptions) -> Vec<EncoderType> {
+    if !chain.contains(&EncoderType::Auto) {
+        return chain.to_vec();
+    }
+
+    println!("Benchmarking encoders at {}x{} {:?}", config.width, config.height, config.format);
+    let results = benchmark(&CANDIDATES, config, options, BENCHMARK_FRAMES);
+    for result in &results {
+        println!("  {}", result);
+    }
+    let ranked = rank(&results);
+    if let Some(best) = ranked.first() {
+        println!("Auto selected {} encoder", best);
+    }
+
+    let mut resolved = Vec::new();
+    for kind in chain {
+        let kinds = if *kind == EncoderType::Auto { ranked.clone() } else { vec![*kind] };
+        for kind in kinds {
+            if !resolved.contains(&kind) {
+                resolved.push(kind);
+            }
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn result(kind: EncoderType, fps: f64, latency_ms: u64) -> BenchmarkResult {
+        BenchmarkResult { kind, fps, latency: Duration::from_millis(latency_ms), error: None }
+    }
+
+    #[test]
+    fn rank_prefers_latency_once_fast_enough() {
+        let results = vec![
+            result(EncoderType::CpuPool, 120.0, 40),
+            result(EncoderType::RockchipMpp, 90.0, 8),
+            result(EncoderType::Cpu, 25.0, 30),
+        ];
+        assert_eq!(rank(&results), vec![EncoderType::RockchipMpp, EncoderType::CpuPool, EncoderType::Cpu]);
+    }
+
+    #[test]
+    fn rank_skips_failed_backends() {
+        let mut failed = result(EncoderType::RockchipMpp, 0.0, 0);
+        failed.error = Some("not compiled in".to_string());
+        let results = vec![failed, result(EncoderType::Cpu, 20.0, 50), result(EncoderType::CpuPool, 45.0, 90)];
+        assert_eq!(rank(&results), vec![EncoderType::CpuPool, EncoderType::Cpu]);
+    }
+
+    #[test]
+    fn chain_without_auto_is_unchanged() {
+        let config = EncoderConfig { width: 4, height: 4, format: crate::StreamPixelFormat::NV12, quality: 80, subsampling: crate::Subsampling::S420 };
+        let chain = vec![EncoderType::Cpu, EncoderType::CpuPool];
+        assert_eq!(resolve_chain(&chain, &config, &EncoderOptions::default()), chain);
+    }
+}
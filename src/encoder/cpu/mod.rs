@@ -0,0 +1,122 @@
+use std::time::Instant;
+
+use rayon::prelude::*;
+
+use crate::{EncoderType, StreamPixelFormat};
+use crate::dirty::DirtyMap;
+use super::{EncodedFrame, Encoder, EncoderConfig, EncoderError, compress_frame, strips};
+
+/// Output of the last frame from `encode_dirty`, so the next one only re-encodes strips that changed.
+struct StripCache {
+    parts: Vec<Vec<u8>>,
+    jpeg: Vec<u8>,
+}
+
+pub struct CpuEncoder {
+    config: Option<EncoderConfig>,
+    strips: usize,
+    cache: Option<StripCache>,
+}
+
+impl CpuEncoder {
+    pub fn new() -> Self {
+        Self::with_strips(0)
+    }
+
+    /// `strips` is 0 to split only frames of 4K and above, 1 to always encode whole frames.
+    pub fn with_strips(strips: usize) -> Self {
+        CpuEncoder { config: None, strips, cache: None }
+    }
+}
+
+impl Default for CpuEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder for CpuEncoder {
+    fn configure(&mut self, config: EncoderConfig) -> Result<(), EncoderError> {
+        if self.config != Some(config) {
+            self.cache = None;
+        }
+        self.config = Some(config);
+        Ok(())
+    }
+
+    fn config(&self) -> Option<EncoderConfig> {
+        self.config
+    }
+
+    fn encode(&mut self, frame: &[u8]) -> Result<Option<EncodedFrame>, EncoderError> {
+        let config = self.config.ok_or(EncoderError::NotConfigured)?;
+        let started = Instant::now();
+        self.cache = None;
+        if config.format == StreamPixelFormat::MJPG {
+            return Ok(Some(EncodedFrame::new(frame.to_vec(), frame.len(), started)));
+        }
+
+        let strips = strips::strip_count(self.strips, &config);
+        let jpeg_data = if strips > 1 {
+            strips::encode_strips(frame, &config, strips)?
+        } else {
+            compress_frame(frame, &config)?
+        };
+        Ok(Some(EncodedFrame::new(jpeg_data, frame.len(), started)))
+    }
+
+    /// Re-encodes only the strips with dirty tiles and stitches them with the cached rest.
+    /// Frames without strips are reused as a whole when nothing changed.
+    fn encode_dirty(&mut self, frame: &[u8], dirty: &DirtyMap) -> Result<Option<EncodedFrame>, EncoderError> {
+        let config = self.config.ok_or(EncoderError::NotConfigured)?;
+        if config.format == StreamPixelFormat::MJPG {
+            return self.encode(frame);
+        }
+        let started = Instant::now();
+        let layout = strips::strip_layout(&config, strips::strip_count(self.strips, &config));
+        let bands = layout.as_ref().map_or_else(|| vec![(0, config.height)], |layout| layout.bands.clone());
+
+        let mut cache = self.cache.take().filter(|cache| cache.parts.len() == bands.len());
+        if dirty.is_clean()
+            && let Some(cache) = cache.take()
+        {
+            let jpeg = cache.jpeg.clone();
+            self.cache = Some(cache);
+            return Ok(Some(EncodedFrame::new(jpeg, frame.len(), started)));
+        }
+
+        let mut previous: Vec<Option<Vec<u8>>> = match cache {
+            Some(cache) => cache.parts.into_iter().map(Some).collect(),
+            None => vec![None; bands.len()],
+        };
+        for (part, &(y, rows)) in previous.iter_mut().zip(&bands) {
+            if dirty.rows_dirty(y, rows) {
+                *part = None;
+            }
+        }
+        let parts = bands
+            .par_iter()
+            .zip(previous)
+            .map(|(&(y, rows), part)| match part {
+                Some(part) => Ok(part),
+                None if layout.is_none() => compress_frame(frame, &config),
+                None => strips::encode_strip(frame, &config, y, rows),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let jpeg = match &layout {
+            Some(layout) => strips::stitch(&parts, config.height as u16, layout.restart_interval)?,
+            None => parts[0].clone(),
+        };
+        self.cache = Some(StripCache { parts, jpeg: jpeg.clone() });
+        Ok(Some(EncodedFrame::new(jpeg, frame.len(), started)))
+    }
+
+    fn flush(&mut self) -> Vec<EncodedFrame> {
+        Vec::new()
+    }
+
+    fn kind(&self) -> EncoderType {
+        EncoderType::Cpu
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+use crate::{EncoderType, StreamPixelFormat};
+use crate::cpu_pool::{CpuEncoderPool, OrderingPolicy};
+use super::{EncodedFrame, Encoder, EncoderConfig, EncoderError, EncoderOptions};
+
+pub struct CpuPoolEncoder {
+    config: Option<EncoderConfig>,
+    pool: CpuEncoderPool,
+}
+
+impl CpuPoolEncoder {
+    pub fn new(options: &EncoderOptions) -> Self {
+        let mut pool = CpuEncoderPool::new(options.workers);
+        if let Some(deadline) = options.max_latency {
+            pool.set_ordering(OrderingPolicy::LatencyBounded { deadline });
+        }
+        println!("WORKERS {}", pool.workers());
+        CpuPoolEncoder { config: None, pool }
+    }
+
+    pub fn pool(&self) -> &CpuEncoderPool {
+        &self.pool
+    }
+}
+
+impl Default for CpuPoolEncoder {
+    fn default() -> Self {
+        Self::new(&EncoderOptions::default())
+    }
+}
+
+impl Encoder for CpuPoolEncoder {
+    fn configure(&mut self, config: EncoderConfig) -> Result<(), EncoderError> {
+        self.config = Some(config);
+        Ok(())
+    }
+
+    fn config(&self) -> Option<EncoderConfig> {
+        self.config
+    }
+
+    fn encode(&mut self, frame: &[u8]) -> Result<Option<EncodedFrame>, EncoderError> {
+        let config = self.config.ok_or(EncoderError::NotConfigured)?;
+        let started = Instant::now();
+        if config.format == StreamPixelFormat::MJPG {
+            return Ok(Some(EncodedFrame::new(frame.to_vec(), frame.len(), started)));
+        }
+
+        if !self.pool.submit(frame.to_vec(), config) {
+            if !self.pool.is_full() {
+                return Err(EncoderError::Compression("encoder workers are gone".to_string()));
+            }
+            // The caller didn't wait for `ready`, the frame is dropped instead of piling up behind busy workers.
+            eprintln!("All encoder workers busy, frame dropped");
+        }
+        let encoded = self.pool.poll();
+        // Failed frames are skipped by the pool, they still have to count against it so the
+        // fallback chain notices a pool that keeps failing.
+        let failed = self.pool.take_failures();
+        if encoded.is_none() && failed > 0 {
+            return Err(EncoderError::Compression(format!("{} frames failed to encode", failed)));
+        }
+        Ok(encoded)
+    }
+
+    fn flush(&mut self) -> Vec<EncodedFrame> {
+        self.pool.drain()
+    }
+
+    fn kind(&self) -> EncoderType {
+        EncoderType::CpuPool
+    }
+
+    fn ready(&self) -> bool {
+        !self.pool.is_full()
+    }
+
+    fn wait_ready(&mut self, timeout: Duration) -> bool {
+        self.pool.wait_ready(timeout)
+    }
+}
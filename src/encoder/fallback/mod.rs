@@ -0,0 +1,285 @@
+use std::time::Duration;
+
+use crate::{EncoderType, StreamPixelFormat};
+use crate::dirty::DirtyMap;
+use super::{EncodedFrame, Encoder, EncoderConfig, EncoderError, EncoderOptions, raw_frame_size, try_create_encoder};
+
+/// Consecutive encode errors after which the next encoder in the chain takes over.
+pub const MAX_FAILURES: u32 = 5;
+
+/// Runs the first working encoder of an ordered chain, e.g. `mpp,cpu-pool,cpu`.
+///
+/// Each encoder is probed with a blank frame whenever it is configured, and replaced by the
+/// next one in the chain when the probe fails or encoding keeps failing at runtime. The capture
+/// loop and the socket to the web server are untouched, so clients only miss a few frames.
+pub struct FallbackEncoder {
+    chain: Vec<EncoderType>,
+    options: EncoderOptions,
+    index: usize,
+    active: Box<dyn Encoder>,
+    failures: u32,
+    reason: Option<String>,
+}
+
+impl FallbackEncoder {
+    pub fn new(chain: Vec<EncoderType>, options: EncoderOptions) -> Result<Self, EncoderError> {
+        let mut reasons = Vec::new();
+        for (index, kind) in chain.iter().enumerate() {
+            match try_create_encoder(*kind, &options) {
+                Ok(active) => {
+                    let reason = if reasons.is_empty() { None } else { Some(reasons.join(", ")) };
+                    return Ok(FallbackEncoder { chain, options, index, active, failures: 0, reason });
+                }
+                Err(e) => {
+                    eprintln!("Cannot use {} encoder: {}", kind, e);
+                    reasons.push(format!("{}: {}", kind, e));
+                }
+            }
+        }
+        Err(EncoderError::Hardware(format!("no usable encoder in chain ({})", reasons.join(", "))))
+    }
+
+    pub fn chain(&self) -> &[EncoderType] {
+        &self.chain
+    }
+
+    /// Switches to the next encoder in the chain that can be created and passes the probe with `config`.
+    fn fall_back(&mut self, reason: String, config: Option<EncoderConfig>) -> bool {
+        let failed = self.active.name();
+        while self.index + 1 < self.chain.len() {
+            self.index += 1;
+            let kind = self.chain[self.index];
+            let mut next = match try_create_encoder(kind, &self.options) {
+                Ok(next) => next,
+                Err(e) => {
+                    eprintln!("Cannot use {} encoder: {}", kind, e);
+                    continue;
+                }
+            };
+            if let Some(config) = config
+                && let Err(e) = next.configure(config).and_then(|_| probe(next.as_mut(), &config))
+            {
+                eprintln!("{} encoder failed probe: {}", kind, e);
+                continue;
+            }
+            eprintln!("{} encoder failed ({}), falling back to {}", failed, reason, next.name());
+            self.reason = Some(format!("{}: {}", failed, reason));
+            self.active = next;
+            self.failures = 0;
+            return true;
+        }
+        eprintln!("{} encoder failed ({}) and no fallback is left", failed, reason);
+        false
+    }
+
+    /// Counts consecutive encode errors and falls back once there are too many.
+    fn track(&mut self, result: Result<Option<EncodedFrame>, EncoderError>) -> Result<Option<EncodedFrame>, EncoderError> {
+        match result {
+            Ok(encoded) => {
+                self.failures = 0;
+                Ok(encoded)
+            }
+            Err(e) => {
+                self.failures += 1;
+                if self.failures >= MAX_FAILURES {
+                    let config = self.active.config();
+                    self.fall_back(e.to_string(), config);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Encodes a blank frame to check the encoder actually works for this configuration.
+///
+/// The probe frame is thrown away, so a video encoder is asked for another keyframe: the one it
+/// owed after configuring, carrying the new SPS/PPS, went into the probe.
+pub fn probe(encoder: &mut dyn Encoder, config: &EncoderConfig) -> Result<(), EncoderError> {
+    if config.format == StreamPixelFormat::MJPG {
+        return Ok(());
+    }
+    let frame = vec![0u8; raw_frame_size(config.format, config.width, config.height)];
+    encoder.encode(&frame)?;
+    encoder.flush();
+    encoder.request_keyframe();
+    Ok(())
+}
+
+impl Encoder for FallbackEncoder {
+    fn configure(&mut self, config: EncoderConfig) -> Result<(), EncoderError> {
+        match self.active.configure(config).and_then(|_| probe(self.active.as_mut(), &config)) {
+            Ok(()) => Ok(()),
+            // fall_back configures and probes the replacement itself
+            Err(e) if self.fall_back(e.to_string(), Some(config)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn config(&self) -> Option<EncoderConfig> {
+        self.active.config()
+    }
+
+    fn encode(&mut self, frame: &[u8]) -> Result<Option<EncodedFrame>, EncoderError> {
+        let result = self.active.encode(frame);
+        self.track(result)
+    }
+
+    fn encode_dirty(&mut self, frame: &[u8], dirty: &DirtyMap) -> Result<Option<EncodedFrame>, EncoderError> {
+        let result = self.active.encode_dirty(frame, dirty);
+        self.track(result)
+    }
+
+    fn flush(&mut self) -> Vec<EncodedFrame> {
+        self.active.flush()
+    }
+
+    fn kind(&self) -> EncoderType {
+        self.active.kind()
+    }
+
+    fn name(&self) -> String {
+        self.active.name()
+    }
+
+    fn ready(&self) -> bool {
+        self.active.ready()
+    }
+
+    fn wait_ready(&mut self, timeout: Duration) -> bool {
+        self.active.wait_ready(timeout)
+    }
+
+    fn set_quality(&mut self, quality: u8) -> Result<(), EncoderError> {
+        self.active.set_quality(quality)
+    }
+
+    fn request_keyframe(&mut self) {
+        self.active.request_keyframe()
+    }
+
+    fn fallback_reason(&self) -> Option<String> {
+        self.reason.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Subsampling;
+
+    struct BrokenEncoder {
+        config: Option<EncoderConfig>,
+    }
+
+    impl Encoder for BrokenEncoder {
+        fn configure(&mut self, config: EncoderConfig) -> Result<(), EncoderError> {
+            self.config = Some(config);
+            Ok(())
+        }
+
+        fn config(&self) -> Option<EncoderConfig> {
+            self.config
+        }
+
+        fn encode(&mut self, _frame: &[u8]) -> Result<Option<EncodedFrame>, EncoderError> {
+            Err(EncoderError::Hardware("broken".to_string()))
+        }
+
+        fn flush(&mut self) -> Vec<EncodedFrame> {
+            Vec::new()
+        }
+
+        fn kind(&self) -> EncoderType {
+            EncoderType::RockchipMpp
+        }
+    }
+
+    #[test]
+    fn falls_back_after_repeated_failures() {
+        let config = EncoderConfig { width: 4, height: 4, format: StreamPixelFormat::MJPG, quality: 80, subsampling: Subsampling::S420 };
+        let mut encoder = FallbackEncoder {
+            chain: vec![EncoderType::RockchipMpp, EncoderType::Cpu],
+            options: EncoderOptions::default(),
+            index: 0,
+            active: Box::new(BrokenEncoder { config: Some(config) }),
+            failures: 0,
+            reason: None,
+        };
+        for _ in 0..MAX_FAILURES {
+            assert!(encoder.encode(&[0xFF, 0xD8]).is_err());
+        }
+        assert_eq!(encoder.kind(), EncoderType::Cpu);
+        assert_eq!(encoder.config(), Some(config));
+        assert_eq!(encoder.fallback_reason().unwrap(), "rockchip mpp: hardware encoder failed: broken");
+        assert!(encoder.encode(&[0xFF, 0xD8]).unwrap().is_some());
+    }
+
+    #[test]
+    fn failing_pool_falls_back() {
+        let config = EncoderConfig { width: 4, height: 4, format: StreamPixelFormat::NV24, quality: 80, subsampling: Subsampling::S444 };
+        let options = EncoderOptions { workers: 1, ..EncoderOptions::default() };
+        let mut encoder = FallbackEncoder::new(vec![EncoderType::CpuPool, EncoderType::Cpu], options).unwrap();
+        encoder.configure(config).unwrap();
+        // Frames too short for the configuration fail in the worker, each failure shows up on the next call.
+        for _ in 0..=MAX_FAILURES {
+            encoder.wait_ready(Duration::from_secs(5));
+            encoder.encode(&[0; 4]).ok();
+        }
+        assert_eq!(encoder.kind(), EncoderType::Cpu);
+    }
+
+    /// Stands in for a video encoder, every frame after a keyframe request is a keyframe.
+    #[derive(Default)]
+    struct KeyframeEncoder {
+        config: Option<EncoderConfig>,
+        keyframe: bool,
+        keyframes: u32,
+    }
+
+    impl Encoder for KeyframeEncoder {
+        fn configure(&mut self, config: EncoderConfig) -> Result<(), EncoderError> {
+            self.config = Some(config);
+            self.keyframe = true;
+            Ok(())
+        }
+
+        fn config(&self) -> Option<EncoderConfig> {
+            self.config
+        }
+
+        fn encode(&mut self, _frame: &[u8]) -> Result<Option<EncodedFrame>, EncoderError> {
+            if std::mem::take(&mut self.keyframe) {
+                self.keyframes += 1;
+            }
+            Ok(None)
+        }
+
+        fn flush(&mut self) -> Vec<EncodedFrame> {
+            Vec::new()
+        }
+
+        fn kind(&self) -> EncoderType {
+            EncoderType::Cpu
+        }
+
+        fn request_keyframe(&mut self) {
+            self.keyframe = true;
+        }
+    }
+
+    #[test]
+    fn probe_leaves_the_keyframe_for_the_stream() {
+        let config = EncoderConfig { width: 4, height: 4, format: StreamPixelFormat::NV24, quality: 80, subsampling: Subsampling::S420 };
+        let mut encoder = KeyframeEncoder::default();
+        encoder.configure(config).unwrap();
+        probe(&mut encoder, &config).unwrap();
+        assert_eq!(encoder.keyframes, 1);
+        assert!(encoder.keyframe);
+    }
+
+    #[test]
+    fn empty_chain_is_an_error() {
+        assert!(FallbackEncoder::new(Vec::new(), EncoderOptions::default()).is_err());
+    }
+}
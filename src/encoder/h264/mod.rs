@@ -0,0 +1,132 @@
+use std::borrow::Cow;
+use std::time::Instant;
+
+use openh264::OpenH264API;
+use openh264::encoder::{BitRate, Encoder as OpenH264Encoder, EncoderConfig as OpenH264Config, FrameRate, IntraFramePeriod, UsageType};
+use openh264::formats::YUVBuffer;
+
+use crate::{EncoderType, StreamPixelFormat};
+use crate::converters::downsampler::Mode;
+use super::{EncodedFrame, Encoder, EncoderConfig, EncoderError, EncoderOptions};
+
+/// Bitrate in kbit/s used when none is configured.
+pub const DEFAULT_BITRATE: u32 = 4000;
+
+/// Keyframe interval in frames used when none is configured.
+pub const DEFAULT_GOP: u32 = 60;
+
+/// Frame rate the rate control plans for, capture rarely goes above it.
+const MAX_FRAME_RATE: f32 = 60.0;
+
+/// Software H.264 encoder built on OpenH264.
+///
+/// Each encoded frame is one Annex-B access unit, sent over the image server socket exactly like
+/// a JPEG. Keyframes repeat the SPS and PPS, so a client can start decoding at any of them.
+/// JPEG quality does not apply, the stream follows `--bitrate` instead.
+pub struct H264Encoder {
+    config: Option<EncoderConfig>,
+    bitrate: u32,
+    gop: u32,
+    encoder: Option<OpenH264Encoder>,
+    keyframe: bool,
+}
+
+impl H264Encoder {
+    pub fn new(options: &EncoderOptions) -> Self {
+        H264Encoder {
+            config: None,
+            bitrate: if options.bitrate == 0 { DEFAULT_BITRATE } else { options.bitrate },
+            gop: if options.gop == 0 { DEFAULT_GOP } else { options.gop },
+            encoder: None,
+            keyframe: true,
+        }
+    }
+
+    fn open(&self) -> Result<OpenH264Encoder, EncoderError> {
+        let config = OpenH264Config::new()
+            .bitrate(BitRate::from_bps(self.bitrate.saturating_mul(1000)))
+            .max_frame_rate(FrameRate::from_hz(MAX_FRAME_RATE))
+            .intra_frame_period(IntraFramePeriod::from_num_frames(self.gop))
+            .usage_type(UsageType::ScreenContentRealTime);
+        OpenH264Encoder::with_api_config(OpenH264API::from_source(), config)
+            .map_err(|e| EncoderError::Compression(e.to_string()))
+    }
+}
+
+/// Converts a raw capture frame to NV12, the layout the H.264 input is built from.
+fn to_nv12<'a>(frame: &'a [u8], config: &EncoderConfig) -> Result<Cow<'a, [u8]>, EncoderError> {
+    let (width, height) = (config.width, config.height);
+    let expected = super::raw_frame_size(config.format, width, height);
+    if frame.len() < expected {
+        return Err(EncoderError::Compression(format!("short {:?} frame: {} bytes", config.format, frame.len())));
+    }
+    match config.format {
+        StreamPixelFormat::NV12 => Ok(Cow::Borrowed(frame)),
+        StreamPixelFormat::NV24 => Ok(Cow::Owned(crate::converters::downsampler::nv24_444_to_nv12_downsampler(frame, width, height, Mode::Fast))),
+        StreamPixelFormat::BGR3 => Ok(Cow::Owned(crate::converters::bgr3_888_to_nv12(frame, width, height))),
+        StreamPixelFormat::YUYV => Ok(Cow::Owned(crate::converters::yuyv422_to_nv12(frame, width as u32, height as u32))),
+        StreamPixelFormat::MJPG => Err(EncoderError::UnsupportedFormat(StreamPixelFormat::MJPG)),
+    }
+}
+
+impl Encoder for H264Encoder {
+    fn configure(&mut self, config: EncoderConfig) -> Result<(), EncoderError> {
+        if config.format == StreamPixelFormat::MJPG {
+            return Err(EncoderError::UnsupportedFormat(config.format));
+        }
+        if config.width % 2 != 0 || config.height % 2 != 0 {
+            return Err(EncoderError::Compression(format!("H.264 needs even frame dimensions, got {}x{}", config.width, config.height)));
+        }
+        let unchanged = self.config.is_some_and(|current| {
+            (current.width, current.height, current.format) == (config.width, config.height, config.format)
+        });
+        if !unchanged {
+            self.encoder = None;
+            self.keyframe = true;
+        }
+        self.config = Some(config);
+        Ok(())
+    }
+
+    fn config(&self) -> Option<EncoderConfig> {
+        self.config
+    }
+
+    fn encode(&mut self, frame: &[u8]) -> Result<Option<EncodedFrame>, EncoderError> {
+        let config = self.config.ok_or(EncoderError::NotConfigured)?;
+        let started = Instant::now();
+        if self.encoder.is_none() {
+            self.encoder = Some(self.open()?);
+        }
+
+        let nv12 = to_nv12(frame, &config)?;
+        let i420 = crate::converters::nv12_to_i420(&nv12, config.width, config.height);
+        let yuv = YUVBuffer::from_vec(i420, config.width, config.height);
+
+        let encoder = self.encoder.as_mut().ok_or(EncoderError::NotConfigured)?;
+        if self.keyframe {
+            encoder.force_intra_frame();
+            self.keyframe = false;
+        }
+        let access_unit = encoder.encode(&yuv)
+            .map_err(|e| EncoderError::Compression(e.to_string()))?
+            .to_vec();
+        if access_unit.is_empty() {
+            // Rate control skipped the frame.
+            return Ok(None);
+        }
+        Ok(Some(EncodedFrame::new(access_unit, frame.len(), started)))
+    }
+
+    fn flush(&mut self) -> Vec<EncodedFrame> {
+        Vec::new()
+    }
+
+    fn kind(&self) -> EncoderType {
+        EncoderType::H264
+    }
+
+    fn request_keyframe(&mut self) {
+        self.keyframe = true;
+    }
+}
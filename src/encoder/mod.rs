@@ -0,0 +1,316 @@
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+
+use crate::{EncoderType, StreamPixelFormat, Subsampling};
+use crate::dirty::DirtyMap;
+
+pub mod cpu;
+pub mod cpu_pool;
+#[cfg(mpp_accel)]
+pub mod mpp;
+pub mod strips;
+pub mod fallback;
+pub mod benchmark;
+pub mod snapshot;
+#[cfg(feature = "h264")]
+pub mod h264;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderConfig {
+    pub width: usize,
+    pub height: usize,
+    pub format: StreamPixelFormat,
+    pub quality: u8,
+    pub subsampling: Subsampling,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct EncodeStats {
+    pub encode_time: Duration,
+    pub input_size: usize,
+    pub output_size: usize,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct EncodedFrame {
+    pub data: Vec<u8>,
+    pub stats: EncodeStats,
+}
+
+impl EncodedFrame {
+    pub fn new(data: Vec<u8>, input_size: usize, started: Instant) -> Self {
+        let output_size = data.len();
+        EncodedFrame {
+            data,
+            stats: EncodeStats {
+                encode_time: started.elapsed(),
+                input_size,
+                output_size,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EncoderError {
+    NotConfigured,
+    UnsupportedFormat(StreamPixelFormat),
+    Compression(String),
+    Hardware(String),
+}
+
+impl std::fmt::Display for EncoderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncoderError::NotConfigured => write!(f, "encoder used before configure()"),
+            EncoderError::UnsupportedFormat(format) => write!(f, "unsupported pixel format {:?}", format),
+            EncoderError::Compression(e) => write!(f, "compression failed: {}", e),
+            EncoderError::Hardware(e) => write!(f, "hardware encoder failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EncoderError {}
+
+/// A JPEG encoding backend.
+///
+/// `configure` is called once the capture format is known and again whenever
+/// it changes. `encode` may return `Ok(None)` when the backend accepted the
+/// frame but has no output ready yet (pipelined backends), in which case the
+/// frame is returned by a later call or by `flush`.
+pub trait Encoder: Send {
+    fn configure(&mut self, config: EncoderConfig) -> Result<(), EncoderError>;
+
+    fn config(&self) -> Option<EncoderConfig>;
+
+    fn encode(&mut self, frame: &[u8]) -> Result<Option<EncodedFrame>, EncoderError>;
+
+    fn flush(&mut self) -> Vec<EncodedFrame>;
+
+    fn kind(&self) -> EncoderType;
+
+    fn name(&self) -> String {
+        self.kind().to_string()
+    }
+
+    /// Whether the encoder can accept another frame right now.
+    fn ready(&self) -> bool {
+        true
+    }
+
+    /// Blocks until the encoder can accept another frame or `timeout` passes.
+    fn wait_ready(&mut self, _timeout: Duration) -> bool {
+        self.ready()
+    }
+
+    /// Why the encoder in use is not the first choice, for encoders that fall back.
+    fn fallback_reason(&self) -> Option<String> {
+        None
+    }
+
+    /// Encodes a frame of which only the tiles marked in `dirty` changed since the previous one.
+    /// Encoders that can reuse their earlier output for the unchanged parts override this.
+    fn encode_dirty(&mut self, frame: &[u8], _dirty: &DirtyMap) -> Result<Option<EncodedFrame>, EncoderError> {
+        self.encode(frame)
+    }
+
+    /// Makes the next encoded frame a keyframe. Only meaningful for video codecs.
+    fn request_keyframe(&mut self) {}
+
+    /// Changes the JPEG quality used for subsequent frames without touching the rest of the configuration.
+    fn set_quality(&mut self, quality: u8) -> Result<(), EncoderError> {
+        let mut config = self.config().ok_or(EncoderError::NotConfigured)?;
+        config.quality = quality;
+        self.configure(config)
+    }
+}
+
+/// Backend specific settings that don't change while streaming.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncoderOptions {
+    /// Number of cpu pool workers, 0 means one per CPU.
+    pub workers: usize,
+    /// Lets the cpu pool drop frames instead of returning them later than this.
+    pub max_latency: Option<Duration>,
+    /// Strips the cpu encoder splits each frame into, 0 for auto and 1 to encode whole frames.
+    pub strips: usize,
+    /// H.264 target bitrate in kbit/s, 0 for the encoder default.
+    pub bitrate: u32,
+    /// Frames between H.264 keyframes, 0 for the encoder default.
+    pub gop: u32,
+}
+
+pub fn create_encoder(kind: EncoderType, options: &EncoderOptions) -> Box<dyn Encoder> {
+    try_create_encoder(kind, options).unwrap_or_else(|e| {
+        eprintln!("{}, using cpu encoder", e);
+        Box::new(cpu::CpuEncoder::with_strips(options.strips))
+    })
+}
+
+/// Like `create_encoder`, but reports backends that are not available instead of substituting one.
+pub fn try_create_encoder(kind: EncoderType, options: &EncoderOptions) -> Result<Box<dyn Encoder>, EncoderError> {
+    match kind {
+        #[cfg(mpp_accel)]
+        EncoderType::RockchipMpp => Ok(Box::new(mpp::MppEncoder::new()?)),
+        #[cfg(not(mpp_accel))]
+        EncoderType::RockchipMpp => Err(EncoderError::Hardware("Rockchip MPP support is not compiled in".to_string())),
+        EncoderType::CpuPool => Ok(Box::new(cpu_pool::CpuPoolEncoder::new(options))),
+        EncoderType::Cpu => Ok(Box::new(cpu::CpuEncoder::with_strips(options.strips))),
+        #[cfg(feature = "h264")]
+        EncoderType::H264 => Ok(Box::new(h264::H264Encoder::new(options))),
+        #[cfg(not(feature = "h264"))]
+        EncoderType::H264 => Err(EncoderError::Compression("H.264 support is not compiled in, build with --features h264".to_string())),
+        EncoderType::Auto => Err(EncoderError::Hardware("auto has to be resolved by benchmark::resolve_chain".to_string())),
+    }
+}
+
+/// Size in bytes of one uncompressed frame.
+pub fn raw_frame_size(format: StreamPixelFormat, width: usize, height: usize) -> usize {
+    match format {
+        StreamPixelFormat::NV12 => width * height * 3 / 2,
+        StreamPixelFormat::YUYV => width * height * 2,
+        StreamPixelFormat::NV24 | StreamPixelFormat::BGR3 => width * height * 3,
+        StreamPixelFormat::MJPG => 0,
+    }
+}
+
+/// Converts a raw capture frame into a packed 24-bit buffer turbojpeg can compress.
+/// BGR3 frames are borrowed as-is, everything else is converted to RGB.
+pub(crate) fn to_packed_rgb<'a>(frame: &'a [u8], config: &EncoderConfig) -> Result<(Cow<'a, [u8]>, turbojpeg::PixelFormat), EncoderError> {
+    let (width, height) = (config.width, config.height);
+    match config.format {
+        StreamPixelFormat::NV12 => {
+            let mut rgb_buf = vec![0u8; width * height * 3];
+            crate::converters::nv12_to_rgb_yuv(frame, width, height, &mut rgb_buf);
+            Ok((Cow::Owned(rgb_buf), turbojpeg::PixelFormat::RGB))
+        }
+        StreamPixelFormat::NV24 => {
+            let mut rgb_buf = vec![0u8; width * height * 3];
+            crate::converters::nv24_to_rgb_yuv(frame, width, height, &mut rgb_buf);
+            Ok((Cow::Owned(rgb_buf), turbojpeg::PixelFormat::RGB))
+        }
+        StreamPixelFormat::YUYV => {
+            let rgb_buf = crate::converters::yuyv_to_rgb_yuv(frame, width as u32, height as u32);
+            Ok((Cow::Owned(rgb_buf), turbojpeg::PixelFormat::RGB))
+        }
+        StreamPixelFormat::BGR3 => Ok((Cow::Borrowed(frame), turbojpeg::PixelFormat::BGR)),
+        StreamPixelFormat::MJPG => Err(EncoderError::UnsupportedFormat(StreamPixelFormat::MJPG)),
+    }
+}
+
+/// Compresses a raw capture frame with turbojpeg using the configured quality and subsampling.
+///
+/// NV24 at 4:4:4 is handed to turbojpeg as planar YUV so full chroma survives without an RGB
+/// round trip, and grayscale output from NV12/NV24 only needs the luma plane.
+pub(crate) fn compress_frame(frame: &[u8], config: &EncoderConfig) -> Result<Vec<u8>, EncoderError> {
+    let (width, height) = (config.width, config.height);
+    let quality = config.quality as i32;
+    let luma_size = width * height;
+    let result = match (config.format, config.subsampling) {
+        (StreamPixelFormat::NV24, Subsampling::S444) => {
+            if frame.len() < luma_size * 3 {
+                return Err(EncoderError::Compression(format!("short NV24 frame: {} bytes", frame.len())));
+            }
+            let mut planar = Vec::with_capacity(luma_size * 3);
+            planar.extend_from_slice(&frame[..luma_size]);
+            let chroma = &frame[luma_size..luma_size * 3];
+            planar.extend(chroma.iter().step_by(2));
+            planar.extend(chroma.iter().skip(1).step_by(2));
+            let image = turbojpeg::YuvImage {
+                pixels: planar.as_slice(),
+                width,
+                align: 1,
+                height,
+                subsamp: turbojpeg::Subsamp::None,
+            };
+            turbojpeg::compress_yuv(image, quality)
+        }
+        (StreamPixelFormat::NV12 | StreamPixelFormat::NV24, Subsampling::Gray) => {
+            if frame.len() < luma_size {
+                return Err(EncoderError::Compression(format!("short frame: {} bytes", frame.len())));
+            }
+            let image = turbojpeg::Image {
+                pixels: &frame[..luma_size],
+                width,
+                pitch: width,
+                height,
+                format: turbojpeg::PixelFormat::GRAY,
+            };
+            turbojpeg::compress(image, quality, turbojpeg::Subsamp::Gray)
+        }
+        _ => {
+            let (pixels, format) = to_packed_rgb(frame, config)?;
+            let image = turbojpeg::Image {
+                pixels: pixels.as_ref(),
+                width,
+                pitch: width * 3,
+                height,
+                format,
+            };
+            turbojpeg::compress(image, quality, config.subsampling.to_turbojpeg())
+        }
+    };
+    result
+        .map(|jpeg| jpeg.to_vec())
+        .map_err(|e| EncoderError::Compression(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mjpg_passes_through() {
+        let config = EncoderConfig { width: 4, height: 4, format: StreamPixelFormat::MJPG, quality: 80, subsampling: Subsampling::S420 };
+        for kind in [EncoderType::Cpu, EncoderType::CpuPool] {
+            let mut encoder = create_encoder(kind, &EncoderOptions { workers: 1, ..Default::default() });
+            encoder.configure(config).unwrap();
+            let frame = encoder.encode(&[0xFF, 0xD8, 0xFF, 0xD9]).unwrap().unwrap();
+            assert_eq!(frame.data, vec![0xFF, 0xD8, 0xFF, 0xD9]);
+            assert_eq!(encoder.name(), kind.to_string());
+        }
+    }
+
+    #[test]
+    fn quality_change_keeps_config() {
+        let config = EncoderConfig { width: 4, height: 4, format: StreamPixelFormat::NV12, quality: 80, subsampling: Subsampling::S420 };
+        let mut encoder = create_encoder(EncoderType::Cpu, &EncoderOptions::default());
+        assert!(encoder.set_quality(50).is_err());
+        encoder.configure(config).unwrap();
+        encoder.set_quality(50).unwrap();
+        assert_eq!(encoder.config(), Some(EncoderConfig { quality: 50, ..config }));
+    }
+
+    #[test]
+    fn short_nv24_frame_is_rejected() {
+        let config = EncoderConfig { width: 4, height: 4, format: StreamPixelFormat::NV24, quality: 80, subsampling: Subsampling::S444 };
+        assert!(matches!(compress_frame(&[0u8; 16], &config), Err(EncoderError::Compression(_))));
+    }
+
+    #[test]
+    fn dirty_strips_match_full_encode() {
+        let config = EncoderConfig { width: 128, height: 128, format: StreamPixelFormat::NV12, quality: 80, subsampling: Subsampling::S420 };
+        let mut encoder = cpu::CpuEncoder::with_strips(4);
+        encoder.configure(config).unwrap();
+        let mut tracker = crate::dirty::DirtyTracker::new();
+        let mut frame: Vec<u8> = (0..128 * 128 * 3 / 2).map(|i| (i % 251) as u8).collect();
+
+        let dirty = tracker.update(&frame, &config);
+        let first = encoder.encode_dirty(&frame, &dirty).unwrap().unwrap().data;
+        let dirty = tracker.update(&frame, &config);
+        assert!(dirty.is_clean());
+        assert_eq!(encoder.encode_dirty(&frame, &dirty).unwrap().unwrap().data, first);
+
+        frame[100 * 128 + 5] ^= 0xFF;
+        let dirty = tracker.update(&frame, &config);
+        let partial = encoder.encode_dirty(&frame, &dirty).unwrap().unwrap().data;
+        assert_ne!(partial, first);
+        assert_eq!(partial, strips::encode_strips(&frame, &config, 4).unwrap());
+    }
+
+    #[test]
+    fn encode_before_configure() {
+        let mut encoder = create_encoder(EncoderType::Cpu, &EncoderOptions::default());
+        assert!(matches!(encoder.encode(&[0u8; 16]), Err(EncoderError::NotConfigured)));
+    }
+}
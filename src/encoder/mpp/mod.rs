@@ -0,0 +1,59 @@
+use std::time::Instant;
+
+use crate::{EncoderType, StreamPixelFormat, Subsampling};
+use crate::rk_mpp::{MppError, MppJpegEncoder};
+use super::{EncodedFrame, Encoder, EncoderConfig, EncoderError};
+
+impl From<MppError> for EncoderError {
+    fn from(e: MppError) -> Self {
+        match e {
+            MppError::UnsupportedFormat(format) => EncoderError::UnsupportedFormat(format),
+            e => EncoderError::Hardware(e.to_string()),
+        }
+    }
+}
+
+pub struct MppEncoder {
+    config: Option<EncoderConfig>,
+    session: MppJpegEncoder,
+}
+
+impl MppEncoder {
+    /// Opens the MPP session up front so a missing or broken VPU shows up before streaming starts.
+    pub fn new() -> Result<Self, EncoderError> {
+        Ok(MppEncoder { config: None, session: MppJpegEncoder::new()? })
+    }
+}
+
+impl Encoder for MppEncoder {
+    fn configure(&mut self, config: EncoderConfig) -> Result<(), EncoderError> {
+        if config.subsampling != Subsampling::S420 {
+            eprintln!("MPP always encodes 4:2:0, ignoring {} subsampling", config.subsampling);
+        }
+        self.config = Some(config);
+        Ok(())
+    }
+
+    fn config(&self) -> Option<EncoderConfig> {
+        self.config
+    }
+
+    fn encode(&mut self, frame: &[u8]) -> Result<Option<EncodedFrame>, EncoderError> {
+        let config = self.config.ok_or(EncoderError::NotConfigured)?;
+        let started = Instant::now();
+        if config.format == StreamPixelFormat::MJPG {
+            return Ok(Some(EncodedFrame::new(frame.to_vec(), frame.len(), started)));
+        }
+
+        let jpeg_data = self.session.encode(frame.to_vec(), config.width as u32, config.height as u32, config.quality, config.format)?;
+        Ok(Some(EncodedFrame::new(jpeg_data, frame.len(), started)))
+    }
+
+    fn flush(&mut self) -> Vec<EncodedFrame> {
+        Vec::new()
+    }
+
+    fn kind(&self) -> EncoderType {
+        EncoderType::RockchipMpp
+    }
+}
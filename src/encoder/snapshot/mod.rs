@@ -0,0 +1,292 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use image::{ExtendedColorType, ImageEncoder};
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use rgb::FromSlice;
+use shared::control::MAX_SNAPSHOT_SIDE;
+
+use crate::{StreamPixelFormat, Subsampling};
+use super::{EncoderConfig, EncoderError, compress_frame, to_packed_rgb};
+
+/// Formats a single snapshot can be requested in, independent of the stream encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    Jpeg,
+    Png,
+    WebP,
+}
+
+impl SnapshotFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "jpeg" | "jpg" => Some(SnapshotFormat::Jpeg),
+            "png" => Some(SnapshotFormat::Png),
+            "webp" => Some(SnapshotFormat::WebP),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for SnapshotFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotFormat::Jpeg => write!(f, "jpeg"),
+            SnapshotFormat::Png => write!(f, "png"),
+            SnapshotFormat::WebP => write!(f, "webp"),
+        }
+    }
+}
+
+/// A snapshot asked for by the web server, e.g. `jpeg,id=3,quality=95,subsampling=444,width=1280`.
+///
+/// Settings left out fall back to the stream's, a missing height keeps the aspect ratio.
+/// Neither side may exceed `MAX_SNAPSHOT_SIDE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotRequest {
+    pub id: u32,
+    pub format: SnapshotFormat,
+    pub quality: Option<u8>,
+    pub subsampling: Option<Subsampling>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+}
+
+impl SnapshotRequest {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut fields = value.trim().split(',');
+        let mut request = SnapshotRequest {
+            id: 0,
+            format: SnapshotFormat::parse(fields.next()?)?,
+            quality: None,
+            subsampling: None,
+            width: None,
+            height: None,
+        };
+        for field in fields {
+            let (key, value) = field.split_once('=')?;
+            match key {
+                "id" => request.id = value.parse().ok()?,
+                "quality" => request.quality = Some(value.parse::<u8>().ok().filter(|q| (1..=100).contains(q))?),
+                "subsampling" => request.subsampling = Some(<Subsampling as clap::ValueEnum>::from_str(value, false).ok()?),
+                "width" => request.width = Some(value.parse::<usize>().ok().filter(|w| (1..=MAX_SNAPSHOT_SIDE).contains(w))?),
+                "height" => request.height = Some(value.parse::<usize>().ok().filter(|h| (1..=MAX_SNAPSHOT_SIDE).contains(h))?),
+                _ => return None,
+            }
+        }
+        Some(request)
+    }
+
+    /// Output size for a source of `width`x`height`, `None` if a side would exceed `MAX_SNAPSHOT_SIDE`.
+    fn size(&self, width: usize, height: usize) -> Option<(usize, usize)> {
+        let (width, height) = match (self.width, self.height) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(w), None) => (w, (height.checked_mul(w)? / width.max(1)).max(1)),
+            (None, Some(h)) => ((width.checked_mul(h)? / height.max(1)).max(1), h),
+            (None, None) => (width, height),
+        };
+        (width <= MAX_SNAPSHOT_SIDE && height <= MAX_SNAPSHOT_SIDE).then_some((width, height))
+    }
+}
+
+/// How long a snapshot waits for capture to hand over a fresh frame before it settles for the last one.
+pub const FRAME_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+struct Retained {
+    data: Vec<u8>,
+    config: Option<EncoderConfig>,
+    /// Bumped on every fill, so waiters can tell a fresh frame from the one they started with.
+    generation: u64,
+}
+
+/// The raw capture frame snapshots are encoded from.
+///
+/// Capture only copies a frame in while a snapshot waits for one, streaming doesn't pay for
+/// snapshots nobody asks for. The last frame copied stays around for when capture can't deliver.
+#[derive(Debug, Default)]
+pub struct RetainedFrame {
+    frame: Mutex<Retained>,
+    filled: Condvar,
+    wanted: AtomicBool,
+}
+
+impl RetainedFrame {
+    /// Whether a snapshot is waiting for the next frame.
+    pub fn wanted(&self) -> bool {
+        self.wanted.load(Ordering::Relaxed)
+    }
+
+    /// Hands `frame` to the snapshots waiting for one, reusing the previous allocation.
+    pub fn fill(&self, frame: &[u8], config: Option<EncoderConfig>) {
+        let Ok(mut retained) = self.frame.lock() else {
+            return;
+        };
+        retained.data.clear();
+        retained.data.extend_from_slice(frame);
+        retained.config = config;
+        retained.generation += 1;
+        self.wanted.store(false, Ordering::Relaxed);
+        self.filled.notify_all();
+    }
+
+    /// Asks capture for its next frame and returns a copy of it, or of the last frame retained
+    /// if none arrives within `wait`.
+    fn next_frame(&self, wait: Duration) -> Result<(Vec<u8>, EncoderConfig), EncoderError> {
+        fn poisoned<T>(_: T) -> EncoderError {
+            EncoderError::Compression("retained frame lock poisoned".to_string())
+        }
+        let retained = self.frame.lock().map_err(poisoned)?;
+        let generation = retained.generation;
+        self.wanted.store(true, Ordering::Relaxed);
+        let (retained, _) = self.filled.wait_timeout_while(retained, wait, |retained| retained.generation == generation).map_err(poisoned)?;
+        Ok((retained.data.clone(), retained.config.ok_or(EncoderError::NotConfigured)?))
+    }
+}
+
+/// Encodes the next captured frame for `request`. The frame is copied out first so capture
+/// isn't held up while the snapshot is encoded.
+pub fn snapshot(retained: &RetainedFrame, request: &SnapshotRequest, wait: Duration) -> Result<Vec<u8>, EncoderError> {
+    let (frame, config) = retained.next_frame(wait)?;
+    encode_snapshot(&frame, &config, request)
+}
+
+/// Decodes a raw or MJPG frame into packed RGB.
+fn to_rgb(frame: &[u8], config: &EncoderConfig) -> Result<(Vec<u8>, usize, usize), EncoderError> {
+    let (rgb, width, height) = match config.format {
+        StreamPixelFormat::MJPG => {
+            let image = turbojpeg::decompress(frame, turbojpeg::PixelFormat::RGB)
+                .map_err(|e| EncoderError::Compression(e.to_string()))?;
+            (image.pixels, image.width, image.height)
+        }
+        _ => {
+            let (pixels, pixel_format) = to_packed_rgb(frame, config)?;
+            let mut rgb = pixels.into_owned();
+            if pixel_format == turbojpeg::PixelFormat::BGR {
+                rgb.chunks_exact_mut(3).for_each(|pixel| pixel.swap(0, 2));
+            }
+            (rgb, config.width, config.height)
+        }
+    };
+    if rgb.len() < width * height * 3 {
+        return Err(EncoderError::Compression(format!("short frame: {} bytes", frame.len())));
+    }
+    Ok((rgb, width, height))
+}
+
+/// Encodes one raw capture frame as a standalone image, independent of the stream settings.
+///
+/// PNG and WebP are lossless so text on BIOS screens comes out pixel exact. A JPEG at the
+/// source size is compressed straight from the raw frame, like a stream frame would be.
+pub fn encode_snapshot(frame: &[u8], config: &EncoderConfig, request: &SnapshotRequest) -> Result<Vec<u8>, EncoderError> {
+    let quality = request.quality.unwrap_or(config.quality);
+    let subsampling = request.subsampling.unwrap_or(config.subsampling);
+    let (width, height) = request
+        .size(config.width, config.height)
+        .ok_or_else(|| EncoderError::Compression(format!("snapshots are limited to {0}x{0}", MAX_SNAPSHOT_SIDE)))?;
+
+    if request.format == SnapshotFormat::Jpeg && (width, height) == (config.width, config.height) && config.format != StreamPixelFormat::MJPG {
+        return compress_frame(frame, &EncoderConfig { quality, subsampling, ..*config });
+    }
+
+    let (mut rgb, src_width, src_height) = to_rgb(frame, config)?;
+    if (width, height) != (src_width, src_height) {
+        let mut scaled = vec![0u8; width * height * 3];
+        let mut resizer = resize::new(src_width, src_height, width, height, resize::Pixel::RGB8, resize::Type::Lanczos3)
+            .map_err(|e| EncoderError::Compression(e.to_string()))?;
+        resizer.resize(rgb[..src_width * src_height * 3].as_rgb(), scaled.as_rgb_mut())
+            .map_err(|e| EncoderError::Compression(e.to_string()))?;
+        rgb = scaled;
+    }
+    let rgb = &rgb[..width * height * 3];
+
+    let mut out = Vec::new();
+    let result = match request.format {
+        SnapshotFormat::Jpeg => {
+            let image = turbojpeg::Image { pixels: rgb, width, pitch: width * 3, height, format: turbojpeg::PixelFormat::RGB };
+            turbojpeg::compress(image, quality as i32, subsampling.to_turbojpeg())
+                .map(|jpeg| out = jpeg.to_vec())
+                .map_err(|e| e.to_string())
+        }
+        SnapshotFormat::Png => PngEncoder::new(&mut out)
+            .write_image(rgb, width as u32, height as u32, ExtendedColorType::Rgb8)
+            .map_err(|e| e.to_string()),
+        SnapshotFormat::WebP => WebPEncoder::new_lossless(&mut out)
+            .write_image(rgb, width as u32, height as u32, ExtendedColorType::Rgb8)
+            .map_err(|e| e.to_string()),
+    };
+    result.map_err(EncoderError::Compression)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(format: SnapshotFormat) -> SnapshotRequest {
+        SnapshotRequest { id: 0, format, quality: None, subsampling: None, width: None, height: None }
+    }
+
+    #[test]
+    fn bgr_snapshot_is_lossless() {
+        let config = EncoderConfig { width: 2, height: 2, format: StreamPixelFormat::BGR3, quality: 80, subsampling: Subsampling::S420 };
+        let bgr = [255, 0, 0, 0, 255, 0, 0, 0, 255, 10, 20, 30];
+        for format in [SnapshotFormat::Png, SnapshotFormat::WebP] {
+            let encoded = encode_snapshot(&bgr, &config, &request(format)).unwrap();
+            let decoded = image::load_from_memory(&encoded).unwrap().to_rgb8();
+            assert_eq!(decoded.as_raw(), &vec![0, 0, 255, 0, 255, 0, 255, 0, 0, 30, 20, 10]);
+        }
+    }
+
+    #[test]
+    fn snapshot_is_scaled() {
+        let config = EncoderConfig { width: 8, height: 4, format: StreamPixelFormat::BGR3, quality: 80, subsampling: Subsampling::S420 };
+        let retained = std::sync::Arc::new(RetainedFrame::default());
+        let png = SnapshotRequest { width: Some(4), ..request(SnapshotFormat::Png) };
+        assert!(matches!(snapshot(&retained, &png, Duration::from_millis(10)), Err(EncoderError::NotConfigured)));
+        assert!(retained.wanted());
+
+        // Stands in for capture, which fills the frame once a snapshot wants one.
+        let capture = retained.clone();
+        let filler = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            capture.fill(&[128; 8 * 4 * 3], Some(config));
+        });
+        let decoded = image::load_from_memory(&snapshot(&retained, &png, FRAME_WAIT).unwrap()).unwrap();
+        filler.join().unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (4, 2));
+        assert!(!retained.wanted());
+
+        // Without a fresh frame the last one is used.
+        let decoded = image::load_from_memory(&snapshot(&retained, &png, Duration::from_millis(10)).unwrap()).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (4, 2));
+    }
+
+    #[test]
+    fn oversized_snapshots_are_refused() {
+        assert_eq!(SnapshotRequest::parse("png,width=100000"), None);
+        assert_eq!(SnapshotRequest::parse(&format!("png,height={}", MAX_SNAPSHOT_SIDE + 1)), None);
+
+        // A side within the limit can still scale the other one past it.
+        let config = EncoderConfig { width: 8, height: 8192, format: StreamPixelFormat::BGR3, quality: 80, subsampling: Subsampling::S420 };
+        let wide = SnapshotRequest { width: Some(16), ..request(SnapshotFormat::Png) };
+        assert_eq!(wide.size(config.width, config.height), None);
+        assert!(matches!(encode_snapshot(&[], &config, &wide), Err(EncoderError::Compression(_))));
+        assert_eq!(SnapshotRequest { width: Some(usize::MAX), ..wide }.size(2, 2), None);
+        assert_eq!(SnapshotRequest { width: Some(4), ..wide }.size(8, 8192), Some((4, 4096)));
+    }
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(SnapshotRequest::parse("png"), Some(request(SnapshotFormat::Png)));
+        assert_eq!(
+            SnapshotRequest::parse("jpeg,id=7,quality=95,subsampling=444,width=1280"),
+            Some(SnapshotRequest { id: 7, quality: Some(95), subsampling: Some(Subsampling::S444), width: Some(1280), ..request(SnapshotFormat::Jpeg) })
+        );
+        assert_eq!(SnapshotRequest::parse("jpeg,quality=0"), None);
+        assert_eq!(SnapshotRequest::parse("jpeg,scale=2"), None);
+        assert_eq!(SnapshotRequest::parse("bmp"), None);
+    }
+}
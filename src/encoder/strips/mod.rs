@@ -0,0 +1,217 @@
+use rayon::prelude::*;
+
+use crate::{StreamPixelFormat, Subsampling};
+use super::{EncoderConfig, EncoderError, compress_frame};
+
+/// Frames at least this large are split into strips when the strip count is left on auto.
+pub const AUTO_STRIP_PIXELS: usize = 3840 * 2160;
+
+/// Width and height in pixels of one MCU for the given subsampling.
+pub fn mcu_size(subsampling: Subsampling) -> (usize, usize) {
+    match subsampling {
+        Subsampling::S444 | Subsampling::Gray => (8, 8),
+        Subsampling::S422 => (16, 8),
+        Subsampling::S420 => (16, 16),
+    }
+}
+
+/// Number of strips to use for a frame. `requested` is 0 for auto, 1 to disable strips.
+pub fn strip_count(requested: usize, config: &EncoderConfig) -> usize {
+    match requested {
+        0 if config.width * config.height >= AUTO_STRIP_PIXELS => num_cpus::get(),
+        0 => 1,
+        n => n,
+    }
+}
+
+/// How a frame is split into strips.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StripLayout {
+    /// First row and number of rows of each strip.
+    pub bands: Vec<(usize, usize)>,
+    /// MCUs per strip, the restart interval of the stitched JPEG.
+    pub restart_interval: u16,
+}
+
+/// Splits a frame into about `strips` bands of whole MCU rows, or `None` if it is encoded whole.
+pub fn strip_layout(config: &EncoderConfig, strips: usize) -> Option<StripLayout> {
+    let (mcu_width, mcu_height) = mcu_size(config.subsampling);
+    let mcu_rows = config.height.div_ceil(mcu_height);
+    let mcus_per_row = config.width.div_ceil(mcu_width);
+    // A restart interval is counted in MCUs and has to fit in the 16 bit DRI field.
+    let max_rows_per_strip = (u16::MAX as usize / mcus_per_row).max(1);
+    let rows_per_strip = mcu_rows.div_ceil(strips.max(1)).clamp(1, max_rows_per_strip);
+    let strip_height = rows_per_strip * mcu_height;
+
+    if strip_height >= config.height || config.format == StreamPixelFormat::MJPG {
+        return None;
+    }
+
+    let bands = (0..config.height)
+        .step_by(strip_height)
+        .map(|y| (y, strip_height.min(config.height - y)))
+        .collect();
+    Some(StripLayout { bands, restart_interval: (rows_per_strip * mcus_per_row) as u16 })
+}
+
+/// Encodes rows `y..y + rows` of a frame as a standalone JPEG, one part for `stitch`.
+pub fn encode_strip(frame: &[u8], config: &EncoderConfig, y: usize, rows: usize) -> Result<Vec<u8>, EncoderError> {
+    let strip = frame_rows(frame, config, y, rows)?;
+    compress_frame(&strip, &EncoderConfig { height: rows, ..*config })
+}
+
+/// Encodes one frame as `strips` horizontal bands in parallel and stitches them into a single
+/// baseline JPEG separated by restart markers.
+///
+/// Every band is a standalone JPEG with the same quality and the standard Huffman tables, so
+/// its entropy-coded data can be reused as one restart interval of the full image.
+pub fn encode_strips(frame: &[u8], config: &EncoderConfig, strips: usize) -> Result<Vec<u8>, EncoderError> {
+    let Some(layout) = strip_layout(config, strips) else {
+        return compress_frame(frame, config);
+    };
+
+    let parts = layout.bands
+        .par_iter()
+        .map(|&(y, rows)| encode_strip(frame, config, y, rows))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    stitch(&parts, config.height as u16, layout.restart_interval)
+}
+
+/// Copies rows `y..y + rows` of a raw frame into a standalone frame of the same format.
+fn frame_rows(frame: &[u8], config: &EncoderConfig, y: usize, rows: usize) -> Result<Vec<u8>, EncoderError> {
+    let width = config.width;
+    let short = || EncoderError::Compression(format!("short {:?} frame: {} bytes", config.format, frame.len()));
+    let strip = match config.format {
+        StreamPixelFormat::NV12 => {
+            let luma = width * config.height;
+            let mut strip = Vec::with_capacity(width * rows * 3 / 2);
+            strip.extend_from_slice(frame.get(y * width..(y + rows) * width).ok_or_else(short)?);
+            let chroma = luma + y / 2 * width;
+            strip.extend_from_slice(frame.get(chroma..chroma + rows.div_ceil(2) * width).ok_or_else(short)?);
+            strip
+        }
+        StreamPixelFormat::NV24 => {
+            let luma = width * config.height;
+            let mut strip = Vec::with_capacity(width * rows * 3);
+            strip.extend_from_slice(frame.get(y * width..(y + rows) * width).ok_or_else(short)?);
+            let chroma = luma + y * width * 2;
+            strip.extend_from_slice(frame.get(chroma..chroma + rows * width * 2).ok_or_else(short)?);
+            strip
+        }
+        StreamPixelFormat::YUYV => frame.get(y * width * 2..(y + rows) * width * 2).ok_or_else(short)?.to_vec(),
+        StreamPixelFormat::BGR3 => frame.get(y * width * 3..(y + rows) * width * 3).ok_or_else(short)?.to_vec(),
+        StreamPixelFormat::MJPG => return Err(EncoderError::UnsupportedFormat(StreamPixelFormat::MJPG)),
+    };
+    Ok(strip)
+}
+
+const SOI: u8 = 0xD8;
+const EOI: u8 = 0xD9;
+const SOS: u8 = 0xDA;
+const DRI: u8 = 0xDD;
+const SOF0: u8 = 0xC0;
+const RST0: u8 = 0xD0;
+
+/// Offsets of the SOF0 segment and the SOS marker, and where the scan data starts.
+fn find_segments(jpeg: &[u8]) -> Result<(usize, usize, usize), EncoderError> {
+    let invalid = |reason: &str| EncoderError::Compression(format!("cannot stitch strip: {}", reason));
+    if jpeg.len() < 4 || jpeg[0] != 0xFF || jpeg[1] != SOI {
+        return Err(invalid("missing SOI"));
+    }
+    let mut sof = None;
+    let mut pos = 2;
+    while pos + 4 <= jpeg.len() {
+        if jpeg[pos] != 0xFF {
+            return Err(invalid("bad marker"));
+        }
+        let marker = jpeg[pos + 1];
+        let length = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        match marker {
+            SOF0 => sof = Some(pos),
+            SOS => {
+                let sof = sof.ok_or_else(|| invalid("not a baseline JPEG"))?;
+                return Ok((sof, pos, pos + 2 + length));
+            }
+            _ => {}
+        }
+        pos += 2 + length;
+    }
+    Err(invalid("missing SOS"))
+}
+
+/// Joins separately encoded strips into one JPEG, using the first strip's headers.
+pub fn stitch(parts: &[Vec<u8>], height: u16, restart_interval: u16) -> Result<Vec<u8>, EncoderError> {
+    let first = parts.first().ok_or_else(|| EncoderError::Compression("no strips to stitch".to_string()))?;
+    let (sof, sos, _) = find_segments(first)?;
+
+    let total: usize = parts.iter().map(|part| part.len()).sum();
+    let mut jpeg = Vec::with_capacity(total + 6 + parts.len() * 2);
+    jpeg.extend_from_slice(&first[..sos]);
+    // SOF0 is FF C0, length, precision, then the image height.
+    jpeg[sof + 5..sof + 7].copy_from_slice(&height.to_be_bytes());
+    jpeg.extend_from_slice(&[0xFF, DRI, 0x00, 0x04]);
+    jpeg.extend_from_slice(&restart_interval.to_be_bytes());
+
+    for (index, part) in parts.iter().enumerate() {
+        let (_, sos, scan) = find_segments(part)?;
+        if index == 0 {
+            jpeg.extend_from_slice(&part[sos..scan]);
+        } else {
+            jpeg.extend_from_slice(&[0xFF, RST0 + ((index - 1) % 8) as u8]);
+        }
+        let end = if part.ends_with(&[0xFF, EOI]) { part.len() - 2 } else { part.len() };
+        jpeg.extend_from_slice(&part[scan..end]);
+    }
+    jpeg.extend_from_slice(&[0xFF, EOI]);
+    Ok(jpeg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fake_jpeg(height: u16, scan: &[u8]) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, SOI];
+        jpeg.extend_from_slice(&[0xFF, 0xDB, 0x00, 0x03, 0x00]);
+        jpeg.extend_from_slice(&[0xFF, SOF0, 0x00, 0x08, 0x08]);
+        jpeg.extend_from_slice(&height.to_be_bytes());
+        jpeg.extend_from_slice(&[0x00, 0x10, 0x01]);
+        jpeg.extend_from_slice(&[0xFF, SOS, 0x00, 0x03, 0x01]);
+        jpeg.extend_from_slice(scan);
+        jpeg.extend_from_slice(&[0xFF, EOI]);
+        jpeg
+    }
+
+    #[test]
+    fn stitch_inserts_restart_markers() {
+        let parts = vec![fake_jpeg(16, &[1, 2]), fake_jpeg(16, &[3, 0xFF, 0x00]), fake_jpeg(8, &[4])];
+        let jpeg = stitch(&parts, 40, 2).unwrap();
+
+        let (sof, sos, scan) = find_segments(&jpeg).unwrap();
+        assert_eq!(&jpeg[sof + 5..sof + 7], &40u16.to_be_bytes());
+        assert_eq!(&jpeg[sos - 6..sos], &[0xFF, DRI, 0x00, 0x04, 0x00, 0x02]);
+        assert_eq!(&jpeg[scan..], &[1, 2, 0xFF, RST0, 3, 0xFF, 0x00, 0xFF, RST0 + 1, 4, 0xFF, EOI]);
+    }
+
+    #[test]
+    fn strip_rows_are_mcu_aligned() {
+        let config = EncoderConfig { width: 64, height: 40, format: StreamPixelFormat::NV12, quality: 80, subsampling: Subsampling::S420 };
+        let frame: Vec<u8> = (0..64 * 40 * 3 / 2).map(|i| i as u8).collect();
+        let strip = frame_rows(&frame, &config, 16, 16).unwrap();
+        assert_eq!(strip.len(), 64 * 16 * 3 / 2);
+        assert_eq!(strip[0], frame[16 * 64]);
+        assert_eq!(strip[64 * 16], frame[64 * 40 + 8 * 64]);
+        assert!(frame_rows(&frame[..100], &config, 16, 16).is_err());
+    }
+
+    #[test]
+    fn auto_strips_only_for_large_frames() {
+        let mut config = EncoderConfig { width: 1920, height: 1080, format: StreamPixelFormat::NV12, quality: 80, subsampling: Subsampling::S420 };
+        assert_eq!(strip_count(0, &config), 1);
+        assert_eq!(strip_count(4, &config), 4);
+        config.width = 3840;
+        config.height = 2160;
+        assert_eq!(strip_count(0, &config), num_cpus::get());
+    }
+}
@@ -8,6 +8,16 @@ pub mod error;
 pub mod config;
 pub mod ring;
 pub mod packet;
+pub mod encoder;
+pub mod control;
+pub mod consumer;
+pub mod adaptive;
+pub mod dedup;
+pub mod demand;
+pub mod dirty;
+pub mod memsink;
+pub mod transport;
+pub use shared::{memfd, protocol, socket};
 
 
 pub struct Color {
@@ -16,26 +26,86 @@ pub struct Color {
     b: u8,
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum StreamPixelFormat {
     NV12,
     BGR3,
     NV24,
+    YUYV,
+    MJPG,
 }
 
-#[derive(PartialEq, Eq)]
-pub enum Encoder {
+impl StreamPixelFormat {
+    pub fn from_fourcc(fourcc: &str) -> Option<Self> {
+        match fourcc {
+            "NV12" => Some(StreamPixelFormat::NV12),
+            "BGR3" => Some(StreamPixelFormat::BGR3),
+            "NV24" => Some(StreamPixelFormat::NV24),
+            "YUYV" => Some(StreamPixelFormat::YUYV),
+            "MJPG" => Some(StreamPixelFormat::MJPG),
+            _ => None,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, clap::ValueEnum)]
+pub enum EncoderType {
+    #[value(name = "mpp")]
     RockchipMpp,
+    #[value(name = "cpu-pool")]
     CpuPool,
+    #[value(name = "cpu")]
     Cpu,
+    /// Software H.264, only available when built with the `h264` feature.
+    #[value(name = "h264")]
+    H264,
+    /// Picked at startup by benchmarking the other backends.
+    #[value(name = "auto")]
+    Auto,
+}
+
+impl std::fmt::Display for EncoderType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncoderType::RockchipMpp => write!(f, "rockchip mpp"),
+            EncoderType::CpuPool => write!(f, "cpu pool"),
+            EncoderType::Cpu => write!(f, "cpu"),
+            EncoderType::H264 => write!(f, "h264"),
+            EncoderType::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Subsampling {
+    #[value(name = "444")]
+    S444,
+    #[value(name = "422")]
+    S422,
+    #[value(name = "420")]
+    S420,
+    #[value(name = "gray")]
+    Gray,
+}
+
+impl Subsampling {
+    pub fn to_turbojpeg(self) -> turbojpeg::Subsamp {
+        match self {
+            Subsampling::S444 => turbojpeg::Subsamp::None,
+            Subsampling::S422 => turbojpeg::Subsamp::Sub2x1,
+            Subsampling::S420 => turbojpeg::Subsamp::Sub2x2,
+            Subsampling::Gray => turbojpeg::Subsamp::Gray,
+        }
+    }
 }
 
-impl ToString for Encoder {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for Subsampling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Encoder::RockchipMpp => "rockchip mpp".to_string(),
-            Encoder::CpuPool => "cpu pool".to_string(),
-            Encoder::Cpu => "cpu".to_string(),
+            Subsampling::S444 => write!(f, "4:4:4"),
+            Subsampling::S422 => write!(f, "4:2:2"),
+            Subsampling::S420 => write!(f, "4:2:0"),
+            Subsampling::Gray => write!(f, "gray"),
         }
     }
 }
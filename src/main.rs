@@ -1,34 +1,45 @@
 
 
 use clap::Parser;
-use turbojpeg::compress;
+use serde_json::json;
 use turbojpeg::image::ImageBuffer;
-use turbojpeg::Image;
-use turbojpeg::Subsamp;
-use ustreamer::Encoder;
+use ustreamer::EncoderType;
 use ustreamer::bind_socket;
+use ustreamer::encoder::{Encoder, EncoderConfig};
+use ustreamer::encoder::benchmark;
+use ustreamer::encoder::snapshot::{self, RetainedFrame, SnapshotRequest};
+use ustreamer::encoder::fallback::FallbackEncoder;
 
+use ustreamer::adaptive::AdaptiveQuality;
 use ustreamer::config::Args;
 use ustreamer::config::StreamConfig;
+use ustreamer::consumer::{Consumers, FrameQueue};
+use ustreamer::control::{CaptureStats, ControlCommand, Reply, ResolutionChange, split_request};
+use ustreamer::demand::{self, Demand, IdlePolicy};
 use ustreamer::lock::StreamLock;
+use ustreamer::memfd;
+use ustreamer::memsink::{self, FrameInfo, Sinks};
 use ustreamer::packet::Packet;
+use ustreamer::protocol;
+use ustreamer::dedup::{ChangeDetector, FrameChange};
+use ustreamer::dirty::{DirtyMap, DirtyTracker};
 use ustreamer::ring::RingBuffer;
 use ustreamer::server;
 use ustreamer::server::img::ImageData;
-use ustreamer::StreamPixelFormat;
-use v4l2r::ioctl::PlaneMapping;
+use ustreamer::transport::{self, FrameWriter};
+use ustreamer::{StreamPixelFormat, Subsampling};
 use v4l2r::ioctl::streamon;
 use v4l2r::ioctl::dqbuf;
 use v4l2r::{device::{DeviceConfig, Device, queue::Queue}, ioctl::{self, mmap, qbuf, reqbufs, GFmtError, MemoryConsistency, RequestBuffers, V4l2Buffer}, memory::MemoryType, Format, PixelFormat, QueueType,};
-use std::io::Write;
-use std::os::fd::{AsFd, AsRawFd};
+use std::io::{BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
 use std::os::unix::net::UnixListener;
 use std::path::Path;
-use std::sync::RwLock as SyncRwLock;
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
-use std::sync::mpsc::Sender;
-use std::sync::{Arc};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 use std::fs::File;
@@ -39,9 +50,6 @@ use std::os::unix::net::UnixStream;
 
 use tokio::sync::RwLock;
 
-#[cfg(mpp_accel)]
-use ustreamer::rk_mpp;
-
 // COMPLETE Integrate server and client
 // IGNORE Reuse MPP components for faster encode times
 // TODO Seperate Image fetch and sending functionality to separate threads 
@@ -70,8 +78,6 @@ use ustreamer::rk_mpp;
 // ssh -L 9000:/home/user/ustreamer/server/debug_ustreamer.sock user@address
 // ffplay -i tcp://127.0.0.1:9000
 
-const ENCODER: Encoder = Encoder::RockchipMpp; 
-
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
@@ -88,12 +94,28 @@ async fn main() {
         if args.exit_on_parent_death {
             unsafe { exit_on_parent_death() };
         }
-        image_server(args.device, args.drop_frames).await;
+        image_server(&args).await;
     }
     
 }
 
-async fn image_server(mut path: String, skip: bool) {
+async fn image_server(args: &Args) {
+    let adaptive = args.adaptive_config();
+    let options = args.encoder_options();
+    let dedup = args.change_config();
+    let mut sinks = Sinks::open(args.jpeg_sink(), args.raw_sink(), args.h264_sink());
+    let consumer_config = args.consumer_config();
+    let idle = args.idle_policy();
+    let socket = args.image_socket();
+    let tcp = match args.tcp_options() {
+        Ok(tcp) => tcp,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let (dirty_tiles, subsampling, frame_metadata) = (args.dirty_tiles, args.subsampling, args.frame_metadata);
+    let mut path = args.device.clone();
     if !path.contains("dev") {
         path = "/dev/video0".to_string();
     }
@@ -101,24 +123,32 @@ async fn image_server(mut path: String, skip: bool) {
     let _lock = StreamLock::aquire_lock("/run/kvmd/ustreamer.lock".to_string());
     
     
-    let buffer_count = if ENCODER == Encoder::RockchipMpp { 4 } else { 8 };
+    let quality = Arc::new(AtomicU8::new(args.quality));
+    let backlog = Arc::new(AtomicUsize::new(0));
+    let keyframe = Arc::new(AtomicBool::new(false));
+    let retained = Arc::new(RetainedFrame::default());
+    let demand = Arc::new(Demand::new());
+    let max_fps = Arc::new(AtomicU32::new(0));
+    let capture_stats = Arc::new(Mutex::new(CaptureStats::default()));
+    let resolution = Arc::new(Mutex::new(None));
+    let mut adaptive = adaptive.map(|config| {
+        let adaptive = AdaptiveQuality::new(config, quality.load(Ordering::Relaxed));
+        quality.store(adaptive.quality(), Ordering::Relaxed);
+        println!("Adaptive quality enabled: {:?}", config);
+        adaptive
+    });
 
-    #[cfg(mpp_accel)]
-    let encoder_fn: fn(PlaneMapping, usize, usize, &str, u8) -> Vec<u8> = if ENCODER == Encoder::RockchipMpp { encode_jpeg_mpp } else if ENCODER == Encoder::CpuPool { ustreamer::cpu_pool::init_pool(); encode_jpeg_cpu_pool } else { encode_jpeg_cpu };
-    #[cfg(not(mpp_accel))]
-    let encoder_fn: fn(PlaneMapping, usize, usize, &str, u8) -> Vec<u8> = if ENCODER == Encoder::CpuPool { ustreamer::cpu_pool::init_pool(); encode_jpeg_cpu_pool } else { encode_jpeg_cpu };
     let embedded = false;
 
     let debug = false;
     let downscaling = false;
-    let skip_repeats = skip;
+    let skip_repeats = dedup.is_some();
+    let mut dedup = dedup.map(ChangeDetector::new);
+    let mut dirty_tracker = dirty_tiles.then(DirtyTracker::new);
 
     
 
 
-    let socket_path = format!("{}/server/ustreamer_rs.sock", env!("CARGO_MANIFEST_DIR"));
-    eprintln!("Removing old socket...");
-    std::fs::remove_file(&socket_path).ok();
     
 
     let port = 7878;
@@ -165,6 +195,25 @@ async fn image_server(mut path: String, skip: bool) {
 
     println!("New FORMAT: {:?}", ioctl::g_fmt::<Format>(&file, q_type));
     let mut pixelformat = ioctl::g_fmt::<Format>(&file, q_type).unwrap().pixelformat.to_string();
+
+    let encoder_chain = match StreamPixelFormat::from_fourcc(&pixelformat) {
+        Some(format) => {
+            let config = EncoderConfig { width, height, format, quality: quality.load(Ordering::Relaxed), subsampling };
+            benchmark::resolve_chain(&args.encoder, &config, &options)
+        }
+        None => args.encoder.clone(),
+    };
+    let gop = options.gop;
+    let mut encoder: Box<dyn Encoder> = match FallbackEncoder::new(encoder_chain, options) {
+        Ok(encoder) => Box::new(encoder),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    println!("Using {} encoder", encoder.name());
+    let buffer_count = if encoder.kind() == EncoderType::RockchipMpp { 4 } else { 8 };
+    configure_encoder(encoder.as_mut(), width, height, &pixelformat, quality.load(Ordering::Relaxed), subsampling);
     
 
     let mut req: RequestBuffers = reqbufs(&file, q_type, MemoryType::Mmap, buffer_count, MemoryConsistency::empty()).map_err(|e| panic!("Failed to request buffers: {e}")).unwrap();
@@ -187,7 +236,16 @@ async fn image_server(mut path: String, skip: bool) {
         embedded,
         port,
         timeout,
-        socket_path,
+        socket,
+        tcp,
+        quality: quality.clone(),
+        backlog: backlog.clone(),
+        keyframe: keyframe.clone(),
+        retained: retained.clone(),
+        demand: demand.clone(),
+        max_fps: max_fps.clone(),
+        stats: capture_stats.clone(),
+        resolution: resolution.clone(),
     };
 
     let mut frames = 0;
@@ -196,32 +254,34 @@ async fn image_server(mut path: String, skip: bool) {
 
     let mut ring = RingBuffer::new(4);
 
-    let mut last_buf = vec![0u8; width as usize * height as usize * 3];
-    last_buf.resize(width as usize * height as usize * 3, 0);
-
-
-    let encoder = get_encoder();
 
     let mut fps = 0;
     let mut total_frames = 0;
     let mut avg_frame_time = 0;
     let mut reset = false;
 
-    let mut same = 0;
-
     let mut packet = Packet {
         frame: Vec::new(),
         width,
         height,
         pixelformat: pixelformat.clone(),
-        encoder,
+        encoder: encoder.name(),
+        encoder_fallback: String::new(),
+        quality: quality.load(Ordering::Relaxed),
         fps,
         total_frames,
         server_skip: 0,
+        identical: 0,
+        captured_us: 0,
+        dirty: None,
+        still: None,
+        reply: None,
+        sealed: memfd::Sealed::default(),
     };
 
-    let stream_status = Arc::new(SyncRwLock::new(false));
-    let mut stream_down_count = 0;
+    let consumers = Arc::new(Consumers::new(consumer_config));
+    let mut streaming = true;
+    let mut paced_frame = Instant::now();
 
     if debug {
         let debug_socket = format!("{}/server/debug_ustreamer.sock", env!("CARGO_MANIFEST_DIR"));
@@ -239,6 +299,7 @@ async fn image_server(mut path: String, skip: bool) {
                 width = format.width as usize;
                 height = format.height as usize;
                 pixelformat = format.pixelformat.to_string();
+                configure_encoder(encoder.as_mut(), width, height, &pixelformat, quality.load(Ordering::Relaxed), subsampling);
                 
                 req = match reqbufs(&file, QueueType::VideoCaptureMplane, MemoryType::Mmap, buffer_count, MemoryConsistency::empty()) {
                     Ok(req) => {
@@ -294,7 +355,7 @@ async fn image_server(mut path: String, skip: bool) {
             
             let buf: V4l2Buffer;
             // println!("Capture deq start frame time {}", frame_time.elapsed().as_millis());
-            if !(ENCODER == Encoder::CpuPool && ustreamer::cpu_pool::workers_full()) {
+            if encoder.wait_ready(Duration::from_millis(100)) {
                 buf = match dqbuf(&file, q_type) {
                     Ok(buf) => {
                         buf
@@ -345,7 +406,12 @@ async fn image_server(mut path: String, skip: bool) {
                 // }
             }
             // println!("Capture frame time {}", frame_time.elapsed().as_millis());
-            let mut jpeg_data = encoder_fn(data, width, height, &pixelformat, 80);
+            apply_quality(encoder.as_mut(), &quality);
+            let jpeg_data = encode_frame(encoder.as_mut(), &data, None);
+            if let Some(adaptive) = adaptive.as_mut() {
+                let next = adaptive.update(quality.load(Ordering::Relaxed), jpeg_data.len(), fps, backlog.load(Ordering::Relaxed));
+                quality.store(next, Ordering::Relaxed);
+            }
             if !jpeg_data.is_empty() {
                 println!("Buffer capacity {}", ring.remaining_capacity());
                 ring.write(jpeg_data);
@@ -377,7 +443,7 @@ async fn image_server(mut path: String, skip: bool) {
                 #[cfg(mpp_accel)]
                 std::thread::sleep(Duration::from_millis(30_u64.saturating_sub(frame_time.elapsed().as_millis() as u64)));
 
-                // if ENCODER == Encoder::CpuPool {
+                // if encoder.kind() == EncoderType::CpuPool {
                 //     std::thread::sleep(Duration::from_millis(30_u64.saturating_sub(frame_time.elapsed().as_millis() as u64)));
                 // }
             }
@@ -388,10 +454,15 @@ async fn image_server(mut path: String, skip: bool) {
         }
     } else {
         let (tx, rx) = mpsc::channel();
-        let stream_task = image_sender_task(rx, streamconfig, stream_status.clone());
+        let stream_task = image_sender_task(rx, streamconfig, consumers.clone());
         loop {
 
             let frame_time = Instant::now();
+            let change = resolution.lock().ok().and_then(|mut change| change.take());
+            if let Some(ResolutionChange { width, height, reply }) = change {
+                reply(set_resolution(file, q_type, width, height));
+                reset = true;
+            }
             if reset {
                 ioctl::streamoff(&file, QueueType::VideoCaptureMplane).map_err(|e| eprintln!("Failed to stop stream: {e}")).ok();
                 format = ioctl::g_fmt(&file, QueueType::VideoCaptureMplane).map_err(|e| eprintln!("Failed to get stream format: {e}")).unwrap();
@@ -402,6 +473,7 @@ async fn image_server(mut path: String, skip: bool) {
                 packet.width = width;
                 packet.height = height;
                 packet.pixelformat = pixelformat.clone();
+                configure_encoder(encoder.as_mut(), width, height, &pixelformat, quality.load(Ordering::Relaxed), subsampling);
 
                 req = match reqbufs(&file, QueueType::VideoCaptureMplane, MemoryType::Mmap, buffer_count, MemoryConsistency::empty()) {
                     Ok(req) => {
@@ -421,9 +493,26 @@ async fn image_server(mut path: String, skip: bool) {
                     unsafe { qbuf::<V4l2Buffer, V4l2Buffer>(&file, buf); }
                 }
                 streamon(&file, q_type).unwrap();
+                streaming = true;
             }
 
-            if stream_down_count < 10 {
+            // Sink clients such as kvmd read frames without the web server being connected,
+            // a pending snapshot needs one frame even without viewers.
+            let watched = idle == IdlePolicy::Persistent || demand.viewers() > 0 || sinks.has_clients() || retained.wanted();
+            if watched || idle != IdlePolicy::StreamOff {
+                if !streaming {
+                    for i in 0..req.count {
+                        let buf = V4l2Buffer::new(q_type, i, MemoryType::Mmap);
+                        unsafe { qbuf::<V4l2Buffer, V4l2Buffer>(&file, buf); }
+                    }
+                    if let Err(e) = streamon(&file, q_type) {
+                        eprintln!("Failed to resume capture: {}", e);
+                        reset = true;
+                        continue
+                    }
+                    streaming = true;
+                    println!("Viewer connected, capture resumed");
+                }
                 // println!("Start frame time {}", frame_time.elapsed().as_millis());
                 if start.elapsed().as_millis() > 1000 {
                     println!("FPS: {} REPEATED FRAMES: {}", frames, rframes);
@@ -437,13 +526,28 @@ async fn image_server(mut path: String, skip: bool) {
                     frames = 0;
                     rframes = 0;
                     start = Instant::now();
+                    if let Ok(mut stats) = capture_stats.lock() {
+                        *stats = CaptureStats {
+                            fps,
+                            total_frames,
+                            width,
+                            height,
+                            format: pixelformat.clone(),
+                            encoder: encoder.name(),
+                            quality: packet.quality,
+                            identical: packet.identical,
+                            streaming,
+                            viewers: demand.viewers(),
+                        };
+                    }
                 }
                 
                 let buf: V4l2Buffer;
                 // println!("Capture deq start frame time {}", frame_time.elapsed().as_millis());
-                if !(ENCODER == Encoder::CpuPool && ustreamer::cpu_pool::workers_full()) {
+                if encoder.wait_ready(Duration::from_millis(100)) {
                     buf = match dqbuf(&file, q_type) {
                         Ok(buf) => {
+                            packet.captured_us = protocol::now_us();
                             buf
                         },
                         Err(e) => {
@@ -456,6 +560,18 @@ async fn image_server(mut path: String, skip: bool) {
                 } else {
                     continue
                 }
+                let grab_ts = memsink::monotonic_now();
+                // A web server asked for fewer frames, or nobody is watching and only the odd frame
+                // is encoded so stats and snapshots stay fresh.
+                let limit = demand::fps_interval(max_fps.load(Ordering::Relaxed));
+                let interval = if watched { limit } else { idle.interval().or(limit) };
+                if let Some(interval) = interval.filter(|_| !retained.wanted()) {
+                    if paced_frame.elapsed() < interval {
+                        unsafe { qbuf::<V4l2Buffer, V4l2Buffer>(&file, buf); }
+                        continue
+                    }
+                    paced_frame = Instant::now();
+                }
                 // println!("Capture deq frame time {}", frame_time.elapsed().as_millis());
                 
                 let plane = buf.get_first_plane();
@@ -464,45 +580,73 @@ async fn image_server(mut path: String, skip: bool) {
                 // println!("plane len: {}", plane.length);
                 let mut server_skip = 0;
                 let data = mmap(&filefd, if let Some(offset) = plane.data_offset {*offset} else {0}, *plane.length).unwrap();
-                if skip_repeats {
-                    if embedded{
-                        if data.data == last_buf.as_slice() && frames % 3 == 0{
-                            same += 1;
-                            // println!("REPEATED FRAMES FOUND!!!");
+                // Before dedup, a snapshot wants this frame even if the picture didn't change.
+                if retained.wanted() {
+                    retained.fill(&data, encoder.config());
+                }
+                if let Some(dedup) = dedup.as_mut() {
+                    match dedup.check(&data) {
+                        FrameChange::Changed => {}
+                        FrameChange::Unchanged => {
                             rframes += 1;
-                            // let mut lock = shared_image_clone.write().await;
-                            // lock.skip = true;
+                            unsafe { qbuf::<V4l2Buffer, V4l2Buffer>(&file, buf); }
                             continue
-                        } else if frames % 3 == 0{
-                            same = 0;
-                            last_buf = data.data.to_vec();
                         }
-                    } else {
-                        if data.data == last_buf.as_slice() && frames % 3 == 0 {
-                            same += 1; 
-                            println!("REPEATED FRAMES FOUND!!!");
-                            rframes += 1;
-                            server_skip = 1; // For External Server Use;
-                        } else if frames % 3 == 0{
-                            last_buf = data.data.to_vec();
-                            server_skip = 0;
-                            same = 0;
-                        }
-                    }
-                    if same > 10 {
-                        server_skip = 1;
-                        // let mut lock = shared_image_clone.write().await;
-                        // lock.skip = true;
+                        // Tells the web server the picture is still the same.
+                        FrameChange::Refresh => server_skip = 1,
                     }
+                    packet.identical = dedup.skipped();
                 }
+                let mut sink_info = FrameInfo {
+                    width: width as u32,
+                    height: height as u32,
+                    format: memsink::fourcc(&pixelformat),
+                    stride: format.plane_fmt.first().map_or(0, |plane| plane.bytesperline),
+                    online: true,
+                    key: false,
+                    gop,
+                    grab_ts,
+                    encode_begin_ts: grab_ts,
+                    encode_end_ts: grab_ts,
+                };
+                memsink::put(&mut sinks.raw, &data, &sink_info);
                 // println!("Capture frame time {}", frame_time.elapsed().as_millis());
-                let mut jpeg_data = encoder_fn(data, width, height, &pixelformat, 80);
+                packet.quality = apply_quality(encoder.as_mut(), &quality);
+                if keyframe.swap(false, Ordering::Relaxed) {
+                    encoder.request_keyframe();
+                }
+                packet.dirty = dirty_tracker.as_mut().and_then(|tracker| encoder.config().map(|config| tracker.update(&data, &config)));
+                sink_info.encode_begin_ts = memsink::monotonic_now();
+                let jpeg_data = encode_frame(encoder.as_mut(), &data, packet.dirty.as_ref());
+                sink_info.encode_end_ts = memsink::monotonic_now();
+                if let Some(adaptive) = adaptive.as_mut() {
+                    let next = adaptive.update(quality.load(Ordering::Relaxed), jpeg_data.len(), fps, backlog.load(Ordering::Relaxed));
+                    quality.store(next, Ordering::Relaxed);
+                }
+                packet.encoder = encoder.name();
+                packet.encoder_fallback = encoder.fallback_reason().unwrap_or_default();
                 packet.total_frames = total_frames;
                 packet.server_skip = server_skip;
                 packet.fps = fps;
 
+                let mut frame = Packet::clone_with_frame(&packet, jpeg_data);
+                if let Some(segment) = frame_metadata {
+                    frame.embed_metadata(segment);
+                }
+                if !frame.frame.is_empty() {
+                    let key_requested = if encoder.kind() == EncoderType::H264 {
+                        let info = FrameInfo { format: memsink::FORMAT_H264, stride: 0, key: memsink::is_h264_key(&frame.frame), ..sink_info };
+                        memsink::put(&mut sinks.h264, &frame.frame, &info)
+                    } else {
+                        let info = FrameInfo { format: memsink::FORMAT_JPEG, stride: 0, ..sink_info };
+                        memsink::put(&mut sinks.jpeg, &frame.frame, &info)
+                    };
+                    if key_requested {
+                        keyframe.store(true, Ordering::Relaxed);
+                    }
+                }
                 let start_send = Instant::now();
-                tx.send(Packet::clone_with_frame(&packet, jpeg_data));
+                tx.send(frame);
                 println!("Frame send time {}", start_send.elapsed().as_millis());
                 // println!("ENCODING TIME {} ", frame_time.elapsed().as_millis());
                 unsafe { qbuf::<V4l2Buffer, V4l2Buffer>(&file, buf); }
@@ -520,7 +664,7 @@ async fn image_server(mut path: String, skip: bool) {
                         // #[cfg(mpp_accel)]
                         // std::thread::sleep(Duration::from_millis(30_u64.saturating_sub(frame_time.elapsed().as_millis() as u64)));
 
-                        // if ENCODER == Encoder::CpuPool {
+                        // if encoder.kind() == EncoderType::CpuPool {
                         //     std::thread::sleep(Duration::from_millis(30_u64.saturating_sub(frame_time.elapsed().as_millis() as u64)));
                         // }
                     }
@@ -529,304 +673,313 @@ async fn image_server(mut path: String, skip: bool) {
                 // println!("Data length: {}", jpeg_data.len());
                 frames += 1;
             } else {
-                match stream_status.read() {
-                    Ok(read) => {
-                        if *read {
-                            stream_down_count = 0;
-                        } else {
-                            stream_down_count += 1;
-                        }
-                    }
-                    Err(e) => {
-
+                if streaming {
+                    ioctl::streamoff(&file, q_type).map_err(|e| eprintln!("Failed to stop stream: {e}")).ok();
+                    streaming = false;
+                    if let Ok(mut stats) = capture_stats.lock() {
+                        stats.fps = 0;
+                        stats.streaming = false;
+                        stats.viewers = 0;
                     }
+                    println!("No viewers, capture stopped");
                 }
-                
+                // Sink clients are polled, socket consumers wake the wait up right away.
+                demand.wait_for_viewers(Duration::from_millis(100));
             }
         }
         stream_task.join();
     }
 }
 
-fn image_sender_task(rx: Receiver<Packet>, stream_config: StreamConfig, stream_status: Arc<SyncRwLock<bool>>) -> JoinHandle<()> {
-    let mut ring = RingBuffer::new(2);
-    let mut stream: Option<UnixStream> = None;
-    let mut server_started = false;
+fn image_sender_task(rx: Receiver<Packet>, stream_config: StreamConfig, consumers: Arc<Consumers<Arc<Packet>>>) -> JoinHandle<()> {
     let shared = Arc::new(RwLock::new(ImageData::new())); 
-    let shared_image_clone = shared.clone();
-    let shared_clone = shared.clone();
-    let listener = UnixListener::bind(stream_config.socket_path).unwrap();
+    let listener = stream_config.socket.bind().unwrap_or_else(|e| panic!("Failed to bind image server socket {}: {}", stream_config.socket.path, e));
+    println!("Listening for consumers on {}", stream_config.socket.path);
 
-    let mut last_check = Instant::now();
     // Start client
     if stream_config.embedded {
-        init_axum_server(stream_config.port, shared_clone.clone());
-        server_started = true;
+        init_axum_server(stream_config.port, shared.clone());
+    }
+    let stream_config = Arc::new(stream_config);
+    if let Some(tcp) = stream_config.tcp.clone() {
+        let listen = tcp.listen.clone();
+        let tcp_config = stream_config.clone();
+        let tcp_consumers = consumers.clone();
+        let served = transport::serve(tcp, move |consumer| {
+            let id = connect_consumer(ConsumerStream::Tcp(consumer.frames), consumer.lines.into_iter(), &tcp_config, &tcp_consumers);
+            println!("Consumer {} connected over TCP from {}, {} connected", id, consumer.peer, tcp_consumers.count());
+        });
+        match served {
+            Ok(()) => println!("Listening for consumers on tcp://{}", listen),
+            Err(e) => eprintln!("Failed to listen on {}: {}", listen, e),
+        }
     }
+    let accept_consumers = consumers.clone();
     std::thread::spawn(move || {
-        loop {
-            if stream.as_ref().is_none() {
-                match stream_status.write() {
-                    Ok(mut write) => {
-                        *write = false;
-                    },
-                    Err(e) => {
-
-                    }
-                }
-                if stream_config.embedded && !server_started {
-                    init_axum_server(stream_config.port, shared_clone.clone());
-                }
-                match listener.accept() {
-                    Ok((stm, addr)) => {
-                        increase_buf_size(&stm, stream_config.width, stream_config.height).ok();
-                        stream.replace(stm);
-                        println!("Client connected: {:?}", addr);
-                    },
-                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                        if last_check.elapsed() >= stream_config.timeout {
-                            println!("No connections in last second");
-                            last_check = Instant::now();
+        for stm in listener.incoming() {
+            match stm {
+                Ok(stm) => {
+                    increase_buf_size(&stm, stream_config.width, stream_config.height).ok();
+                    let lines = match stm.try_clone() {
+                        Ok(control) => Some(BufReader::new(control).lines().map_while(Result::ok)),
+                        Err(e) => {
+                            eprintln!("Failed to open control channel: {}", e);
+                            None
                         }
-                        std::thread::sleep(Duration::from_millis(50)); 
-                    },
-                    Err(e) => {
-                        eprintln!("Connection failed: {}", e);
-                    }
-                }
-                println!("looking for client");
-            } else {
-                match stream_status.write() {
-                    Ok(mut write) => {
-                        *write = true;
-                    },
-                    Err(e) => {
-
-                    }
-                }
-                let packet = match rx.try_recv() {
-                    Ok(data) => {data},
-                    Err(std::sync::mpsc::TryRecvError::Empty) => {
-                        Packet::default()
-                    },
-                    Err(std::sync::mpsc::TryRecvError::Disconnected) => break,
-                };
-                
-                if !packet.frame.is_empty() {
-                    println!("Buffer capacity {}", ring.remaining_capacity());
-                    let val = ring.write(packet.frame);
-                    if val.is_err() {
-                        println!("Ring buffer filled");
-                    }
-                }
-                if let Ok(jpeg_data) = ring.read() {
-
-                    // let mut lock = shared_image_clone.write().await;
-                    // let metadata = format!("{width}x{height}x{pixelformat}x{encoder}x{fps}x{total_frames}");
-                    // lock.frame = Some(jpeg_data.clone());
-                    // lock.client_total_frames.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                    // lock.skip = false;
-                    // let parts: Vec<&str> = metadata.split('x').collect();
-                    // if parts.len() == 6 {
-                    //     lock.width = parts[0].parse::<u32>().unwrap_or(lock.width);
-                    //     lock.height = parts[1].parse::<u32>().unwrap_or(lock.height);
-                    //     lock.format = parts[2].to_owned();
-                    //     lock.encoder = parts[3].to_owned();
-                    //     lock.server_fps.swap(parts[4].parse::<usize>().unwrap_or(lock.server_fps.load(std::sync::atomic::Ordering::Relaxed)), std::sync::atomic::Ordering::Relaxed);
-                    //     lock.server_total_frames.swap(parts[5].parse::<usize>().unwrap_or(lock.server_total_frames.load(std::sync::atomic::Ordering::Relaxed)), std::sync::atomic::Ordering::Relaxed);
-                    // }
-
-
-                    if stream.is_some() {
-                        let mut frame = Vec::new();
-
-                        let len: [u8; 8] = jpeg_data.len().to_be_bytes();
-                        let mut open_stream  = stream.take().unwrap();
-                        // frame.extend_from_slice(&len);
-                        // frame.extend_from_slice(&jpeg_data);
-                        // println!("{:?}", len);
-                        if let Err(e) = open_stream.write_all(&len) {
-                            stream = None; 
-                            eprintln!("v0.1.0 stream dropped {}", e);
-                            continue
-                        } 
-                        
-                        if let Err(e) = open_stream.write_all(&jpeg_data) {
-                            stream = None; 
-                            eprintln!("v0.1.0 stream dropped {}", e);
-                            continue
-                        } 
-
-                        if packet.total_frames % 10 == 0 {
-                            //Width x Height x Pixel Format x Encoder x Server FPS x Total Frames
-                            let mut stream_metadata = format!("{}x{}x{}x{}x{}x{}x{}", 
-                                packet.width,
-                                packet.height,
-                                packet.pixelformat,
-                                packet.encoder,
-                                packet.fps,
-                                packet.total_frames,
-                                packet.server_skip).as_bytes().to_vec();
-                            stream_metadata.resize(1024, 0u8);
-                            // open_stream.write_all(&stream_metadata).unwrap();
-                            frame.extend_from_slice(&stream_metadata);
-                        } else {
-                            frame.extend_from_slice(&vec![0u8; 1024]);
-                        };
-
-                        if let Err(e) = open_stream.write_all(&frame) {
-                            stream = None; 
-                            eprintln!("v0.1.0 stream dropped {}", e);
-                            continue
-                        } 
-                        // println!("Data length: {}", jpeg_data.len());
-                        stream.replace(open_stream);
-                    } else {
-                        let mut open_stream = stream.take().unwrap();
-                        open_stream.write_all(format!("{}x{}x{}", packet.width, packet.height, packet.width*packet.height*3).as_bytes()).expect("Write Failed");
-                        open_stream.flush();
-                        // metadata_sent = true;
-                        stream.replace(open_stream);
-                    }
-                    // println!("frame {}", frame_time.elapsed().as_millis());
+                    };
+                    let stm = ConsumerStream::Unix(stm, Arc::new(AtomicBool::new(false)));
+                    let id = connect_consumer(stm, lines.into_iter().flatten(), &stream_config, &accept_consumers);
+                    println!("Consumer {} connected, {} connected", id, accept_consumers.count());
+                },
+                Err(e) => {
+                    eprintln!("Connection failed: {}", e);
                 }
             }
         }
+    });
+    std::thread::spawn(move || {
+        for packet in rx {
+            if !packet.frame.is_empty() {
+                // Consumers share the packet, none of them gets a copy of the picture.
+                consumers.broadcast(&Arc::new(packet));
+            }
+        }
     })
 }
 
-#[cfg(mpp_accel)]
-fn encode_jpeg_mpp(data: PlaneMapping, width:usize, height: usize, pixelformat: &str, quality: u8) -> Vec<u8> {
-    let mut jpeg_data = Vec::new();
-
-    // let processing = Instant::now();
-    // let mut rgb_buf = vec![0u8; width as usize * height as usize * 3];
-    if pixelformat == "NV12" {
-        jpeg_data = rk_mpp::encode_jpeg(data.data.to_vec(), width as u32, height as u32, quality, StreamPixelFormat::NV12).unwrap();
-    } else if pixelformat == "BGR3" {
-        jpeg_data = rk_mpp::encode_jpeg(data.data.to_vec(), width as u32, height as u32, quality, StreamPixelFormat::BGR3).unwrap();
-    } else if pixelformat == "NV24" {
-        // std::fs::write("nv24.raw", data.data.to_vec()).unwrap();
-        jpeg_data = rk_mpp::encode_jpeg(data.data.to_vec(), width as u32, height as u32, quality, StreamPixelFormat::NV24).unwrap();
-    } else if pixelformat == "YUYV" {
-        use ustreamer::converters::yuyv422_to_nv12;
-        let nv12 = yuyv422_to_nv12(&data.data.to_vec(), width as u32, height as u32);
-        jpeg_data = rk_mpp::encode_jpeg(nv12, width as u32, height as u32, quality, StreamPixelFormat::NV12).unwrap();
-    } else if pixelformat == "MJPG" {
-        jpeg_data = data.data.to_vec();
-    }
-    // println!("Frame processing time: {}", processing.elapsed().as_millis());
-    jpeg_data
+/// Where a consumer's frames go.
+enum ConsumerStream {
+    /// Frames go as memfds instead of inline once the consumer asked with `memfd=1`.
+    Unix(UnixStream, Arc<AtomicBool>),
+    Tcp(FrameWriter),
 }
 
-
-fn encode_jpeg_cpu(data: PlaneMapping, width:usize, height: usize, pixelformat: &str, quality: u8) -> Vec<u8> {
-    // println!("Using CPU for encoding");
-    let mut jpeg_data = Vec::new();
-    let raw = data.data.to_vec();
-    if pixelformat == "NV12" {
-        let mut rgb_buf = vec![0u8; (width * height * 3) as usize];
-        ustreamer::converters::nv12_to_rgb_yuv(&data, width, height, &mut rgb_buf);
-        let image = Image{ 
-            pixels: rgb_buf.as_slice(), 
-            width: width, 
-            pitch: width * 3, 
-            height: height, 
-            format: turbojpeg::PixelFormat::RGB
-        };
-
-        jpeg_data = compress(image, 80, Subsamp::Sub2x2).unwrap().to_vec();
-
-        // println!("Conversion processing time: {}", processing.elapsed().as_millis());    
-        // let file = File::create(format!("output_{}.jpg", 0)).unwrap();
+impl ConsumerStream {
+    /// Closes a Unix connection both ways, which also ends the control reader on its cloned socket.
+    /// A TCP connection closes once its `FrameWriter` is dropped.
+    fn shutdown(&self) {
+        if let ConsumerStream::Unix(stream, _) = self {
+            stream.shutdown(Shutdown::Both).ok();
+        }
     }
-    
-    else if pixelformat == "BGR3" {
-        let image = Image{ 
-            pixels: raw.as_slice(), 
-            width: width, 
-            pitch: width * 3, 
-            height: height, 
-            format: turbojpeg::PixelFormat::BGR
-        };
-
-        jpeg_data = compress(image, 80, Subsamp::Sub2x2).unwrap().to_vec();
+}
 
-        // Write JPEG to file
-        // std::fs::write("outputbgr.jpg", &jpeg_data).unwrap();
+/// Registers a consumer and starts its writer and control reader, whichever transport it came in on.
+fn connect_consumer(stream: ConsumerStream, lines: impl Iterator<Item = String> + Send + 'static, config: &StreamConfig, consumers: &Arc<Consumers<Arc<Packet>>>) -> u32 {
+    let (id, queue) = consumers.register();
+    config.demand.connect(id);
+    let memfd = match &stream {
+        ConsumerStream::Unix(_, memfd) => Some(memfd.clone()),
+        ConsumerStream::Tcp(_) => None,
+    };
+    spawn_control_reader(lines, id, memfd, config, queue.clone());
+    spawn_consumer_writer(stream, id, queue, consumers.clone(), config.demand.clone());
+    id
+}
 
-        // rgb_buf.resize(width as usize * height as usize * 3, 0);
-        // ustreamer::converters::nv12_to_rgb_yuv(&data, width, height, &mut rgb_buf);
+/// Writes the frames queued for one consumer until it disconnects, so it never holds up the others.
+fn spawn_consumer_writer(mut stream: ConsumerStream, id: u32, queue: Arc<FrameQueue<Arc<Packet>>>, consumers: Arc<Consumers<Arc<Packet>>>, demand: Arc<Demand>) {
+    std::thread::spawn(move || {
+        while let Some(packet) = queue.pop() {
+            if let Err(e) = send_frame(&mut stream, &packet) {
+                eprintln!("v0.1.0 stream dropped {}", e);
+                break
+            }
+        }
+        // Also after the drop policy disconnected it, so the consumer sees EOF and reconnects.
+        stream.shutdown();
+        consumers.remove(id);
+        demand.disconnect(id);
+        println!("Consumer {} disconnected, {} frames dropped", id, queue.dropped());
+    });
+}
 
-        // println!("Conversion processing time: {}", processing.elapsed().as_millis());    
-        // let file = File::create(format!("output_{}.jpg", 0)).unwrap();
+/// Writes one packet to the web server, framed as described in `ustreamer::protocol`.
+fn send_frame(stream: &mut ConsumerStream, packet: &Packet) -> std::io::Result<()> {
+    let mut frame = packet.frame_header();
+    frame.sent_us = protocol::now_us();
+    match stream {
+        ConsumerStream::Unix(stream, memfd) if memfd.load(Ordering::Relaxed) => memfd::send_frame(stream, &frame, &packet.frame, &packet.sealed),
+        ConsumerStream::Unix(stream, _) => frame.write_with(&packet.frame, stream),
+        ConsumerStream::Tcp(writer) => frame.write_with(&packet.frame, writer),
     }
+}
 
-    else if pixelformat == "NV24" {
-        // std::fs::write("nv24.raw", data.data.to_vec()).unwrap();
-        let mut rgb_buf = vec![0u8; (width * height * 3) as usize];
-        ustreamer::converters::nv24_to_rgb_yuv(&data, width, height, &mut rgb_buf);
-        let image = Image{ 
-            pixels: rgb_buf.as_slice(), 
-            width: width, 
-            pitch: width * 3, 
-            height: height, 
-            format: turbojpeg::PixelFormat::RGB
-        };
-
-        jpeg_data = compress(image, 80, Subsamp::Sub2x2).unwrap().to_vec();
+fn configure_encoder(encoder: &mut dyn Encoder, width: usize, height: usize, pixelformat: &str, quality: u8, subsampling: Subsampling) {
+    match StreamPixelFormat::from_fourcc(pixelformat) {
+        Some(format) => {
+            let config = EncoderConfig { width, height, format, quality, subsampling };
+            if let Err(e) = encoder.configure(config) {
+                eprintln!("Failed to configure {} encoder: {}", encoder.name(), e);
+            }
+        }
+        None => {
+            eprintln!("Unsupported pixel format {}", pixelformat);
+        }
     }
+}
 
-    else if pixelformat == "YUYV" {
-        let rgb_buf = ustreamer::converters::yuyv_to_rgb_yuv(&data, width as u32, height as u32);
-        
-        let image = Image {
-                pixels: rgb_buf.as_slice(),
-                width: width,
-                pitch: width * 3,
-                height: height,
-                format: turbojpeg::PixelFormat::RGB,
-        };  
-
-        jpeg_data = compress(image, 80, Subsamp::Sub2x2).unwrap().to_vec();
+/// Picks up quality changes made through the control channel and returns the quality in use.
+fn apply_quality(encoder: &mut dyn Encoder, quality: &AtomicU8) -> u8 {
+    let wanted = quality.load(Ordering::Relaxed);
+    match encoder.config() {
+        Some(config) if config.quality != wanted => {
+            match encoder.set_quality(wanted) {
+                Ok(()) => {
+                    println!("JPEG quality changed to {}", wanted);
+                    wanted
+                }
+                Err(e) => {
+                    eprintln!("Failed to change quality: {}", e);
+                    config.quality
+                }
+            }
+        }
+        Some(config) => config.quality,
+        None => wanted,
     }
+}
 
-    else if pixelformat == "MJPG" {
-        jpeg_data = raw;
-    }
-    jpeg_data
+fn spawn_control_reader(lines: impl Iterator<Item = String> + Send + 'static, id: u32, memfd: Option<Arc<AtomicBool>>, config: &StreamConfig, queue: Arc<FrameQueue<Arc<Packet>>>) {
+    let quality = config.quality.clone();
+    let backlog = config.backlog.clone();
+    let keyframe = config.keyframe.clone();
+    let retained = config.retained.clone();
+    let demand = config.demand.clone();
+    let max_fps = config.max_fps.clone();
+    let stats = config.stats.clone();
+    let resolution = config.resolution.clone();
+    std::thread::spawn(move || {
+        for line in lines {
+            let (command, request) = split_request(&line);
+            let done = Reply::Ok(String::new());
+            let reply = match ControlCommand::parse(command) {
+                Some(ControlCommand::SetQuality(value)) => {
+                    quality.store(value, Ordering::Relaxed);
+                    Some(Reply::Ok(json!({ "quality": value }).to_string()))
+                }
+                Some(ControlCommand::Backlog(bytes)) => {
+                    backlog.store(bytes, Ordering::Relaxed);
+                    Some(done)
+                }
+                Some(ControlCommand::Keyframe) => {
+                    keyframe.store(true, Ordering::Relaxed);
+                    Some(done)
+                }
+                // Answered by the still itself, failures also with an error when asked for a reply.
+                Some(ControlCommand::Snapshot(still)) => send_snapshot(&retained, &still, &queue).err().map(Reply::Error),
+                Some(ControlCommand::DropPolicy(policy)) => {
+                    queue.set_policy(policy);
+                    Some(done)
+                }
+                Some(ControlCommand::Queue(frames)) => {
+                    queue.set_capacity(frames);
+                    Some(done)
+                }
+                Some(ControlCommand::Clients(clients)) => {
+                    demand.report(id, clients);
+                    Some(done)
+                }
+                Some(ControlCommand::Fps(fps)) => {
+                    max_fps.store(fps, Ordering::Relaxed);
+                    println!("Frame rate limited to {} fps", fps);
+                    Some(Reply::Ok(json!({ "fps": fps }).to_string()))
+                }
+                Some(ControlCommand::Memfd(enabled)) => match &memfd {
+                    Some(memfd) => {
+                        memfd.store(enabled, Ordering::Relaxed);
+                        println!("Consumer {} gets frames {}", id, if enabled { "as memfds" } else { "inline" });
+                        Some(done)
+                    }
+                    None => Some(Reply::Error("memfd frames need a Unix socket".to_string())),
+                },
+                Some(ControlCommand::Stats) => {
+                    let stats = stats.lock().map(|stats| stats.to_json().to_string()).unwrap_or_default();
+                    Some(Reply::Ok(stats))
+                }
+                // Answered by the capture loop once the device took the new resolution or refused it.
+                Some(ControlCommand::Resolution(width, height)) => {
+                    let queue = queue.clone();
+                    let reply = Box::new(move |reply: Reply| {
+                        if let Some(request) = request {
+                            queue.push_always(Arc::new(Packet::reply(request, &reply)));
+                        }
+                    });
+                    if let Ok(mut resolution) = resolution.lock() {
+                        *resolution = Some(ResolutionChange { width, height, reply });
+                    }
+                    None
+                }
+                None => {
+                    eprintln!("Unknown control command {:?}", line);
+                    Some(Reply::Error(format!("unknown command {:?}", command)))
+                }
+            };
+            // Replies are never dropped, the web server waits for them.
+            if let (Some(request), Some(reply)) = (request, reply) {
+                queue.push_always(Arc::new(Packet::reply(request, &reply)));
+            }
+        }
+    });
 }
 
-fn encode_jpeg_cpu_pool(data: PlaneMapping, width:usize, height: usize, pixelformat: &str, quality: u8) -> Vec<u8> {
-    // println!("Using CPU for encoding");
-    let mut jpeg_data = Vec::new();
-    let raw = data.data.to_vec();
-    if pixelformat == "NV12" {
-        let mut rgb_buf = vec![0u8; (width * height * 3) as usize];
-        ustreamer::converters::nv12_to_rgb_yuv(&data, width, height, &mut rgb_buf);
-        jpeg_data = ustreamer::cpu_pool::encode_jpeg_pool(rgb_buf, width, height, false, quality)
-    }
-    
-    else if pixelformat == "BGR3" {
-         jpeg_data = ustreamer::cpu_pool::encode_jpeg_pool(raw, width, height, true, quality)
+/// Stops capture and asks the device for another resolution, the capture loop then restarts
+/// with whatever the device settled on. Replies with the resolution in use.
+fn set_resolution(mut file: RawFd, q_type: QueueType, width: u32, height: u32) -> Reply {
+    ioctl::streamoff(&file, q_type).map_err(|e| eprintln!("Failed to stop stream: {e}")).ok();
+    // Buffers have to be released before the format can change.
+    if let Err(e) = reqbufs::<RequestBuffers>(&file, q_type, MemoryType::Mmap, 0, MemoryConsistency::empty()) {
+        return Reply::Error(format!("failed to release buffers: {}", e));
     }
-
-    else if pixelformat == "NV24" {
-        // std::fs::write("nv24.raw", data.data.to_vec()).unwrap();
-        let mut rgb_buf = vec![0u8; (width * height * 3) as usize];
-        ustreamer::converters::nv24_to_rgb_yuv(&data, width, height, &mut rgb_buf);
-        jpeg_data = ustreamer::cpu_pool::encode_jpeg_pool(rgb_buf, width, height, false, quality)
+    let mut format: Format = match ioctl::g_fmt(&file, q_type) {
+        Ok(format) => format,
+        Err(e) => return Reply::Error(format!("failed to get format: {}", e)),
+    };
+    format.width = width;
+    format.height = height;
+    match ioctl::s_fmt::<_, Format>(&mut file, (q_type, &format)) {
+        Ok(format) => {
+            println!("Resolution changed to {}x{}", format.width, format.height);
+            Reply::Ok(json!({ "width": format.width, "height": format.height }).to_string())
+        }
+        Err(e) => {
+            eprintln!("Failed to set resolution {}x{}: {}", width, height, e);
+            Reply::Error(format!("device refused {}x{}: {}", width, height, e))
+        }
     }
+}
 
-    else if pixelformat == "YUYV" {
-        let rgb_buf = ustreamer::converters::yuyv_to_rgb_yuv(&data, width as u32, height as u32);
-        jpeg_data = ustreamer::cpu_pool::encode_jpeg_pool(rgb_buf, width, height, false, quality)
+/// Encodes the next captured frame and queues it for the consumer that asked, next to its stream frames.
+/// Failed snapshots are still answered, with an empty frame, so the request doesn't wait for nothing.
+fn send_snapshot(retained: &RetainedFrame, request: &SnapshotRequest, queue: &FrameQueue<Arc<Packet>>) -> Result<(), String> {
+    // Snapshots are never dropped, unlike stream frames.
+    match snapshot::snapshot(retained, request, snapshot::FRAME_WAIT) {
+        Ok(frame) => {
+            println!("Sending {} snapshot ({} bytes)", request.format, frame.len());
+            queue.push_always(Arc::new(Packet { frame, still: Some(request.id), ..Packet::default() }));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Failed to encode {} snapshot: {}", request.format, e);
+            queue.push_always(Arc::new(Packet { still: Some(request.id), ..Packet::default() }));
+            Err(e.to_string())
+        }
     }
+}
 
-    else if pixelformat == "MJPG" {
-        jpeg_data = raw;
+fn encode_frame(encoder: &mut dyn Encoder, data: &[u8], dirty: Option<&DirtyMap>) -> Vec<u8> {
+    let encoded = match dirty {
+        Some(dirty) => encoder.encode_dirty(data, dirty),
+        None => encoder.encode(data),
+    };
+    match encoded {
+        Ok(Some(frame)) => frame.data,
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            eprintln!("Failed to encode frame: {}", e);
+            Vec::new()
+        }
     }
-    jpeg_data
 }
 
 fn init_axum_server(port: u32, shared: Arc<RwLock<ImageData>>) {
@@ -838,10 +991,6 @@ fn init_axum_server(port: u32, shared: Arc<RwLock<ImageData>>) {
     });
 }
 
-fn get_encoder() -> String {
-    ENCODER.to_string()
-}
-
 
 fn increase_buf_size(stream: &UnixStream, width: usize, height: usize) -> std::io::Result<()> {
     use std::io;
@@ -862,4 +1011,36 @@ unsafe fn exit_on_parent_death() {
 #[cfg(not(target_os="linux"))]
 fn exit_on_parent_death() {
     panic!("Exit on parent death is only supported on linux platforms");
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use ustreamer::consumer::{ConsumerConfig, DropPolicy};
+
+    #[test]
+    fn disconnected_consumer_reads_eof() {
+        let consumers = Arc::new(Consumers::new(ConsumerConfig { queue: 1, policy: DropPolicy::Disconnect }));
+        let (id, queue) = consumers.register();
+        let (server, mut client) = UnixStream::pair().unwrap();
+
+        // Stands in for the control reader, which holds its own clone of the socket.
+        let (done_tx, done) = mpsc::channel();
+        let control = server.try_clone().unwrap();
+        std::thread::spawn(move || {
+            BufReader::new(control).lines().map_while(Result::ok).for_each(drop);
+            done_tx.send(()).ok();
+        });
+
+        queue.push(Arc::new(Packet::default()));
+        assert!(!queue.push(Arc::new(Packet::default())));
+        spawn_consumer_writer(ConsumerStream::Unix(server, Arc::new(AtomicBool::new(false))), id, queue, consumers.clone(), Arc::new(Demand::new()));
+
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(client.read_to_end(&mut rest).unwrap(), 0);
+        done.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(consumers.count(), 0);
+    }
+}
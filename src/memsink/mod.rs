@@ -0,0 +1,582 @@
+//! Shared-memory frame sinks laid out like upstream ustreamer's memsink, so kvmd and its Janus
+//! plugin can read frames straight from `--jpeg-sink`, `--raw-sink` and `--h264-sink` objects.
+//!
+//! Each sink is a POSIX shared-memory object holding one frame: a header followed by the frame
+//! data. Writer and readers take turns through `flock` on the object. A reader stamps
+//! `last_client_ts` on every read, the writer only copies frames while that stamp is younger than
+//! the client TTL or a reader holds the lock, which is how upstream tells whether anyone listens.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+/// `magic` of an initialised sink.
+pub const MAGIC: u64 = 0xCAFE_BABE_CAFE_BABE;
+
+/// Layout version, upstream bumps it whenever the header changes.
+pub const VERSION: u32 = 7;
+
+/// `format` of JPEG frames, the fourcc of V4L2_PIX_FMT_JPEG.
+pub const FORMAT_JPEG: u32 = u32::from_le_bytes(*b"JPEG");
+
+/// `format` of H.264 frames, the fourcc of V4L2_PIX_FMT_H264.
+pub const FORMAT_H264: u32 = u32::from_le_bytes(*b"H264");
+
+/// The C `long double` upstream keeps its timestamps in, which differs between targets.
+#[cfg(target_arch = "x86_64")]
+#[repr(C, align(16))]
+#[derive(Clone, Copy, Default)]
+struct Ldf([u8; 16]);
+
+#[cfg(target_arch = "x86_64")]
+impl Ldf {
+    /// x87 extended precision: 15 bit exponent and a 64 bit mantissa with an explicit integer bit.
+    fn from_f64(value: f64) -> Self {
+        let bits = value.to_bits();
+        let sign = ((bits >> 63) as u16) << 15;
+        let exponent = ((bits >> 52) & 0x7FF) as u16;
+        let fraction = bits & ((1 << 52) - 1);
+        let (exponent, mantissa) = match exponent {
+            0 => (0, 0),
+            0x7FF => (0x7FFF, (1 << 63) | (fraction << 11)),
+            _ => (exponent + 16383 - 1023, (1 << 63) | (fraction << 11)),
+        };
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&mantissa.to_le_bytes());
+        bytes[8..10].copy_from_slice(&(sign | exponent).to_le_bytes());
+        Ldf(bytes)
+    }
+
+    fn to_f64(self) -> f64 {
+        let mantissa = u64::from_le_bytes(self.0[..8].try_into().unwrap());
+        let top = u16::from_le_bytes([self.0[8], self.0[9]]);
+        let sign = ((top >> 15) as u64) << 63;
+        let exponent = (top & 0x7FFF) as i64;
+        let fraction = (mantissa << 1) >> 12;
+        let exponent = match exponent {
+            0 => return f64::from_bits(sign),
+            0x7FFF => 0x7FF,
+            _ => exponent - 16383 + 1023,
+        };
+        if exponent <= 0 {
+            f64::from_bits(sign)
+        } else if exponent >= 0x7FF {
+            f64::from_bits(sign | (0x7FF << 52) | fraction)
+        } else {
+            f64::from_bits(sign | ((exponent as u64) << 52) | fraction)
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+#[repr(C, align(16))]
+#[derive(Clone, Copy, Default)]
+struct Ldf([u8; 16]);
+
+#[cfg(target_arch = "aarch64")]
+impl Ldf {
+    /// IEEE binary128: 15 bit exponent and a 112 bit fraction.
+    fn from_f64(value: f64) -> Self {
+        let bits = value.to_bits();
+        let sign = (bits >> 63) as u128;
+        let exponent = ((bits >> 52) & 0x7FF) as u128;
+        let fraction = (bits & ((1 << 52) - 1)) as u128;
+        let exponent = match exponent {
+            0 => return Ldf((sign << 127).to_le_bytes()),
+            0x7FF => 0x7FFF,
+            _ => exponent + 16383 - 1023,
+        };
+        Ldf(((sign << 127) | (exponent << 112) | (fraction << 60)).to_le_bytes())
+    }
+
+    fn to_f64(self) -> f64 {
+        let bits = u128::from_le_bytes(self.0);
+        let sign = ((bits >> 127) as u64) << 63;
+        let exponent = ((bits >> 112) & 0x7FFF) as i64;
+        let fraction = ((bits >> 60) as u64) & ((1 << 52) - 1);
+        let exponent = match exponent {
+            0 => return f64::from_bits(sign),
+            0x7FFF => 0x7FF,
+            _ => exponent - 16383 + 1023,
+        };
+        if exponent <= 0 {
+            f64::from_bits(sign)
+        } else if exponent >= 0x7FF {
+            f64::from_bits(sign | (0x7FF << 52) | fraction)
+        } else {
+            f64::from_bits(sign | ((exponent as u64) << 52) | fraction)
+        }
+    }
+}
+
+/// 32 bit ARM and most other targets have a `long double` that is just a `double`.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[repr(transparent)]
+#[derive(Clone, Copy, Default)]
+struct Ldf(f64);
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+impl Ldf {
+    fn from_f64(value: f64) -> Self {
+        Ldf(value)
+    }
+
+    fn to_f64(self) -> f64 {
+        self.0
+    }
+}
+
+/// `us_memsink_shared_s`, the header at the start of every sink.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Header {
+    magic: u64,
+    version: u32,
+    id: u64,
+    used: usize,
+    width: u32,
+    height: u32,
+    format: u32,
+    stride: u32,
+    online: bool,
+    key: bool,
+    gop: u32,
+    grab_ts: Ldf,
+    encode_begin_ts: Ldf,
+    encode_end_ts: Ldf,
+    last_client_ts: Ldf,
+    key_requested: bool,
+}
+
+/// Data bytes that follow the header, told by the suffix of the object name after the last `:`
+/// or `.`, as kvmd expects, e.g. `kvmd::ustreamer::jpeg`.
+pub fn data_size(name: &str) -> Option<usize> {
+    let suffix = name.rsplit([':', '.']).next()?;
+    match suffix.to_ascii_lowercase().as_str() {
+        "jpeg" => Some(4 * 1024 * 1024),
+        "h264" => Some(2 * 1024 * 1024),
+        "raw" => Some(1920 * 1200 * 3),
+        _ => None,
+    }
+}
+
+/// Seconds on the monotonic clock, which all sink timestamps use.
+pub fn monotonic_now() -> f64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as f64 + ts.tv_nsec as f64 / 1_000_000_000.0
+}
+
+/// A V4L2 fourcc such as `NV12` as the number the header stores, 0 if it isn't four characters.
+pub fn fourcc(format: &str) -> u32 {
+    format.as_bytes().try_into().map(u32::from_le_bytes).unwrap_or(0)
+}
+
+/// Whether an Annex B access unit holds an IDR slice, i.e. can start a decode.
+pub fn is_h264_key(unit: &[u8]) -> bool {
+    unit.windows(4).any(|window| window[..3] == [0, 0, 1] && window[3] & 0x1F == 5)
+}
+
+/// How a sink is created, from the `--*-sink` options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SinkOptions {
+    pub name: String,
+    /// Permissions of the object, e.g. `0o660`.
+    pub mode: u32,
+    /// Remove the object when the sink is closed.
+    pub remove: bool,
+    /// How long a client counts as listening after its last read.
+    pub client_ttl: Duration,
+    /// How long to wait for a reader to release the lock before a frame is dropped.
+    pub timeout: Duration,
+}
+
+/// Everything but the data of a frame in a sink.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameInfo {
+    pub width: u32,
+    pub height: u32,
+    /// V4L2 fourcc, see `fourcc`.
+    pub format: u32,
+    /// Bytes per line of raw frames, 0 for compressed ones.
+    pub stride: u32,
+    pub online: bool,
+    /// Set on H.264 keyframes.
+    pub key: bool,
+    pub gop: u32,
+    /// When the frame was captured, encoding started and encoding ended, see `monotonic_now`.
+    pub grab_ts: f64,
+    pub encode_begin_ts: f64,
+    pub encode_end_ts: f64,
+}
+
+/// A mapped shared-memory object, the part writer and reader have in common.
+struct Mapping {
+    fd: OwnedFd,
+    base: *mut u8,
+    size: usize,
+    timeout: Duration,
+}
+
+// The mapping is owned by whoever holds the `Mapping`, other processes are synchronised by flock.
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn open(name: &str, create: bool, timeout: Duration) -> io::Result<Self> {
+        let data_size = data_size(name).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("sink name {} doesn't end in jpeg, h264 or raw", name))
+        })?;
+        let size = size_of::<Header>() + data_size;
+        let c_name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let flags = if create { libc::O_RDWR | libc::O_CREAT } else { libc::O_RDWR };
+        let fd = unsafe { libc::shm_open(c_name.as_ptr(), flags, 0o600 as libc::mode_t) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        if create {
+            if unsafe { libc::ftruncate(fd.as_raw_fd(), size as libc::off_t) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        } else {
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            if unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if (stat.st_size as usize) < size {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("sink {} is not set up yet", name)));
+            }
+        }
+        let base = unsafe {
+            libc::mmap(std::ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd.as_raw_fd(), 0)
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { fd, base: base as *mut u8, size, timeout })
+    }
+
+    fn header(&mut self) -> &mut Header {
+        unsafe { &mut *(self.base as *mut Header) }
+    }
+
+    fn data(&mut self) -> &mut [u8] {
+        let offset = size_of::<Header>();
+        unsafe { std::slice::from_raw_parts_mut(self.base.add(offset), self.size - offset) }
+    }
+
+    fn try_lock(&self) -> io::Result<bool> {
+        if unsafe { libc::flock(self.fd.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(true);
+        }
+        let error = io::Error::last_os_error();
+        match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(false),
+            _ => Err(error),
+        }
+    }
+
+    /// Takes the lock within the timeout, returns false if the other side kept holding it.
+    fn lock(&self) -> io::Result<bool> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if self.try_lock()? {
+                return Ok(true);
+            }
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        if unsafe { libc::flock(self.fd.as_raw_fd(), libc::LOCK_UN) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base as *mut libc::c_void, self.size) };
+    }
+}
+
+/// The writing end of a sink, owned by the image server.
+pub struct MemSink {
+    name: String,
+    mapping: Mapping,
+    client_ttl: f64,
+    remove: bool,
+    last: FrameInfo,
+}
+
+impl MemSink {
+    /// Creates or takes over the shared-memory object `options.name`.
+    pub fn open(options: &SinkOptions) -> io::Result<Self> {
+        let mut mapping = Mapping::open(&options.name, true, options.timeout)?;
+        if unsafe { libc::fchmod(mapping.fd.as_raw_fd(), options.mode as libc::mode_t) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if mapping.lock()? {
+            // Readers ignore the sink until the first frame sets the magic again.
+            mapping.header().magic = 0;
+            mapping.unlock()?;
+        }
+        Ok(MemSink {
+            name: options.name.clone(),
+            mapping,
+            client_ttl: options.client_ttl.as_secs_f64(),
+            remove: options.remove,
+            last: FrameInfo::default(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Largest frame the sink can hold.
+    pub fn capacity(&self) -> usize {
+        self.mapping.size - size_of::<Header>()
+    }
+
+    /// Whether a client read from the sink within the TTL, is reading right now or asked for a
+    /// keyframe. Also true before the first frame, so clients find a valid header to start from.
+    pub fn has_clients(&mut self) -> io::Result<bool> {
+        if !self.mapping.try_lock()? {
+            return Ok(true);
+        }
+        let header = *self.mapping.header();
+        self.mapping.unlock()?;
+        Ok(header.magic != MAGIC
+            || header.version != VERSION
+            || header.key_requested
+            || header.last_client_ts.to_f64() + self.client_ttl > monotonic_now())
+    }
+
+    /// Whether `info` should be written even without clients, so readers don't keep a stale geometry.
+    pub fn geometry_changed(&self, info: &FrameInfo) -> bool {
+        (info.width, info.height, info.format, info.stride, info.online)
+            != (self.last.width, self.last.height, self.last.format, self.last.stride, self.last.online)
+    }
+
+    /// Writes a frame if a client listens or the geometry changed. Returns whether a client asked
+    /// for a keyframe, which a keyframe written here answers.
+    pub fn put(&mut self, data: &[u8], info: &FrameInfo) -> io::Result<bool> {
+        if !self.has_clients()? && !self.geometry_changed(info) {
+            return Ok(false);
+        }
+        if data.len() > self.capacity() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} byte frame doesn't fit into sink {} ({} bytes)", data.len(), self.name, self.capacity()),
+            ));
+        }
+        if !self.mapping.lock()? {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("sink {} is locked by a client", self.name)));
+        }
+        self.mapping.data()[..data.len()].copy_from_slice(data);
+        let header = self.mapping.header();
+        header.id = header.id.wrapping_add(1);
+        header.used = data.len();
+        header.width = info.width;
+        header.height = info.height;
+        header.format = info.format;
+        header.stride = info.stride;
+        header.online = info.online;
+        header.key = info.key;
+        header.gop = info.gop;
+        header.grab_ts = Ldf::from_f64(info.grab_ts);
+        header.encode_begin_ts = Ldf::from_f64(info.encode_begin_ts);
+        header.encode_end_ts = Ldf::from_f64(info.encode_end_ts);
+        if info.key {
+            header.key_requested = false;
+        }
+        let key_requested = header.key_requested;
+        header.magic = MAGIC;
+        header.version = VERSION;
+        self.mapping.unlock()?;
+        self.last = *info;
+        Ok(key_requested)
+    }
+}
+
+impl Drop for MemSink {
+    fn drop(&mut self) {
+        if self.remove && let Ok(name) = CString::new(self.name.as_str()) {
+            unsafe { libc::shm_unlink(name.as_ptr()) };
+        }
+    }
+}
+
+/// The reading end of a sink, what kvmd does to get frames, used to test the writer.
+pub struct MemSinkReader {
+    mapping: Mapping,
+    last_id: Option<u64>,
+}
+
+impl MemSinkReader {
+    pub fn open(name: &str, timeout: Duration) -> io::Result<Self> {
+        Ok(MemSinkReader { mapping: Mapping::open(name, false, timeout)?, last_id: None })
+    }
+
+    /// The frame in the sink, `None` if there is no new one since the last call or the writer
+    /// held the lock for longer than the timeout. With `request_key` the writer is asked for a keyframe.
+    pub fn get(&mut self, request_key: bool) -> io::Result<Option<(Vec<u8>, FrameInfo)>> {
+        if !self.mapping.lock()? {
+            return Ok(None);
+        }
+        let header = *self.mapping.header();
+        let frame = if header.magic != MAGIC || header.version != VERSION || Some(header.id) == self.last_id {
+            None
+        } else {
+            self.last_id = Some(header.id);
+            let used = header.used.min(self.mapping.size - size_of::<Header>());
+            let data = self.mapping.data()[..used].to_vec();
+            let info = FrameInfo {
+                width: header.width,
+                height: header.height,
+                format: header.format,
+                stride: header.stride,
+                online: header.online,
+                key: header.key,
+                gop: header.gop,
+                grab_ts: header.grab_ts.to_f64(),
+                encode_begin_ts: header.encode_begin_ts.to_f64(),
+                encode_end_ts: header.encode_end_ts.to_f64(),
+            };
+            Some((data, info))
+        };
+        let header = self.mapping.header();
+        header.last_client_ts = Ldf::from_f64(monotonic_now());
+        if request_key {
+            header.key_requested = true;
+        }
+        self.mapping.unlock()?;
+        Ok(frame)
+    }
+}
+
+/// The sinks enabled on the command line.
+#[derive(Default)]
+pub struct Sinks {
+    pub jpeg: Option<MemSink>,
+    pub raw: Option<MemSink>,
+    pub h264: Option<MemSink>,
+}
+
+impl Sinks {
+    /// Opens every configured sink, a sink that fails to open is reported and left out.
+    pub fn open(jpeg: Option<SinkOptions>, raw: Option<SinkOptions>, h264: Option<SinkOptions>) -> Self {
+        let open = |options: Option<SinkOptions>| {
+            let options = options?;
+            match MemSink::open(&options) {
+                Ok(sink) => {
+                    println!("Writing frames to sink {}", options.name);
+                    Some(sink)
+                }
+                Err(e) => {
+                    eprintln!("Failed to open sink {}: {}", options.name, e);
+                    None
+                }
+            }
+        };
+        Sinks { jpeg: open(jpeg), raw: open(raw), h264: open(h264) }
+    }
+
+    /// Whether any sink has a client, so frames are worth capturing without a web server.
+    pub fn has_clients(&mut self) -> bool {
+        [&mut self.jpeg, &mut self.raw, &mut self.h264]
+            .into_iter()
+            .flatten()
+            .any(|sink| sink.has_clients().unwrap_or(false))
+    }
+}
+
+/// Writes to `sink` if it is enabled, returns whether a client asked for a keyframe.
+pub fn put(sink: &mut Option<MemSink>, data: &[u8], info: &FrameInfo) -> bool {
+    let Some(sink) = sink.as_mut() else {
+        return false;
+    };
+    match sink.put(data, info) {
+        Ok(key_requested) => key_requested,
+        Err(e) => {
+            eprintln!("Failed to write to sink {}: {}", sink.name(), e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(name: &str) -> SinkOptions {
+        SinkOptions {
+            name: format!("ustreamer-rs-test-{}::{}", std::process::id(), name),
+            mode: 0o600,
+            remove: true,
+            client_ttl: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn size_comes_from_the_name() {
+        assert_eq!(data_size("kvmd::ustreamer::jpeg"), Some(4 * 1024 * 1024));
+        assert_eq!(data_size("kvmd.ustreamer.H264"), Some(2 * 1024 * 1024));
+        assert_eq!(data_size("kvmd::ustreamer::raw"), Some(1920 * 1200 * 3));
+        assert_eq!(data_size("kvmd::ustreamer"), None);
+    }
+
+    #[test]
+    fn timestamps_survive_long_double() {
+        for value in [0.0, 1.5, 12345.678901, -2.25, 1e-300] {
+            assert_eq!(Ldf::from_f64(value).to_f64(), value);
+        }
+    }
+
+    #[test]
+    fn reader_gets_each_frame_once() {
+        let options = options("jpeg");
+        let mut sink = MemSink::open(&options).unwrap();
+        let mut reader = MemSinkReader::open(&options.name, options.timeout).unwrap();
+        assert!(reader.get(false).unwrap().is_none());
+
+        let info = FrameInfo { width: 640, height: 480, format: FORMAT_JPEG, online: true, grab_ts: 1.25, ..FrameInfo::default() };
+        assert!(!sink.put(&[0xFF, 0xD8, 0xFF, 0xD9], &info).unwrap());
+        let (data, read) = reader.get(true).unwrap().unwrap();
+        assert_eq!(data, [0xFF, 0xD8, 0xFF, 0xD9]);
+        assert_eq!(read, info);
+        assert!(reader.get(false).unwrap().is_none());
+
+        // The reader asked for a keyframe, which stays requested until one is written.
+        assert!(sink.put(&[1], &info).unwrap());
+        assert!(!sink.put(&[2], &FrameInfo { key: true, ..info }).unwrap());
+        assert_eq!(reader.get(false).unwrap().unwrap().0, [2]);
+    }
+
+    #[test]
+    fn frames_are_skipped_without_clients() {
+        let mut options = options("raw");
+        options.client_ttl = Duration::ZERO;
+        let mut sink = MemSink::open(&options).unwrap();
+        let info = FrameInfo { width: 2, height: 1, format: fourcc("BGR3"), stride: 6, online: true, ..FrameInfo::default() };
+        // The first frame sets up the header, later ones only go out while someone reads.
+        sink.put(&[1; 6], &info).unwrap();
+        assert!(!sink.has_clients().unwrap());
+        let mut reader = MemSinkReader::open(&options.name, options.timeout).unwrap();
+        sink.put(&[2; 6], &info).unwrap();
+        assert_eq!(reader.get(false).unwrap().unwrap().0, [1; 6]);
+    }
+
+    #[test]
+    fn h264_keyframes_are_detected() {
+        assert!(is_h264_key(&[0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x65, 0x88]));
+        assert!(!is_h264_key(&[0, 0, 0, 1, 0x41, 0x9A]));
+    }
+}
@@ -1,3 +1,25 @@
+use bytes::Bytes;
+
+use crate::EncoderType;
+use crate::control::Reply;
+use crate::dirty::DirtyMap;
+use crate::memfd;
+use crate::protocol::{FLAG_SKIP, Frame, FrameKind, Metadata};
+
+/// Where `--frame-metadata` puts the frame details inside each JPEG.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, clap::ValueEnum)]
+pub enum FrameMetadata {
+    /// A comment (COM) segment.
+    #[value(name = "com")]
+    Com,
+    /// An APP11 segment, starting with the `USTR\0` identifier.
+    #[value(name = "app")]
+    App,
+}
+
+/// Identifier at the start of the APP11 metadata segment.
+const APP_IDENTIFIER: &[u8] = b"USTR\0";
+
 #[derive(Debug, Default, Clone)]
 pub struct Packet {
     pub frame: Vec<u8>,
@@ -5,9 +27,23 @@ pub struct Packet {
     pub height: usize,
     pub pixelformat: String,
     pub encoder: String,
+    pub encoder_fallback: String,
+    pub quality: u8,
     pub fps: u32,
     pub total_frames: u32,
     pub server_skip: i32,
+    /// Frames dropped so far by `--drop-same-frames`.
+    pub identical: u64,
+    /// When the frame was dequeued, in microseconds since the UNIX epoch.
+    pub captured_us: u64,
+    /// Tiles that changed since the previous frame, with `--dirty-tiles`.
+    pub dirty: Option<DirtyMap>,
+    /// Set on snapshots, the id of the request they answer.
+    pub still: Option<u32>,
+    /// Set on replies to control requests, the id of the request. `frame` holds the reply.
+    pub reply: Option<u32>,
+    /// `frame` as a sealed memfd, made once for all consumers that get frames as memfds.
+    pub sealed: memfd::Sealed,
 }
 
 impl Packet {
@@ -18,9 +54,161 @@ impl Packet {
             height: packet.height, 
             pixelformat: packet.pixelformat.clone(), 
             encoder: packet.encoder.clone(), 
+            encoder_fallback: packet.encoder_fallback.clone(),
+            quality: packet.quality,
             fps: packet.fps, 
             total_frames: packet.total_frames, 
-            server_skip: packet.server_skip 
+            server_skip: packet.server_skip,
+            identical: packet.identical,
+            captured_us: packet.captured_us,
+            dirty: packet.dirty.clone(),
+            still: packet.still,
+            reply: packet.reply,
+            sealed: memfd::Sealed::default(),
+        }
+    }
+
+    /// The answer to the control request `id`.
+    pub fn reply(id: u32, reply: &Reply) -> Self {
+        Packet { frame: reply.to_payload(), reply: Some(id), ..Packet::default() }
+    }
+
+    /// Frames the packet for the web server socket.
+    pub fn into_frame(mut self) -> Frame {
+        let payload = std::mem::take(&mut self.frame);
+        Frame { payload: payload.into(), ..self.frame_header() }
+    }
+
+    /// The frame describing the packet, without its payload, so a packet shared by several
+    /// consumers is written to each without copying the picture.
+    pub fn frame_header(&self) -> Frame {
+        if let Some(request) = self.reply {
+            // Replies describe no picture, so they go without the stream details.
+            let mut frame = Frame::new(FrameKind::Reply, Vec::new());
+            frame.metadata.request = Some(request);
+            return frame;
+        }
+        let kind = if self.still.is_some() {
+            FrameKind::Still
+        } else if self.encoder == EncoderType::H264.to_string() {
+            FrameKind::H264
+        } else {
+            FrameKind::Jpeg
+        };
+        Frame {
+            kind,
+            flags: if self.server_skip != 0 { FLAG_SKIP } else { 0 },
+            sequence: self.total_frames as u64,
+            captured_us: self.captured_us,
+            sent_us: 0,
+            metadata: Metadata {
+                width: Some(self.width as u32),
+                height: Some(self.height as u32),
+                pixel_format: Some(self.pixelformat.clone()).filter(|format| !format.is_empty()),
+                encoder: Some(self.encoder.clone()).filter(|encoder| !encoder.is_empty()),
+                fps: Some(self.fps),
+                quality: Some(self.quality),
+                identical: Some(self.identical),
+                fallback: Some(self.encoder_fallback.clone()).filter(|reason| !reason.is_empty()),
+                dirty: self.dirty.as_ref().map(|dirty| dirty.to_string()),
+                still: self.still,
+                request: None,
+                memfd: None,
+            },
+            payload: Bytes::new(),
+        }
+    }
+
+    /// The frame details as `key=value` pairs, e.g.
+    /// `seq=42 ts=1760000000000000 size=1920x1080 format=NV24 encoder=cpu`.
+    pub fn metadata_text(&self) -> String {
+        format!("seq={} ts={} size={}x{} format={} encoder={}",
+            self.total_frames, self.captured_us, self.width, self.height, self.pixelformat, self.encoder)
+    }
+
+    /// Inserts the frame details into the JPEG right after SOI and any APP0/APP1 segments, so saved
+    /// frames describe themselves.
+    /// Frames that aren't JPEGs, like H.264 access units, are left alone.
+    pub fn embed_metadata(&mut self, segment: FrameMetadata) {
+        if !self.frame.starts_with(&[0xFF, 0xD8]) {
+            return;
         }
-    }    
-}
\ No newline at end of file
+        let (marker, identifier) = match segment {
+            FrameMetadata::Com => (0xFE, &[][..]),
+            FrameMetadata::App => (0xEB, APP_IDENTIFIER),
+        };
+        // JFIF wants its APP0 right after SOI, and EXIF its APP1, so the segment goes behind them.
+        let mut at = 2;
+        while let Some(&[0xFF, 0xE0 | 0xE1, high, low, ..]) = self.frame.get(at..) {
+            at += 2 + u16::from_be_bytes([high, low]) as usize;
+        }
+        if at > self.frame.len() {
+            return;
+        }
+        let text = self.metadata_text();
+        let length = 2 + identifier.len() + text.len();
+        let mut header = Vec::with_capacity(2 + length);
+        header.extend_from_slice(&[0xFF, marker]);
+        header.extend_from_slice(&(length as u16).to_be_bytes());
+        header.extend_from_slice(identifier);
+        header.extend_from_slice(text.as_bytes());
+        self.frame.splice(at..at, header);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metadata_follows_jfif_header() {
+        let pixels = [128u8; 8 * 8 * 3];
+        let image = turbojpeg::Image { pixels: &pixels[..], width: 8, pitch: 8 * 3, height: 8, format: turbojpeg::PixelFormat::RGB };
+        let jpeg = turbojpeg::compress(image, 80, turbojpeg::Subsamp::Sub2x2).unwrap().to_vec();
+        assert_eq!(&jpeg[2..4], &[0xFF, 0xE0]);
+        assert_eq!(&jpeg[6..11], b"JFIF\0");
+        let at = 4 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+
+        let packet = Packet {
+            frame: jpeg.clone(),
+            width: 1920,
+            height: 1080,
+            pixelformat: "NV24".to_string(),
+            encoder: "cpu".to_string(),
+            total_frames: 42,
+            captured_us: 7,
+            ..Packet::default()
+        };
+        let text = b"seq=42 ts=7 size=1920x1080 format=NV24 encoder=cpu";
+
+        let mut com = packet.clone();
+        com.embed_metadata(FrameMetadata::Com);
+        assert_eq!(&com.frame[..at], &jpeg[..at]);
+        assert_eq!(&com.frame[at..at + 2], &[0xFF, 0xFE]);
+        assert_eq!(u16::from_be_bytes([com.frame[at + 2], com.frame[at + 3]]) as usize, 2 + text.len());
+        assert_eq!(&com.frame[at + 4..at + 4 + text.len()], text);
+        assert_eq!(&com.frame[at + 4 + text.len()..], &jpeg[at..]);
+
+        let mut app = packet.clone();
+        app.embed_metadata(FrameMetadata::App);
+        assert_eq!(&app.frame[at..at + 2], &[0xFF, 0xEB]);
+        assert_eq!(&app.frame[at + 4..at + 9], APP_IDENTIFIER);
+
+        // Without APP0 the segment follows SOI.
+        let mut bare = Packet { frame: vec![0xFF, 0xD8, 0xFF, 0xD9], ..packet.clone() };
+        bare.embed_metadata(FrameMetadata::Com);
+        assert_eq!(&bare.frame[..4], &[0xFF, 0xD8, 0xFF, 0xFE]);
+
+        let mut h264 = Packet { frame: vec![0, 0, 0, 1, 0x67], ..packet };
+        h264.embed_metadata(FrameMetadata::Com);
+        assert_eq!(h264.frame, vec![0, 0, 0, 1, 0x67]);
+    }
+
+    #[test]
+    fn replies_carry_only_the_request() {
+        let frame = Packet::reply(5, &Reply::Error("unknown command".to_string())).into_frame();
+        assert_eq!(frame.kind, FrameKind::Reply);
+        assert_eq!(frame.metadata, Metadata { request: Some(5), ..Metadata::default() });
+        assert_eq!(frame.payload, &b"error unknown command"[..]);
+    }
+}
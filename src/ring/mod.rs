@@ -1,21 +1,21 @@
-pub struct RingBuffer {
+pub struct RingBuffer<T = Vec<u8>> {
     size: usize,
-    bufs: Vec<Vec<u8>>,
+    bufs: Vec<T>,
     write: usize,
     read: usize,
 }
 
-impl RingBuffer {
+impl<T: Default> RingBuffer<T> {
     pub fn new(size: usize) -> Self {
         RingBuffer {
             size,
-            bufs: vec![Vec::new(); size + 1],
+            bufs: (0..=size).map(|_| T::default()).collect(),
             write: 0,
             read: 0,
         }
     }
 
-    pub fn from_vec(vec: Vec<Vec<u8>>) -> Self {
+    pub fn from_vec(vec: Vec<T>) -> Self {
         RingBuffer {
             size: vec.len(),
             bufs: vec,
@@ -24,7 +24,7 @@ impl RingBuffer {
         }
     }
 
-    pub fn write(&mut self, img_buf: Vec<u8>) -> Result<(), RingBufError> {
+    pub fn write(&mut self, img_buf: T) -> Result<(), RingBufError> {
         if !self.full() {
             self.bufs[self.write] = img_buf;
             self.write = (self.write + 1) % (self.size + 1);
@@ -34,10 +34,9 @@ impl RingBuffer {
         }
     }
 
-    pub fn read(&mut self) -> Result<Vec<u8>, RingBufError> {
+    pub fn read(&mut self) -> Result<T, RingBufError> {
         if self.read != self.write {
-            let mut output = Vec::new();
-            std::mem::swap(&mut output, &mut self.bufs[self.read]);
+            let output = std::mem::take(&mut self.bufs[self.read]);
             self.read = (self.read + 1) % (self.size + 1);
             Ok(output)
         } else {
@@ -45,9 +44,8 @@ impl RingBuffer {
         }
     }
 
-    pub fn read_write(&mut self, mut img_buf: Vec<u8>) -> Result<Vec<u8>, RingBufError> {
-        let mut output = Vec::new();
-        std::mem::swap(&mut output, &mut self.bufs[self.read]);
+    pub fn read_write(&mut self, mut img_buf: T) -> Result<T, RingBufError> {
+        let output = std::mem::take(&mut self.bufs[self.read]);
         std::mem::swap(&mut img_buf, &mut self.bufs[self.write]);
         self.read = (self.read + 1) % (self.size + 1);
         self.write = (self.write + 1) % (self.size + 1);
@@ -69,7 +67,9 @@ impl RingBuffer {
             self.read - self.write
         }
     }
+}
 
+impl RingBuffer<Vec<u8>> {
     pub fn slots(&self) -> Vec<bool> {
         // println!("Write Head {} Read Head {}", self.write, self.read);
         let mut filled = vec![false; self.size];
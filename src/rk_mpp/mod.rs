@@ -6,144 +6,257 @@ include!(concat!(env!("CARGO_MANIFEST_DIR"), "/mpp/bindings.rs"));
 #[cfg(rga_converter)]
 use crate::converters::rk_rga;
 
+use std::ffi::CStr;
+
 use crate::StreamPixelFormat;
 
-pub fn encode_jpeg(mut raw_buf: Vec<u8>, width: u32, height: u32, quality: u8, format: StreamPixelFormat) -> Option<Vec<u8>> {
-    let (mut raw_buf, frame_size) = convert_to_nv12(raw_buf, width, height, format);
-    
-    let width = width as i32;
-    let height = height as i32;
-    let quality = quality as i32;
-    
-    // println!("Set Quality Configs");
-    unsafe {
-        let mut ctx: MppCtx = std::ptr::null_mut();  // Replace with actual context init
-        let mut mpi: *mut MppApi_t = std::ptr::null_mut() as *mut _;
-        // println!("Created MPI Configs");
-        let ret = mpp_create(&mut ctx as *mut _, &mut mpi as *mut _);
-        if ret != MPP_RET_MPP_OK {
-            panic!("mpp_create failed with code: {}", ret);
-        }
-
-        mpp_init(ctx, MppCtxType_MPP_CTX_ENC, MppCodingType_MPP_VIDEO_CodingMJPEG);
-
-        let mut cfg: MppEncCfg = std::ptr::null_mut();
-        mpp_enc_cfg_init(&mut cfg);
-        mpp_enc_cfg_set_s32(cfg, b"prep:width\0" as *const _ , width);
-        mpp_enc_cfg_set_s32(cfg, b"prep:height\0" as *const _ , height);
-        mpp_enc_cfg_set_s32(cfg, b"prep:hor_stride\0" as *const _ , width);
-        mpp_enc_cfg_set_s32(cfg, b"prep:ver_stride\0" as *const _ , height);
-        mpp_enc_cfg_set_s32(cfg, b"prep:format\0" as *const _ , MppFrameFormat_MPP_FMT_YUV420SP as i32);
-        mpp_enc_cfg_set_s32(cfg, b"rc:mode\0" as *const _ , MppEncRcMode_e_MPP_ENC_RC_MODE_FIXQP as i32);
-        mpp_enc_cfg_set_s32(cfg, b"jpeg:q_factor\0" as *const _,quality); 
-        mpp_enc_cfg_set_s32(cfg, b"jpeg:qf_max\0" as *const _ , 99);
-        mpp_enc_cfg_set_s32(cfg, b"jpeg:qf_min\0" as *const _, 1);
+#[cfg(mpp_stub)]
+pub mod stub;
+
+/// Caller name MPP logs buffer operations under.
+const CALLER: &CStr = c"ustreamer";
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MppError {
+    /// An MPP call returned an error code.
+    Call(&'static str, MPP_RET),
+    /// An MPP call reported success but handed back a null pointer.
+    Null(&'static str),
+    /// The raw frame is smaller than its resolution and format require.
+    ShortFrame { expected: usize, actual: usize },
+    UnsupportedFormat(StreamPixelFormat),
+}
 
-        if mpi.is_null() {
-            panic!("Null MPI");
+impl std::fmt::Display for MppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MppError::Call(call, ret) => write!(f, "{} failed with code {}", call, ret),
+            MppError::Null(call) => write!(f, "{} returned null", call),
+            MppError::ShortFrame { expected, actual } => write!(f, "short frame: {} bytes, expected {}", actual, expected),
+            MppError::UnsupportedFormat(format) => write!(f, "unsupported pixel format {:?}", format),
         }
+    }
+}
 
+impl std::error::Error for MppError {}
 
-        if let Some(ctrl_fn) = (*mpi).control {
-            ctrl_fn(ctx, MpiCmd_MPP_ENC_SET_CFG, cfg);
-        } else {
-            panic!("Null Control Function")
-        }
-        
+fn check(call: &'static str, ret: MPP_RET) -> Result<(), MppError> {
+    if ret == MPP_RET_MPP_OK { Ok(()) } else { Err(MppError::Call(call, ret)) }
+}
 
-        let mut input_buf: MppBuffer = std::ptr::null_mut();
+/// A JPEG encoding session on the Rockchip VPU.
+///
+/// The MPP context, encoder config and input buffer are created once and reused for every
+/// frame. The encoder is only reconfigured when the resolution or quality changes, and the
+/// input buffer is only reallocated when a frame needs more room than it has.
+pub struct MppJpegEncoder {
+    ctx: MppCtx,
+    mpi: *mut MppApi,
+    cfg: MppEncCfg,
+    group: MppBufferGroup,
+    input: MppBuffer,
+    input_size: usize,
+    /// Settings last applied with MPP_ENC_SET_CFG, `None` until the first frame or after an error.
+    applied: Option<(u32, u32, u8)>,
+}
 
-        let ret = mpp_buffer_get_with_tag(
-                std::ptr::null_mut(),
-                &mut input_buf,
-                frame_size,
-                std::ptr::null(),
-                b"main\0".as_ptr(),
-            );
+// The MPP handles are only ever used through `&mut self`.
+unsafe impl Send for MppJpegEncoder {}
 
-        if ret != 0 || input_buf.is_null() {
-            panic!("Failed to get MPP buffer");
+impl MppJpegEncoder {
+    pub fn new() -> Result<Self, MppError> {
+        let mut encoder = MppJpegEncoder {
+            ctx: std::ptr::null_mut(),
+            mpi: std::ptr::null_mut(),
+            cfg: std::ptr::null_mut(),
+            group: std::ptr::null_mut(),
+            input: std::ptr::null_mut(),
+            input_size: 0,
+            applied: None,
+        };
+        // Whatever was set up before a failing call is released by Drop.
+        unsafe {
+            check("mpp_create", mpp_create(&mut encoder.ctx, &mut encoder.mpi))?;
+            if encoder.ctx.is_null() || encoder.mpi.is_null() {
+                return Err(MppError::Null("mpp_create"));
+            }
+            check("mpp_init", mpp_init(encoder.ctx, MppCtxType_MPP_CTX_ENC, MppCodingType_MPP_VIDEO_CodingMJPEG))?;
+            check("mpp_enc_cfg_init", mpp_enc_cfg_init(&mut encoder.cfg))?;
+            if encoder.cfg.is_null() {
+                return Err(MppError::Null("mpp_enc_cfg_init"));
+            }
+            check("mpp_buffer_group_get", mpp_buffer_group_get(
+                &mut encoder.group,
+                MppBufferType_MPP_BUFFER_TYPE_ION,
+                MppBufferMode_MPP_BUFFER_INTERNAL,
+                CALLER.as_ptr(),
+                CALLER.as_ptr(),
+            ))?;
+            if encoder.group.is_null() {
+                return Err(MppError::Null("mpp_buffer_group_get"));
+            }
         }
-        
-        // println!("INPUT BUF: {:?}", input_buf);
-        // let buf_size = unsafe { mpp_buffer_get_size_with_caller(input_buf, b"main\0".as_ptr()) } as usize; println!("buf_size = {}", buf_size); 
-        // let buf_ptr = mpp_buffer_get_ptr_with_caller(input_buf, b"main\0".as_ptr()) as *mut u8;
-        // println!("buf_ptr = {:?}", buf_ptr); if buf_ptr.is_null() { panic!("mpp_buffer_get_ptr_with_caller returned null"); } 
-        
-        // assert!(buf_size >= frame_size); 
-        // unsafe { std::ptr::copy_nonoverlapping(raw_buf.as_ptr(), buf_ptr, frame_size); }
-        // // std::ptr::copy_nonoverlapping(raw_buf.as_ptr(), buf_ptr, frame_size);
-        assert!(raw_buf.len() >= frame_size);
-
-        let buf_size = mpp_buffer_get_size_with_caller(input_buf, b"main\0".as_ptr()) as usize;
+        Ok(encoder)
+    }
 
-        assert!(buf_size >= frame_size);
-        let buf_ptr = mpp_buffer_get_ptr_with_caller(input_buf, b"main\0".as_ptr()) as *mut u8;
+    /// Encodes one raw frame. MJPG frames have to be passed through by the caller.
+    pub fn encode(&mut self, raw_buf: Vec<u8>, width: u32, height: u32, quality: u8, format: StreamPixelFormat) -> Result<Vec<u8>, MppError> {
+        if format == StreamPixelFormat::MJPG {
+            return Err(MppError::UnsupportedFormat(format));
+        }
+        let expected = crate::encoder::raw_frame_size(format, width as usize, height as usize);
+        if raw_buf.len() < expected {
+            return Err(MppError::ShortFrame { expected, actual: raw_buf.len() });
+        }
 
-        if buf_ptr.is_null() {
-            panic!("mpp_buffer_get_ptr_with_caller returned null");
+        let result = self.encode_nv12(raw_buf, width, height, quality, format);
+        if result.is_err() {
+            // Don't trust the encoder state after a failure, push the config again on the next frame.
+            self.applied = None;
         }
+        result
+    }
 
-        std::ptr::copy_nonoverlapping(raw_buf.as_ptr(), buf_ptr, frame_size);
+    fn encode_nv12(&mut self, raw_buf: Vec<u8>, width: u32, height: u32, quality: u8, format: StreamPixelFormat) -> Result<Vec<u8>, MppError> {
+        let (raw_buf, frame_size) = convert_to_nv12(raw_buf, width, height, format);
+        self.configure(width, height, quality)?;
+        self.reserve_input(frame_size)?;
 
+        unsafe {
+            let buf_ptr = mpp_buffer_get_ptr_with_caller(self.input, CALLER.as_ptr()) as *mut u8;
+            if buf_ptr.is_null() {
+                return Err(MppError::Null("mpp_buffer_get_ptr"));
+            }
+            std::ptr::copy_nonoverlapping(raw_buf.as_ptr(), buf_ptr, frame_size);
 
-        let mut frame: MppFrame = std::ptr::null_mut() as *mut _ ;
-        mpp_frame_init(&mut frame);
-        mpp_frame_set_width(frame, width as u32);
-        mpp_frame_set_height(frame, height as u32);
-        mpp_frame_set_hor_stride(frame, width as u32);
-        mpp_frame_set_ver_stride(frame, height as u32);
-        mpp_frame_set_fmt(frame, MppFrameFormat_MPP_FMT_YUV420SP);
-        mpp_frame_set_pts(frame, 0);
-        mpp_frame_set_buffer(frame, input_buf);
-    
-        if frame.is_null() || ctx.is_null() || mpi.is_null() {
-            panic!("Null frame");
+            let mut frame = Frame(std::ptr::null_mut());
+            check("mpp_frame_init", mpp_frame_init(&mut frame.0))?;
+            if frame.0.is_null() {
+                return Err(MppError::Null("mpp_frame_init"));
+            }
+            mpp_frame_set_width(frame.0, width);
+            mpp_frame_set_height(frame.0, height);
+            mpp_frame_set_hor_stride(frame.0, width);
+            mpp_frame_set_ver_stride(frame.0, height);
+            mpp_frame_set_fmt(frame.0, MppFrameFormat_MPP_FMT_YUV420SP);
+            mpp_frame_set_pts(frame.0, 0);
+            mpp_frame_set_buffer(frame.0, self.input);
 
+            let put_frame = (*self.mpi).encode_put_frame.ok_or(MppError::Null("encode_put_frame"))?;
+            check("encode_put_frame", put_frame(self.ctx, frame.0))?;
+
+            let get_packet = (*self.mpi).encode_get_packet.ok_or(MppError::Null("encode_get_packet"))?;
+            let mut packet = Packet(std::ptr::null_mut());
+            check("encode_get_packet", get_packet(self.ctx, &mut packet.0))?;
+            if packet.0.is_null() {
+                return Err(MppError::Null("encode_get_packet"));
+            }
+            let pkt_ptr = mpp_packet_get_pos(packet.0) as *const u8;
+            let pkt_len = mpp_packet_get_length(packet.0);
+            if pkt_ptr.is_null() {
+                return Err(MppError::Null("mpp_packet_get_pos"));
+            }
+            Ok(std::slice::from_raw_parts(pkt_ptr, pkt_len).to_vec())
         }
-        // let time = std::time::Instant::now();
-        if let Some(encode_fn) = (*mpi).encode_put_frame {
-            encode_fn(ctx, frame);
-        } else {
-            panic!("encode_fn is empty")
-        }
-        
-        let mut packet: MppPacket = std::ptr::null_mut() as *mut _ ;
+    }
 
-        if let Some(retrieve_fn) = (*mpi).encode_get_packet {
-            retrieve_fn(ctx, &mut packet);
+    /// Pushes the encoder config to MPP if the resolution or quality differs from the last frame.
+    fn configure(&mut self, width: u32, height: u32, quality: u8) -> Result<(), MppError> {
+        if self.applied == Some((width, height, quality)) {
+            return Ok(());
         }
-        
-        if packet.is_null() {
-            panic!("packet is null")
+        let settings = [
+            (c"prep:width", width as i32),
+            (c"prep:height", height as i32),
+            (c"prep:hor_stride", width as i32),
+            (c"prep:ver_stride", height as i32),
+            (c"prep:format", MppFrameFormat_MPP_FMT_YUV420SP as i32),
+            (c"rc:mode", MppEncRcMode_e_MPP_ENC_RC_MODE_FIXQP as i32),
+            (c"jpeg:q_factor", quality as i32),
+            (c"jpeg:qf_max", 99),
+            (c"jpeg:qf_min", 1),
+        ];
+        unsafe {
+            for (name, value) in settings {
+                check("mpp_enc_cfg_set_s32", mpp_enc_cfg_set_s32(self.cfg, name.as_ptr(), value))?;
+            }
+            let control = (*self.mpi).control.ok_or(MppError::Null("control"))?;
+            check("MPP_ENC_SET_CFG", control(self.ctx, MpiCmd_MPP_ENC_SET_CFG, self.cfg))?;
         }
-        let pkt_ptr: *mut u8 = mpp_packet_get_pos(packet) as *mut u8;
-        let pkt_len: usize = mpp_packet_get_length(packet);
-        // println!("Time processing {}", time.elapsed().as_millis());
-        
-        let data = std::slice::from_raw_parts(pkt_ptr, pkt_len).to_vec();
+        self.applied = Some((width, height, quality));
+        Ok(())
+    }
 
-        if !packet.is_null() {
-            mpp_packet_deinit(&mut packet);
+    /// Makes sure the input buffer holds at least `size` bytes, replacing it if it is too small.
+    fn reserve_input(&mut self, size: usize) -> Result<(), MppError> {
+        if !self.input.is_null() && self.input_size >= size {
+            return Ok(());
         }
-        if !frame.is_null() {
-            mpp_frame_deinit(&mut frame);
+        unsafe {
+            if !self.input.is_null() {
+                mpp_buffer_put_with_caller(self.input, CALLER.as_ptr());
+                self.input = std::ptr::null_mut();
+                self.input_size = 0;
+            }
+            check("mpp_buffer_get", mpp_buffer_get_with_tag(self.group, &mut self.input, size, CALLER.as_ptr(), CALLER.as_ptr()))?;
+            if self.input.is_null() {
+                return Err(MppError::Null("mpp_buffer_get"));
+            }
+            self.input_size = mpp_buffer_get_size_with_caller(self.input, CALLER.as_ptr());
         }
-        if !input_buf.is_null() {
-            mpp_buffer_put_with_caller(input_buf, b"main\0".as_ptr());
+        if self.input_size < size {
+            return Err(MppError::ShortFrame { expected: size, actual: self.input_size });
         }
-        if !cfg.is_null() {
-            mpp_enc_cfg_deinit(cfg);
+        Ok(())
+    }
+}
+
+impl Drop for MppJpegEncoder {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.input.is_null() {
+                mpp_buffer_put_with_caller(self.input, CALLER.as_ptr());
+            }
+            if !self.group.is_null() {
+                mpp_buffer_group_put(self.group);
+            }
+            if !self.cfg.is_null() {
+                mpp_enc_cfg_deinit(self.cfg);
+            }
+            if !self.ctx.is_null() {
+                mpp_destroy(self.ctx);
+            }
         }
-        if !ctx.is_null() {
-            mpp_destroy(ctx);
+    }
+}
+
+/// Releases an input frame descriptor on every return path.
+struct Frame(MppFrame);
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { mpp_frame_deinit(&mut self.0) };
         }
-        
-        return Some(data);
     }
 }
 
+/// Releases an output packet on every return path.
+struct Packet(MppPacket);
+
+impl Drop for Packet {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { mpp_packet_deinit(&mut self.0) };
+        }
+    }
+}
+
+/// Encodes a single frame with a throwaway session. Prefer keeping an `MppJpegEncoder` around.
+pub fn encode_jpeg(raw_buf: Vec<u8>, width: u32, height: u32, quality: u8, format: StreamPixelFormat) -> Result<Vec<u8>, MppError> {
+    MppJpegEncoder::new()?.encode(raw_buf, width, height, quality, format)
+}
+
 
 // TODO Temporarily disabling RGA Conversion as it produces washed out colors
 #[cfg(rga_converter)]
@@ -166,6 +279,14 @@ fn convert_to_nv12(mut raw_buf: Vec<u8>, width: u32, height: u32, format: Stream
             frame_size = (width * ((height + 15) & !15) * 3 / 2) as usize;
             raw_buf.resize(frame_size, 0);
         }
+        StreamPixelFormat::YUYV => {
+            raw_buf = crate::converters::yuyv422_to_nv12(&raw_buf, width, height);
+            frame_size = (width * ((height + 15) & !15) * 3 / 2) as usize;
+            raw_buf.resize(frame_size, 0);
+        }
+        StreamPixelFormat::MJPG => {
+            unreachable!("MJPG frames are passed through without re-encoding");
+        }
     }
     (raw_buf, frame_size)
 }
@@ -190,6 +311,95 @@ fn convert_to_nv12(mut raw_buf: Vec<u8>, width: u32, height: u32, format: Stream
             frame_size = (width * ((height + 15) & !15) * 3 / 2) as usize;
             raw_buf.resize(frame_size, 0);
         }
+        StreamPixelFormat::YUYV => {
+            raw_buf = crate::converters::yuyv422_to_nv12(&raw_buf, width, height);
+            frame_size = (width * ((height + 15) & !15) * 3 / 2) as usize;
+            raw_buf.resize(frame_size, 0);
+        }
+        StreamPixelFormat::MJPG => {
+            unreachable!("MJPG frames are passed through without re-encoding");
+        }
     }
     (raw_buf, frame_size)
-}
\ No newline at end of file
+}
+#[cfg(all(test, mpp_stub))]
+mod test {
+    use super::*;
+
+    fn nv12(width: u32, height: u32) -> Vec<u8> {
+        vec![0x80; (width * height * 3 / 2) as usize]
+    }
+
+    fn comment(jpeg: &[u8]) -> String {
+        String::from_utf8_lossy(&jpeg[6..jpeg.len() - 2]).into_owned()
+    }
+
+    #[test]
+    fn session_is_reused_across_frames() {
+        let before = stub::calls();
+        let mut encoder = MppJpegEncoder::new().unwrap();
+        for _ in 0..3 {
+            let jpeg = encoder.encode(nv12(64, 32), 64, 32, 80, StreamPixelFormat::NV12).unwrap();
+            assert_eq!(comment(&jpeg), "stub 64x32 q80");
+        }
+        let calls = stub::calls();
+        assert_eq!(calls.contexts - before.contexts, 1);
+        assert_eq!(calls.set_cfg - before.set_cfg, 1);
+        assert_eq!(calls.buffers - before.buffers, 1);
+        assert_eq!(calls.packets - before.packets, 3);
+    }
+
+    #[test]
+    fn reconfigures_only_on_change() {
+        let before = stub::calls();
+        let mut encoder = MppJpegEncoder::new().unwrap();
+        encoder.encode(nv12(64, 32), 64, 32, 80, StreamPixelFormat::NV12).unwrap();
+        let jpeg = encoder.encode(nv12(64, 32), 64, 32, 50, StreamPixelFormat::NV12).unwrap();
+        assert_eq!(comment(&jpeg), "stub 64x32 q50");
+        assert_eq!(stub::calls().set_cfg - before.set_cfg, 2);
+        assert_eq!(stub::calls().buffers - before.buffers, 1);
+
+        let jpeg = encoder.encode(nv12(128, 64), 128, 64, 50, StreamPixelFormat::NV12).unwrap();
+        assert_eq!(comment(&jpeg), "stub 128x64 q50");
+        encoder.encode(nv12(64, 32), 64, 32, 50, StreamPixelFormat::NV12).unwrap();
+        let calls = stub::calls();
+        assert_eq!(calls.set_cfg - before.set_cfg, 4);
+        // The bigger buffer is kept when the resolution drops again.
+        assert_eq!(calls.buffers - before.buffers, 2);
+        assert_eq!(calls.buffers_released - before.buffers_released, 1);
+
+        drop(encoder);
+        let calls = stub::calls();
+        assert_eq!(calls.buffers_released - before.buffers_released, 2);
+        assert_eq!(calls.groups_released - before.groups_released, 1);
+        assert_eq!(calls.contexts_destroyed - before.contexts_destroyed, 1);
+    }
+
+    #[test]
+    fn failures_are_returned_as_errors() {
+        let mut encoder = MppJpegEncoder::new().unwrap();
+        assert_eq!(
+            encoder.encode(vec![0; 16], 64, 32, 80, StreamPixelFormat::NV12),
+            Err(MppError::ShortFrame { expected: 64 * 32 * 3 / 2, actual: 16 })
+        );
+
+        let before = stub::calls();
+        stub::fail_next("encode_put_frame");
+        assert_eq!(
+            encoder.encode(nv12(64, 32), 64, 32, 80, StreamPixelFormat::NV12),
+            Err(MppError::Call("encode_put_frame", MPP_RET_MPP_NOK))
+        );
+        // The next frame pushes the config again and goes through.
+        encoder.encode(nv12(64, 32), 64, 32, 80, StreamPixelFormat::NV12).unwrap();
+        assert_eq!(stub::calls().set_cfg - before.set_cfg, 2);
+        assert_eq!(stub::calls().frames - before.frames, 2);
+    }
+
+    #[test]
+    fn failed_setup_releases_context() {
+        let before = stub::calls();
+        stub::fail_next("mpp_init");
+        assert_eq!(MppJpegEncoder::new().err(), Some(MppError::Call("mpp_init", MPP_RET_MPP_NOK)));
+        assert_eq!(stub::calls().contexts_destroyed - before.contexts_destroyed, 1);
+    }
+}
@@ -0,0 +1,310 @@
+//! A stand-in for `librockchip_mpp`, built with the `mpp_stub` feature instead of linking the
+//! real library. It implements the MPP calls `MppJpegEncoder` makes so the encoder logic runs
+//! on any Linux machine.
+//!
+//! Instead of a real JPEG every frame comes out as SOI, a COM segment reading
+//! `stub WxH qQ` with the settings last applied through MPP_ENC_SET_CFG, and EOI. Calls are
+//! counted per thread, and `fail_next` makes the next call of a given name return an error.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CStr, c_char, c_void};
+
+use super::*;
+
+/// How often each kind of MPP object was created and released on this thread.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Calls {
+    pub contexts: usize,
+    pub contexts_destroyed: usize,
+    pub set_cfg: usize,
+    pub buffers: usize,
+    pub buffers_released: usize,
+    pub groups: usize,
+    pub groups_released: usize,
+    pub frames: usize,
+    pub packets: usize,
+}
+
+thread_local! {
+    static CALLS: RefCell<Calls> = RefCell::new(Calls::default());
+    static FAIL: RefCell<Option<&'static str>> = const { RefCell::new(None) };
+}
+
+/// Call counters for the current thread.
+pub fn calls() -> Calls {
+    CALLS.with(|calls| calls.borrow().clone())
+}
+
+/// Makes the next call to `call` (e.g. `"mpp_init"` or `"encode_put_frame"`) on this thread fail.
+pub fn fail_next(call: &'static str) {
+    FAIL.with(|fail| *fail.borrow_mut() = Some(call));
+}
+
+fn count(update: impl FnOnce(&mut Calls)) {
+    CALLS.with(|calls| update(&mut calls.borrow_mut()));
+}
+
+fn result(call: &'static str) -> MPP_RET {
+    let fails = FAIL.with(|fail| {
+        let mut fail = fail.borrow_mut();
+        if *fail == Some(call) { fail.take().is_some() } else { false }
+    });
+    if fails { MPP_RET_MPP_NOK } else { MPP_RET_MPP_OK }
+}
+
+struct Context {
+    api: MppApi,
+    applied: HashMap<String, i32>,
+    packet: Option<Vec<u8>>,
+}
+
+struct Config(HashMap<String, i32>);
+
+struct Buffer(Vec<u8>);
+
+#[derive(Default)]
+struct Frame {
+    width: u32,
+    height: u32,
+    buffer: MppBuffer,
+}
+
+struct Packet(Vec<u8>);
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_create(ctx: *mut MppCtx, mpi: *mut *mut MppApi) -> MPP_RET {
+    let ret = result("mpp_create");
+    if ret != MPP_RET_MPP_OK {
+        return ret;
+    }
+    let context = Box::into_raw(Box::new(Context {
+        api: MppApi {
+            size: std::mem::size_of::<MppApi>() as RK_U32,
+            version: 0,
+            decode: None,
+            decode_put_packet: None,
+            decode_get_frame: None,
+            encode: None,
+            encode_put_frame: Some(encode_put_frame),
+            encode_get_packet: Some(encode_get_packet),
+            isp: None,
+            isp_put_frame: None,
+            isp_get_frame: None,
+            poll: None,
+            dequeue: None,
+            enqueue: None,
+            reset: None,
+            control: Some(control),
+            reserv: [0; 16],
+        },
+        applied: HashMap::new(),
+        packet: None,
+    }));
+    count(|calls| calls.contexts += 1);
+    unsafe {
+        *ctx = context as MppCtx;
+        *mpi = &mut (*context).api;
+    }
+    ret
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_init(_ctx: MppCtx, _type: MppCtxType, _coding: MppCodingType) -> MPP_RET {
+    result("mpp_init")
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_destroy(ctx: MppCtx) -> MPP_RET {
+    drop(unsafe { Box::from_raw(ctx as *mut Context) });
+    count(|calls| calls.contexts_destroyed += 1);
+    MPP_RET_MPP_OK
+}
+
+unsafe extern "C" fn control(ctx: MppCtx, cmd: MpiCmd, param: MppParam) -> MPP_RET {
+    let ret = result("control");
+    if ret == MPP_RET_MPP_OK && cmd == MpiCmd_MPP_ENC_SET_CFG {
+        unsafe { (*(ctx as *mut Context)).applied = (*(param as *mut Config)).0.clone() };
+        count(|calls| calls.set_cfg += 1);
+    }
+    ret
+}
+
+unsafe extern "C" fn encode_put_frame(ctx: MppCtx, frame: MppFrame) -> MPP_RET {
+    let ret = result("encode_put_frame");
+    if ret != MPP_RET_MPP_OK {
+        return ret;
+    }
+    let (context, frame) = unsafe { (&mut *(ctx as *mut Context), &*(frame as *const Frame)) };
+    if frame.buffer.is_null() {
+        return MPP_RET_MPP_ERR_NULL_PTR;
+    }
+    let setting = |name: &str| context.applied.get(name).copied().unwrap_or(0);
+    let comment = format!("stub {}x{} q{}", frame.width, frame.height, setting("jpeg:q_factor"));
+    let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xFE];
+    jpeg.extend_from_slice(&(comment.len() as u16 + 2).to_be_bytes());
+    jpeg.extend_from_slice(comment.as_bytes());
+    jpeg.extend_from_slice(&[0xFF, 0xD9]);
+    context.packet = Some(jpeg);
+    ret
+}
+
+unsafe extern "C" fn encode_get_packet(ctx: MppCtx, packet: *mut MppPacket) -> MPP_RET {
+    let ret = result("encode_get_packet");
+    if ret != MPP_RET_MPP_OK {
+        return ret;
+    }
+    let context = unsafe { &mut *(ctx as *mut Context) };
+    let output = match context.packet.take() {
+        Some(data) => {
+            count(|calls| calls.packets += 1);
+            Box::into_raw(Box::new(Packet(data))) as MppPacket
+        }
+        None => std::ptr::null_mut(),
+    };
+    unsafe { *packet = output };
+    ret
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_enc_cfg_init(cfg: *mut MppEncCfg) -> MPP_RET {
+    let ret = result("mpp_enc_cfg_init");
+    if ret == MPP_RET_MPP_OK {
+        unsafe { *cfg = Box::into_raw(Box::new(Config(HashMap::new()))) as MppEncCfg };
+    }
+    ret
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_enc_cfg_deinit(cfg: MppEncCfg) -> MPP_RET {
+    drop(unsafe { Box::from_raw(cfg as *mut Config) });
+    MPP_RET_MPP_OK
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_enc_cfg_set_s32(cfg: MppEncCfg, name: *const c_char, val: RK_S32) -> MPP_RET {
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned();
+    unsafe { (*(cfg as *mut Config)).0.insert(name, val) };
+    result("mpp_enc_cfg_set_s32")
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_buffer_group_get(
+    group: *mut MppBufferGroup,
+    _type: MppBufferType,
+    _mode: MppBufferMode,
+    _tag: *const c_char,
+    _caller: *const c_char,
+) -> MPP_RET {
+    let ret = result("mpp_buffer_group_get");
+    if ret == MPP_RET_MPP_OK {
+        unsafe { *group = Box::into_raw(Box::new(0u8)) as MppBufferGroup };
+        count(|calls| calls.groups += 1);
+    }
+    ret
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_buffer_group_put(group: MppBufferGroup) -> MPP_RET {
+    drop(unsafe { Box::from_raw(group as *mut u8) });
+    count(|calls| calls.groups_released += 1);
+    MPP_RET_MPP_OK
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_buffer_get_with_tag(
+    _group: MppBufferGroup,
+    buffer: *mut MppBuffer,
+    size: usize,
+    _tag: *const c_char,
+    _caller: *const c_char,
+) -> MPP_RET {
+    let ret = result("mpp_buffer_get");
+    if ret == MPP_RET_MPP_OK {
+        unsafe { *buffer = Box::into_raw(Box::new(Buffer(vec![0; size]))) as MppBuffer };
+        count(|calls| calls.buffers += 1);
+    }
+    ret
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_buffer_put_with_caller(buffer: MppBuffer, _caller: *const c_char) -> MPP_RET {
+    drop(unsafe { Box::from_raw(buffer as *mut Buffer) });
+    count(|calls| calls.buffers_released += 1);
+    MPP_RET_MPP_OK
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_buffer_get_ptr_with_caller(buffer: MppBuffer, _caller: *const c_char) -> *mut c_void {
+    unsafe { (*(buffer as *mut Buffer)).0.as_mut_ptr() as *mut c_void }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_buffer_get_size_with_caller(buffer: MppBuffer, _caller: *const c_char) -> usize {
+    unsafe { (*(buffer as *const Buffer)).0.len() }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_frame_init(frame: *mut MppFrame) -> MPP_RET {
+    let ret = result("mpp_frame_init");
+    if ret == MPP_RET_MPP_OK {
+        unsafe { *frame = Box::into_raw(Box::new(Frame::default())) as MppFrame };
+        count(|calls| calls.frames += 1);
+    }
+    ret
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_frame_deinit(frame: *mut MppFrame) -> MPP_RET {
+    unsafe {
+        drop(Box::from_raw(*frame as *mut Frame));
+        *frame = std::ptr::null_mut();
+    }
+    MPP_RET_MPP_OK
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_frame_set_width(frame: MppFrame, width: RK_U32) {
+    unsafe { (*(frame as *mut Frame)).width = width };
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_frame_set_height(frame: MppFrame, height: RK_U32) {
+    unsafe { (*(frame as *mut Frame)).height = height };
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_frame_set_hor_stride(_frame: MppFrame, _hor_stride: RK_U32) {}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_frame_set_ver_stride(_frame: MppFrame, _ver_stride: RK_U32) {}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_frame_set_fmt(_frame: MppFrame, _fmt: MppFrameFormat) {}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_frame_set_pts(_frame: MppFrame, _pts: RK_S64) {}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_frame_set_buffer(frame: MppFrame, buffer: MppBuffer) {
+    unsafe { (*(frame as *mut Frame)).buffer = buffer };
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_packet_deinit(packet: *mut MppPacket) -> MPP_RET {
+    unsafe {
+        drop(Box::from_raw(*packet as *mut Packet));
+        *packet = std::ptr::null_mut();
+    }
+    MPP_RET_MPP_OK
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_packet_get_pos(packet: MppPacket) -> *mut c_void {
+    unsafe { (*(packet as *mut Packet)).0.as_mut_ptr() as *mut c_void }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn mpp_packet_get_length(packet: MppPacket) -> usize {
+    unsafe { (*(packet as *const Packet)).0.len() }
+}
@@ -1,112 +1,28 @@
-use std::{sync::Arc, time::Duration};
+use tokio::net::TcpStream;
 
-use tokio::{io::{AsyncReadExt, BufReader, Interest}, net::TcpStream, sync::RwLock};
+use crate::protocol::{Frame, FrameReader};
+
+pub use shared::image::ImageData;
 
 use futures::{Stream, StreamExt};
 
 
 pub struct ImgStream {
-    socket: Arc<RwLock<TcpStream>>,
-    counter: usize,
+    reader: FrameReader<TcpStream>,
 }
 
 impl ImgStream {
-    pub fn new(socket: Arc<RwLock<TcpStream>>) -> Self {
-        ImgStream { 
-            socket, 
-            counter: 0,
+    pub fn new(socket: TcpStream) -> Self {
+        ImgStream {
+            reader: FrameReader::new(socket),
         }
     }
 
-    pub fn get_stream(&mut self) -> impl Stream<Item = (Vec<u8>, Option<String>)> {
-        self.counter += 1;
-        futures::stream::unfold(
-            Arc::new(RwLock::new(StreamState {
-                socket: self.socket.clone(),
-                counter: 0,
-            })),  
-            move | mut state| async move {
-                
-                let mut state_guard = state.write().await;
-                state_guard.counter += 1;
-            
-                
-                let mut socket_guard = state_guard.socket.write().await;
-                let mut open_socket = BufReader::new(&mut *socket_guard);
-                
-                let mut len_buf = [0u8; 8];
-                open_socket.read_exact(&mut len_buf).await.unwrap_or_else(|_| {return 0;});
-                // println!("{:?}", len_buf)       ;
-                let len = usize::from_be_bytes(len_buf);
-                // println!("len recieved {}", len);
-                
-                if len < 10000*10000*3 {
-                    let mut buffer = vec![0u8; len];
-                    match open_socket.read_exact(&mut buffer).await {
-                        Ok(n) if n > 0 => {
-                        }
-                        _ => {return None},
-                    }
-
-                    // let test_vec = std::fs::read("ustreaner.jpg").unwrap();
-
-                    let mut metadata_buf = [0u8; 1024];
-                    if open_socket.read_exact(&mut metadata_buf).await.is_err() {
-                        return None;
-                    }
-                    if metadata_buf[0] == 0 {
-                        Some(((buffer, None), state.clone()))
-                        // Some(((test_vec, None), state.clone()))
-                    } else {
-                        let stripped = metadata_buf.into_iter().take_while(|&b| b != 0).collect::<Vec<u8>>();
-                        let content = String::from_utf8(stripped).unwrap_or_default();
-                        // println!("{}", content);
-                        Some(((buffer, Some(content)), state.clone()))
-                        //  Some(((test_vec, Some(content)), state.clone()))
-                    }
-                } else {
-                   None
-                }
-                                                
-            }
-        ).fuse()
+    /// Frames from the image server until the connection closes.
+    pub fn get_stream(self) -> impl Stream<Item = Frame> {
+        futures::stream::unfold(self.reader, |mut reader| async move {
+            let frame = reader.next_frame().await.ok()?;
+            Some((frame, reader))
+        }).fuse()
     }
-
-}
-
-struct StreamState {
-    socket: Arc<RwLock<TcpStream>>,
-    counter: usize,
 }
-
-use std::sync::atomic::{AtomicUsize, Ordering};
-
-pub struct ImageData{
-    pub skip: bool,
-    pub frame: Option<Vec<u8>>,
-    pub width: u32,
-    pub height: u32,
-    pub client_fps: Arc<AtomicUsize>,
-    pub client_total_frames: Arc<AtomicUsize>,
-    pub server_fps: Arc<AtomicUsize>,
-    pub server_total_frames: Arc<AtomicUsize>,
-    pub encoder: String,
-    pub format: String,
-}
-
-impl ImageData {
-    pub fn new() -> Self {
-        ImageData {
-            skip: false,
-            frame: None, 
-            width: 1920, 
-            height: 1080, 
-            client_fps: Arc::new(AtomicUsize::new(0)), 
-            client_total_frames: Arc::new(AtomicUsize::new(0)),
-            server_fps: Arc::new(AtomicUsize::new(0)), 
-            server_total_frames: Arc::new(AtomicUsize::new(0)),
-            encoder: String::new(),
-            format: String::from(""),
-        }
-    }
-}
\ No newline at end of file
@@ -7,16 +7,16 @@ use client::Clients;
 use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{TcpStream, UnixListener, UnixStream}, pin, sync::RwLock, time::sleep};
 use futures::stream::{self, StreamExt};
 
-use std::{io::Read, os::unix::fs::PermissionsExt, path::Path, sync::{mpsc, Arc}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use std::{io::Read, os::unix::fs::PermissionsExt, path::Path, sync::{mpsc, Arc}, time::{Duration, Instant}};
 use axum::response::Json;
 use serde_json::json;
 
-use chrono::Utc;
-use chrono::format::strftime::StrftimeItems;
+use shared::http;
 
-pub mod client;
 pub mod img;
 
+pub use shared::client;
+
  use users::{get_current_username, get_user_by_uid};
 
 // TODO: Transition primites to `atomic` to ensure thread safety
@@ -286,17 +286,7 @@ async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<ImageDa
         // println!("{}", line);
         if line.starts_with("GET /snapshot HTTP/1.1") {
             if let Some(img) = &shared_clone.read().await.frame {   
-                let now = Utc::now();
-                let date = now.format_with_items(StrftimeItems::new("%a, %d %b %Y %H:%M:%S GMT")).to_string();
-
-                let mut response = Vec::new();
-                response.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
-                response.extend_from_slice(b"Content-Type: image/jpeg\r\n");
-                response.extend_from_slice(format!("Date: {}\r\n", date).as_bytes());
-                response.extend_from_slice(format!("Content-Length: {}\r\n", img.len()).as_bytes());
-                response.extend_from_slice(b"\r\n");
-
-                response.extend_from_slice(img);
+                let response = http::response("200 OK", "image/jpeg", img);
 
                 // Send response and flush
                 if let Err(e) = writer.write_all(&response).await {
@@ -306,7 +296,7 @@ async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<ImageDa
                 let _ = writer.shutdown().await;
                 // println!("Snapshot sent and connection closed");
             } else {
-                let _ = writer.write_all(b"HTTP/1.1 503 Service Unavailable\r\n\r\n").await;
+                let _ = writer.write_all(&http::empty_response("503 Service Unavailable")).await;
                 let _ = writer.shutdown().await;
                 // println!("No image available");
             }
@@ -314,16 +304,7 @@ async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<ImageDa
             let client_clone = client_list.clone();
             let stream_shared = shared_clone.clone();
             let _c_id = client_clone.write().await.add_client_from_header(line.clone());
-            let headers = format!(
-                "HTTP/1.1 200 OK\r\n\
-                Cache-Control: no-store, no-cache, must-revalidate, proxy-revalidate, pre-check=0, post-check=0, max-age=0\r\n\
-                Pragma: no-cache\r\n\
-                Expires: Mon, 3 Jan 2000 12:34:56 GMT\r\n\
-                Set-Cookie: stream_client={}/{}; path=/; max-age=30\r\n\
-                Connection: keep-alive\r\n\
-                Content-Type: multipart/x-mixed-replace;boundary=boundarydonotcross\r\n\r\n",
-                _c_id.1, _c_id.0
-            );
+            let headers = http::stream_headers(&_c_id.1, &_c_id.0);
 
             writer.write_all(headers.as_bytes()).await.unwrap();
             writer.flush().await.unwrap();
@@ -359,24 +340,7 @@ async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<ImageDa
                         lock.frame.clone().unwrap()
                     };
                     // println!("img lock acquired parent {}/{}", _c_id.1, _c_id.0);
-                    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
-                    frame = Vec::new();
-                    frame.extend_from_slice(format!(
-                        "--boundarydonotcross\r\n\
-                        Content-Type: image/jpeg\r\n\
-                        Content-Length: {}\r\n\
-                        X-Timestamp: {:.6}\r\n\r\n",
-                        // img.as_ref().map_or(0, |i| i.len()),
-                        img.len(),
-                        timestamp
-                    ).as_bytes());
-
-                    // println!("frame header: {}", String::from_utf8(frame.clone()).unwrap());
-                    // if let Some(img) = img {
-                        frame.extend_from_slice(&img);
-                    // }
-
-                    frame.extend_from_slice(b"\r\n");
+                    frame = http::stream_part(&img, true, "");
 
                     prev_frame.replace(frame.clone());
                 } else {
@@ -387,24 +351,7 @@ async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<ImageDa
                             let img = lock.frame.clone().unwrap();
                             // println!("img length:{}", img.len());
                             // println!("img lock acquired parent {}/{}", _c_id.1, _c_id.0);
-                            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
-                            frame = Vec::new();
-                            frame.extend_from_slice(format!(
-                                "--boundarydonotcross\r\n\
-                                Content-Type: image/jpeg\r\n\
-                                Content-Length: {}\r\n\
-                                X-Timestamp: {:.6}\r\n\r\n",
-                                // img.as_ref().map_or(0, |i| i.len()),
-                                img.len(),
-                                timestamp
-                            ).as_bytes());
-
-                            // println!("frame header: {}", String::from_utf8(frame.clone()).unwrap());
-                            // if let Some(img) = img {
-                                frame.extend_from_slice(&img);
-                            // }
-
-                            frame.extend_from_slice(b"\r\n");
+                            frame = http::stream_part(&img, true, "");
                             prev_frame.replace(frame.clone());
                         } else {
                             skip = true;
@@ -482,7 +429,7 @@ async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<ImageDa
                     "encoder": {
                         "encoder": encoder,
                         // "pixel format": pixformat,
-                        "quality": 80,
+                        "quality": lock.quality,
                     },
                     "source": {
                         "resolution": {
@@ -502,18 +449,7 @@ async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<ImageDa
                 }
             }).to_string();
 
-            let now = Utc::now();
-            let date = now.format_with_items(StrftimeItems::new("%a, %d %b %Y %H:%M:%S GMT")).to_string();
-
-            let mut response = Vec::new();
-            response.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
-            response.extend_from_slice(b"Content-Type: application/json\r\n");
-            response.extend_from_slice(b"Date: ");
-            response.extend_from_slice(date.as_bytes());
-            response.extend_from_slice(b"\r\nContent-Length: ");
-            response.extend_from_slice(json_body.len().to_string().as_bytes());
-            response.extend_from_slice(b"\r\n\r\n");
-            response.extend_from_slice(json_body.as_bytes());
+            let response = http::response("200 OK", "application/json", json_body.as_bytes());
             // println!("Sending response:\n{}", String::from_utf8_lossy(&response));
         
             if let Err(e) = writer.write_all(&response).await {
@@ -557,18 +493,7 @@ async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<ImageDa
                     "port": port,
                 }
             })).to_string();
-            let now = Utc::now();
-            let date = now.format_with_items(StrftimeItems::new("%a, %d %b %Y %H:%M:%S GMT")).to_string();
-
-            let mut response = Vec::new();
-            response.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
-            response.extend_from_slice(b"Content-Type: application/json\r\n");
-            response.extend_from_slice(b"Date: ");
-            response.extend_from_slice(date.as_bytes());
-            response.extend_from_slice(b"\r\nContent-Length: ");
-            response.extend_from_slice(json_body.len().to_string().as_bytes());
-            response.extend_from_slice(b"\r\n\r\n");
-            response.extend_from_slice(json_body.as_bytes());
+            let response = http::response("200 OK", "application/json", json_body.as_bytes());
             // println!("Sending response:\n{}", String::from_utf8_lossy(&response));
         
             if let Err(e) = writer.write_all(&response).await {
@@ -579,7 +504,7 @@ async fn connection_handler(stream: UnixStream, shared_clone: Arc<RwLock<ImageDa
             // println!("Ustreamer Status JSON sent and connection closed");
         } else {
             // println!("Tried accessing {}", line);
-            let _ = writer.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await;
+            let _ = writer.write_all(&http::empty_response("404 Not Found")).await;
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,110 @@
+//! Serves the image server socket protocol over TCP, for web servers on other hosts.
+//! The handshake and TLS setup live in `shared::transport`.
+
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+
+pub use shared::transport::{Psk, tls};
+use shared::transport::{Authenticated, authenticate};
+
+#[derive(Clone)]
+pub struct TcpOptions {
+    /// `host:port` to listen on.
+    pub listen: String,
+    pub psk: Psk,
+    pub tls: Option<tls::Server>,
+}
+
+/// A web server that connected over TCP and proved it knows the key.
+pub struct TcpConsumer {
+    pub peer: SocketAddr,
+    /// Control lines it sends, ends when it disconnects.
+    pub lines: std::sync::mpsc::Receiver<String>,
+    /// Where its frames go, writes fail once it disconnected.
+    pub frames: FrameWriter,
+}
+
+/// Hands what a consumer's writer thread writes to the connection's task. Holds one write at a
+/// time, so a slow connection backs up into the consumer's queue and its drop policy.
+pub struct FrameWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for FrameWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.blocking_send(buf.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Listens on `options.listen` in a thread of its own and passes every consumer that
+/// authenticated to `connected`, which must not block.
+pub fn serve(options: TcpOptions, connected: impl Fn(TcpConsumer) + Send + Sync + 'static) -> io::Result<()> {
+    let listener = std::net::TcpListener::bind(&options.listen)?;
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    std::thread::spawn(move || {
+        runtime.block_on(accept_loop(listener, options, Arc::new(connected)));
+    });
+    Ok(())
+}
+
+async fn accept_loop(listener: std::net::TcpListener, options: TcpOptions, connected: Arc<dyn Fn(TcpConsumer) + Send + Sync>) {
+    let listener = match tokio::net::TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen for TCP consumers: {}", e);
+            return;
+        }
+    };
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Failed to accept TCP consumer: {}", e);
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                continue;
+            }
+        };
+        let options = options.clone();
+        let connected = connected.clone();
+        tokio::spawn(async move {
+            match authenticate(stream, peer, &options.psk, options.tls.as_ref()).await {
+                Ok(consumer) => start(consumer, connected.as_ref()),
+                Err(e) => eprintln!("Refused TCP consumer {}: {}", peer, e),
+            }
+        });
+    }
+}
+
+/// Pumps control lines in and frames out of an authenticated connection.
+fn start(consumer: Authenticated, connected: &(dyn Fn(TcpConsumer) + Send + Sync)) {
+    let Authenticated { peer, reader, mut writer } = consumer;
+    let (line_tx, lines) = std::sync::mpsc::channel();
+    let (frame_tx, mut frames) = mpsc::channel::<Vec<u8>>(1);
+    let reader = tokio::spawn(async move {
+        let mut reader = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = reader.next_line().await {
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(bytes) = frames.recv().await {
+            if let Err(e) = writer.write_all(&bytes).await {
+                eprintln!("TCP consumer {} dropped: {}", peer, e);
+                break;
+            }
+        }
+        reader.abort();
+    });
+    connected(TcpConsumer { peer, lines, frames: FrameWriter(frame_tx) });
+}